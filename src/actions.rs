@@ -0,0 +1,595 @@
+//! Macros defined in the config file as a list of actions, e.g.
+//!
+//! ```toml
+//! [macros.KEY_F1]
+//! actions = [
+//!     { set_led = { led = "LED_CAPSL", on = true } },
+//!     { send_keys = "+KEY_LEFTSHIFT KEY_H -KEY_LEFTSHIFT KEY_I" },
+//!     { send_keys = "Ctrl+Shift+T F5" },
+//!     { command = "notify-send typed" },
+//!     { http = { method = "POST", url = "http://localhost:8123/api/webhook/typed" } },
+//!     { dbus = { destination = "org.mpris.MediaPlayer2.spotify", path = "/org/mpris/MediaPlayer2", interface = "org.mpris.MediaPlayer2.Player", method = "Pause" } },
+//!     { write_file = { path = "journal.txt", content = "{timestamp} typed\n" } },
+//!     { sleep = 0.5 },
+//!     { set_led = { led = "LED_CAPSL", on = false } },
+//!     { notify = { summary = "Done", urgency = "low", icon = "dialog-information" } },
+//! ]
+//! ```
+//!
+//! The actions of one macro run in order on their own thread, so a `sleep` or a
+//! long command doesn't hold up other key presses.
+
+use crate::{
+    config::{self, Repeat, SynReports, WhileHeld},
+    dbus_call, http, log, privileges, process, source, Command,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use evdev::{
+    uinput::VirtualDevice, AttributeSet, EventType, InputEvent, Key, LedType, RelativeAxisType,
+};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    io::Write,
+    os::fd::OwnedFd,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Action {
+    /// A shell command, which has to exit successfully before the next action runs.
+    Command(String),
+    /// Keys to type on the virtual keyboard: `KEY_A` presses and releases a key,
+    /// `+KEY_A` only presses it and `-KEY_A` only releases it.
+    /// `KEY_LEFTCTRL+KEY_C` presses the keys in order and releases them in reverse,
+    /// like typing a shortcut. Keys can also have shorter names, e.g. `Ctrl+C`
+    /// (see `parse_key_name`).
+    /// Mouse buttons (e.g. `BTN_LEFT`) go to a virtual pointer, see `virtual_pointer`.
+    SendKeys(
+        #[serde(
+            deserialize_with = "key_sequence",
+            serialize_with = "serialize_key_sequence"
+        )]
+        Vec<(Key, i32)>,
+    ),
+    /// An HTTP request, which has to succeed (with a 2xx status), see `http.rs`.
+    Http(http::Request),
+    /// A D-Bus method call, which has to succeed, see `dbus_call.rs`.
+    Dbus(dbus_call::Call),
+    /// Switch an LED of the grabbed keyboards.
+    SetLed {
+        #[serde(
+            deserialize_with = "config::name",
+            serialize_with = "config::serialize_name"
+        )]
+        led: LedType,
+        on: bool,
+    },
+    /// A desktop notification, unless they're off (`--no-notify`).
+    Notify {
+        summary: String,
+        #[serde(default)]
+        body: String,
+        /// The notification server's default if unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        urgency: Option<Urgency>,
+        /// An icon name (e.g. `dialog-information`) or path.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        icon: Option<String>,
+        /// Buttons that run other macros when they're clicked, if the
+        /// notification server supports them. Otherwise, the notification is
+        /// shown without them.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        buttons: Vec<Button>,
+    },
+    /// Write to a file as the user, e.g. to keep a journal.
+    WriteFile(WriteFile),
+    /// Wait this many seconds.
+    Sleep(
+        #[serde(
+            deserialize_with = "config::seconds",
+            serialize_with = "config::serialize_seconds"
+        )]
+        Duration,
+    ),
+}
+
+/// A button of a `notify` action, e.g. to ask before doing something:
+///
+/// ```toml
+/// [macros.KEY_F10]
+/// actions = [{ notify = { summary = "Shut down?", buttons = [{ label = "Shut down", run = "shutdown" }, { label = "Cancel" }] } }]
+///
+/// [macros.shutdown]
+/// actions = [{ command = "systemctl poweroff" }]
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Button {
+    pub label: String,
+    /// The macro to run when it's clicked, like the D-Bus `RunMacro` method
+    /// does. Without one, clicking it only closes the notification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run: Option<String>,
+}
+
+/// E.g. `{ write_file = { path = "journal.txt", content = "{timestamp} {key}\n" } }`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteFile {
+    /// Relative to the config directory. Missing directories are created.
+    pub path: PathBuf,
+    /// `{key}` is replaced with the name of the macro, and `{timestamp}` with
+    /// the local time (RFC 3339).
+    pub content: String,
+    #[serde(default)]
+    pub mode: WriteMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    /// Add to the end of the file (the default).
+    #[default]
+    Append,
+    /// Replace what's in the file.
+    Truncate,
+}
+
+impl WriteFile {
+    fn run(&self, macro_name: &str) -> Result<(), String> {
+        let path = config::config_dir()
+            .map_err(|err| err.to_string())?
+            .join(&self.path);
+        let content = self
+            .content
+            .replace("{key}", macro_name)
+            .replace("{timestamp}", &log::rfc3339(SystemTime::now()));
+        // With the user's ids even for the directories, so they own them.
+        privileges::as_user_fs(|| {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(self.mode == WriteMode::Append)
+                .write(true)
+                .truncate(self.mode == WriteMode::Truncate)
+                .open(&path)?
+                .write_all(content.as_bytes())
+        })
+        .map_err(|err| format!("Failed to write {path}: {err}", path = path.display()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl From<Urgency> for notify_rust::Urgency {
+    fn from(urgency: Urgency) -> Self {
+        match urgency {
+            Urgency::Low => notify_rust::Urgency::Low,
+            Urgency::Normal => notify_rust::Urgency::Normal,
+            Urgency::Critical => notify_rust::Urgency::Critical,
+        }
+    }
+}
+
+/// A key in `send_keys`: its name (`KEY_C`), the name without `KEY_` in any
+/// case (`c`, `F5`, `Enter`), or one of the modifiers `Ctrl`, `Shift`, `Alt`,
+/// `AltGr` and `Super` (the left ones, except for `AltGr`).
+fn parse_key_name(name: &str) -> Result<Key, String> {
+    let modifier = match name.to_ascii_lowercase().as_str() {
+        "ctrl" | "control" => Some(Key::KEY_LEFTCTRL),
+        "shift" => Some(Key::KEY_LEFTSHIFT),
+        "alt" => Some(Key::KEY_LEFTALT),
+        "altgr" => Some(Key::KEY_RIGHTALT),
+        "super" | "meta" | "win" => Some(Key::KEY_LEFTMETA),
+        _ => None,
+    };
+    modifier
+        .or_else(|| name.parse().ok())
+        .or_else(|| format!("KEY_{}", name.to_ascii_uppercase()).parse().ok())
+        .ok_or_else(|| format!("unknown key `{name}`"))
+}
+
+/// Parse a `send_keys` string into key events (key, value).
+fn parse_key_sequence(sequence: &str) -> Result<Vec<(Key, i32)>, String> {
+    let mut events = Vec::new();
+    for token in sequence.split_whitespace() {
+        let (name, values): (&str, &[i32]) = match token.as_bytes()[0] {
+            b'+' => (&token[1..], &[1]),
+            b'-' => (&token[1..], &[0]),
+            _ => (token, &[1, 0]),
+        };
+        if name.contains('+') {
+            if values.len() != 2 {
+                return Err(format!("`{token}`: combos can only be typed as a whole"));
+            }
+            let keys = name
+                .split('+')
+                .map(|name| match name {
+                    "" => Err(format!("`{token}` is missing a key")),
+                    name => parse_key_name(name),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(key) = keys
+                .iter()
+                .enumerate()
+                .find_map(|(i, key)| keys[..i].contains(key).then_some(key))
+            {
+                return Err(format!("`{token}` has {key:?} twice"));
+            }
+            events.extend(keys.iter().map(|key| (*key, 1)));
+            events.extend(keys.iter().rev().map(|key| (*key, 0)));
+            continue;
+        }
+        let key = parse_key_name(name)?;
+        events.extend(values.iter().map(|value| (key, *value)));
+    }
+    Ok(events)
+}
+
+fn key_sequence<'de, D>(deserializer: D) -> Result<Vec<(Key, i32)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_key_sequence(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// The inverse of [`parse_key_sequence`].
+fn serialize_key_sequence<S: Serializer>(
+    events: &[(Key, i32)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut tokens = Vec::new();
+    let mut events = events.iter().peekable();
+    while let Some((key, value)) = events.next() {
+        if *value == 1 && events.peek() == Some(&&(*key, 0)) {
+            events.next();
+            tokens.push(format!("{key:?}"));
+        } else {
+            let sign = if *value == 1 { '+' } else { '-' };
+            tokens.push(format!("{sign}{key:?}"));
+        }
+    }
+    serializer.serialize_str(&tokens.join(" "))
+}
+
+/// The keys a virtual keyboard needs to support to run `actions`.
+pub fn keys_used<'a>(actions: impl IntoIterator<Item = &'a Action>) -> AttributeSet<Key> {
+    let mut keys = AttributeSet::new();
+    for action in actions {
+        if let Action::SendKeys(events) = action {
+            for (key, _) in events {
+                keys.insert(*key);
+            }
+        }
+    }
+    keys
+}
+
+/// The keys `forward_modifiers` (in `config.rs`) passes on.
+pub const MODIFIERS: [Key; 8] = [
+    Key::KEY_LEFTSHIFT,
+    Key::KEY_RIGHTSHIFT,
+    Key::KEY_LEFTCTRL,
+    Key::KEY_RIGHTCTRL,
+    Key::KEY_LEFTALT,
+    Key::KEY_RIGHTALT,
+    Key::KEY_LEFTMETA,
+    Key::KEY_RIGHTMETA,
+];
+
+/// Whether `key` is a mouse button, for `virtual_pointer` in `config.rs`.
+pub fn is_pointer_button(key: Key) -> bool {
+    (Key::BTN_LEFT.code()..=Key::BTN_TASK.code()).contains(&key.code())
+}
+
+pub fn create_keyboard(keys: &AttributeSet<Key>) -> std::io::Result<VirtualDevice> {
+    evdev::uinput::VirtualDeviceBuilder::new()?
+        .name("evdev-macros virtual keyboard")
+        .with_keys(keys)?
+        .build()
+}
+
+/// A mouse with the given `buttons`.
+///
+/// It also needs relative axes to be recognized as a pointer, even though we
+/// never move it.
+pub fn create_pointer(buttons: &AttributeSet<Key>) -> std::io::Result<VirtualDevice> {
+    let mut axes = AttributeSet::new();
+    axes.insert(RelativeAxisType::REL_X);
+    axes.insert(RelativeAxisType::REL_Y);
+    evdev::uinput::VirtualDeviceBuilder::new()?
+        .name("evdev-macros virtual pointer")
+        .with_keys(buttons)?
+        .with_relative_axes(&axes)?
+        .build()
+}
+
+/// Everything the actions need, shared with the thread running them.
+#[derive(Clone)]
+pub struct Context {
+    /// For `send_keys`, if any macro uses it and it could be created.
+    pub keyboard: Option<Arc<Mutex<VirtualDevice>>>,
+    /// For the mouse buttons in `send_keys`, if they don't go to the `keyboard`.
+    pub pointer: Option<Arc<Mutex<VirtualDevice>>>,
+    pub syn_reports: SynReports,
+    /// Writable handles to the grabbed devices, for `set_led`. Devices that
+    /// are plugged in later are added, see `device.rs`.
+    pub leds: Arc<Mutex<Vec<OwnedFd>>>,
+    pub commands: Sender<Command>,
+}
+
+impl Context {
+    /// The virtual device to send `key` on.
+    fn device(&self, key: Key) -> Result<&Arc<Mutex<VirtualDevice>>, String> {
+        match &self.pointer {
+            Some(pointer) if is_pointer_button(key) => Ok(pointer),
+            _ => self
+                .keyboard
+                .as_ref()
+                .ok_or_else(|| "The virtual keyboard is not available".to_string()),
+        }
+    }
+}
+
+/// Merge consecutive `send_keys` actions, if they're [`SynReports::Batched`].
+fn batch(actions: Vec<Action>, syn_reports: SynReports) -> Vec<Action> {
+    if syn_reports != SynReports::Batched {
+        return actions;
+    }
+    let mut batched: Vec<Action> = Vec::new();
+    for action in actions {
+        match (batched.last_mut(), action) {
+            (Some(Action::SendKeys(previous)), Action::SendKeys(events)) => previous.extend(events),
+            (_, action) => batched.push(action),
+        }
+    }
+    batched
+}
+
+impl Action {
+    fn run(
+        &self,
+        macro_name: &str,
+        context: &Context,
+        settings: &process::Settings,
+    ) -> Result<(), String> {
+        match self {
+            Action::Command(command) => {
+                process::log("resolved", macro_name, format_args!("command={command:?}"));
+                let mut shell = std::process::Command::new("sh");
+                shell.arg("-c").arg(command);
+                settings.env.apply(&mut shell);
+                process::prepare(&mut shell, settings)
+                    .map_err(|err| format!("`{command}` failed to start: {err}"))?;
+                let child = shell
+                    .spawn()
+                    .map_err(|err| format!("`{command}` failed to start: {err}"))?;
+                process::log(
+                    "spawned",
+                    macro_name,
+                    format_args!("pid={pid} command={command:?}", pid = child.id()),
+                );
+                let status =
+                    process::supervise(child, macro_name, settings.limits, &context.commands)
+                        .map_err(|err| format!("Failed to wait for `{command}`: {err}"))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(format!("`{command}` failed: {status}"))
+                }
+            }
+            Action::SendKeys(events) => {
+                let per_event = context.syn_reports == SynReports::PerEvent;
+                let mut events = events.iter().peekable();
+                while let Some((key, value)) = events.next() {
+                    let device = context.device(*key)?;
+                    let mut report = vec![InputEvent::new(EventType::KEY, key.code(), *value)];
+                    // A report can't span devices.
+                    while let Some((next, value)) = events.peek().filter(|_| !per_event) {
+                        if !Arc::ptr_eq(context.device(*next)?, device) {
+                            break;
+                        }
+                        report.push(InputEvent::new(EventType::KEY, next.code(), *value));
+                        events.next();
+                    }
+                    // `emit` ends the report with a SYN_REPORT.
+                    device
+                        .lock()
+                        .unwrap()
+                        .emit(&report)
+                        .map_err(|err| format!("Failed to send {key:?}: {err}"))?;
+                }
+                Ok(())
+            }
+            Action::SetLed { led, on } => {
+                let events = [
+                    InputEvent::new(EventType::LED, led.0, i32::from(*on)),
+                    InputEvent::new(EventType::SYNCHRONIZATION, 0, 0),
+                ];
+                let leds = context.leds.lock().unwrap();
+                if leds.is_empty() {
+                    log!("{macro_name} macro: not setting {led:?}, no device is connected");
+                }
+                for fd in leds.iter() {
+                    source::write_events(fd, &events)
+                        .map_err(|err| format!("Failed to set {led:?}: {err}"))?;
+                }
+                Ok(())
+            }
+            Action::Notify {
+                summary,
+                body,
+                urgency,
+                icon,
+                buttons,
+            } => {
+                context
+                    .commands
+                    .send(Command::Notify {
+                        summary: summary.clone(),
+                        body: body.clone(),
+                        urgency: *urgency,
+                        icon: icon.clone(),
+                        buttons: buttons.clone(),
+                    })
+                    .ok();
+                Ok(())
+            }
+            Action::Http(request) => {
+                let status = request.send()?;
+                log!(
+                    "{macro_name} macro: {method} {url}: {status}",
+                    method = request.method,
+                    url = request.url
+                );
+                Ok(())
+            }
+            Action::Dbus(call) => {
+                call.send(&settings.env)?;
+                log!("{macro_name} macro: called {call}");
+                Ok(())
+            }
+            Action::WriteFile(write) => write.run(macro_name),
+            Action::Sleep(duration) => {
+                std::thread::sleep(*duration);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What a macro runs on its worker thread, see [`run`].
+#[derive(Debug)]
+pub struct Sequence {
+    pub actions: Vec<Action>,
+    pub continue_on_error: bool,
+    /// See `on_success` and `on_failure` in `config.rs`.
+    pub on_success: Vec<Action>,
+    pub on_failure: Vec<Action>,
+}
+
+/// Run the actions of `sequence` in order on a new thread, and then its
+/// `on_success` or `on_failure` actions.
+pub fn run(
+    macro_name: String,
+    sequence: Sequence,
+    context: Context,
+    settings: process::Settings,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let run_all = |actions| {
+            run_all(
+                &macro_name,
+                actions,
+                sequence.continue_on_error,
+                &context,
+                &settings,
+            )
+        };
+        let (branch, actions) = if run_all(sequence.actions) {
+            ("on_success", sequence.on_success)
+        } else {
+            ("on_failure", sequence.on_failure)
+        };
+        if !actions.is_empty() {
+            log!("{macro_name} macro: running {branch}");
+            run_all(actions);
+        }
+    })
+}
+
+/// Run the `on_press` actions of `held` on a new thread, then its `interval`
+/// actions until `stop` disconnects, and then its `on_release` actions.
+pub fn while_held(
+    macro_name: String,
+    held: WhileHeld,
+    context: Context,
+    settings: process::Settings,
+    stop: Receiver<()>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut succeeded = run_all(&macro_name, held.on_press, false, &context, &settings);
+        if let Some(interval) = held.interval {
+            while succeeded && stop.recv_timeout(interval.every) == Err(RecvTimeoutError::Timeout) {
+                let actions = interval.actions.clone();
+                succeeded = run_all(&macro_name, actions, false, &context, &settings);
+            }
+        }
+        // Returns once the key is released.
+        stop.recv().ok();
+        run_all(&macro_name, held.on_release, false, &context, &settings);
+    })
+}
+
+/// Run `actions` in order, reporting errors. Returns whether they all succeeded.
+fn run_all(
+    macro_name: &str,
+    actions: Vec<Action>,
+    continue_on_error: bool,
+    context: &Context,
+    settings: &process::Settings,
+) -> bool {
+    let mut succeeded = true;
+    for action in &batch(actions, context.syn_reports) {
+        if let Err(err) = action.run(macro_name, context, settings) {
+            log!("{macro_name} macro: {err}");
+            context
+                .commands
+                .send(Command::Notify {
+                    summary: format!("Error executing {macro_name} macro"),
+                    body: err,
+                    urgency: None,
+                    icon: None,
+                    buttons: Vec::new(),
+                })
+                .ok();
+            succeeded = false;
+            if !continue_on_error {
+                break;
+            }
+        }
+    }
+    succeeded
+}
+
+/// Run `actions` again and again on a new thread, until `stop` disconnects.
+///
+/// The first run is up to the caller, so this waits for the `repeat` delay first.
+/// Stops at the first error, which the first run will already have reported.
+pub fn repeat(
+    macro_name: String,
+    actions: Vec<Action>,
+    repeat: Repeat,
+    context: Context,
+    settings: process::Settings,
+    stop: Receiver<()>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let actions = batch(actions, context.syn_reports);
+        let interval = Duration::from_secs_f64(1.0 / repeat.rate);
+        let mut wait = repeat.delay;
+        // Dropping the sender wakes us up right away, so releasing the key
+        // stops the repeat without waiting out the interval.
+        while stop.recv_timeout(wait) == Err(RecvTimeoutError::Timeout) {
+            for action in &actions {
+                if let Err(err) = action.run(&macro_name, &context, &settings) {
+                    log!("{macro_name} macro: {err} - no longer repeating it");
+                    return;
+                }
+            }
+            wait = interval;
+        }
+    })
+}
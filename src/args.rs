@@ -0,0 +1,145 @@
+//! Command line arguments.
+
+use evdev_macros::log::Timestamps;
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: evdev-macros [OPTIONS]
+
+Options:
+    --interactive      Pick the keyboard to grab and store it in config.toml
+    --validate-config  Check config.toml for errors and exit
+    --check            Check that the configured devices can be read and grabbed,
+                       and virtual devices created, and exit
+    --print-config     Print the configuration in effect, with all includes and
+                       defaults resolved, and exit
+    --no-grab          Don't grab the keyboard: macros still run, but key presses
+                       also reach other applications as usual
+    --once KEY         Run the macro for KEY (e.g. KEY_F1) as if it was pressed,
+                       wait for it to finish and exit, without grabbing anything
+    --dry-run          Only print which macros would run
+    --once-per-device[=COUNT]
+                       Read the configured devices as a dry run, and print for
+                       each key event which device it came from and which macro
+                       it resolved to (e.g. in the layer of the device), until
+                       COUNT keys (10 by default) were released or a minute passed
+    --test SESSION     Replay the events recorded from the mirror pipe in SESSION
+                       as a dry run, print what each key event would have done
+                       and exit, e.g. to compare the output with an expected one
+    -v, --verbose      Log more, repeat for even more: -vv also logs the events
+                       that aren't keys (e.g. MSC_SCAN)
+    --foreground-log-timestamps[=FORMAT]
+                       Start log lines with a timestamp: rfc3339 (local time,
+                       the default), monotonic (seconds since boot) or none.
+                       Leave it off under journald, which adds its own
+    --no-notify        Don't show any desktop notifications
+    --test-notify      Show sample notifications and exit
+    --allow-insecure-scripts
+                       Also run macro scripts that are world-writable or owned
+                       by another user
+    --safe-mode        Grab the keyboard, but don't run any macros: only ESC
+                       works, to quit. Holding ESC while starting does the same
+    --uniq UNIQ        Only use the device with this unique id (serial), overriding
+                       `uniq` in config.toml
+    --config-dir DIR   Read config.toml and the macros from DIR instead of
+                       ~/.config/evdev-macros (or $EVDEV_MACROS_CONFIG_DIR)
+    --init             Create the config directory if it doesn't exist
+    -h, --help         Print this help";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub interactive: bool,
+    pub validate_config: bool,
+    pub print_config: bool,
+    pub check: bool,
+    /// Read the device without grabbing it, so its events also reach other applications.
+    pub no_grab: bool,
+    /// Run this macro and exit, instead of reading any device.
+    pub once: Option<String>,
+    pub dry_run: bool,
+    /// Print which device each key came from and its macro, for this many keys.
+    pub once_per_device: Option<usize>,
+    /// Replay this recorded session and exit, see `replay.rs`.
+    pub test: Option<PathBuf>,
+    /// How often `-v` was given.
+    pub verbose: u8,
+    pub log_timestamps: Timestamps,
+    /// Disable all macros, to recover from a broken config.
+    pub safe_mode: bool,
+    pub allow_insecure_scripts: bool,
+    pub no_notify: bool,
+    pub test_notify: bool,
+    pub uniq: Option<String>,
+    pub config_dir: Option<PathBuf>,
+    pub init: bool,
+}
+
+impl Args {
+    pub fn parse() -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut arguments = std::env::args().skip(1);
+        while let Some(arg) = arguments.next() {
+            match arg.as_str() {
+                "--interactive" => args.interactive = true,
+                "--validate-config" => args.validate_config = true,
+                "--print-config" => args.print_config = true,
+                "--check" => args.check = true,
+                "--no-grab" => args.no_grab = true,
+                "--once" => {
+                    let key = arguments
+                        .next()
+                        .ok_or_else(|| format!("--once needs a key name\n\n{USAGE}"))?;
+                    args.once = Some(key);
+                }
+                "--dry-run" => args.dry_run = true,
+                "--once-per-device" => args.once_per_device = Some(10),
+                other if other.starts_with("--once-per-device=") => {
+                    let (_, count) = other.split_once('=').unwrap();
+                    let count = count
+                        .parse()
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .ok_or_else(|| format!("Invalid key count: {count}\n\n{USAGE}"))?;
+                    args.once_per_device = Some(count);
+                }
+                "--test" => {
+                    let session = arguments
+                        .next()
+                        .ok_or_else(|| format!("--test needs a session file\n\n{USAGE}"))?;
+                    args.test = Some(session.into());
+                }
+                "-v" | "--verbose" => args.verbose = args.verbose.saturating_add(1),
+                "-vv" => args.verbose = args.verbose.saturating_add(2),
+                "--safe-mode" => args.safe_mode = true,
+                "--allow-insecure-scripts" => args.allow_insecure_scripts = true,
+                "--no-notify" => args.no_notify = true,
+                "--test-notify" => args.test_notify = true,
+                "--uniq" => {
+                    let uniq = arguments
+                        .next()
+                        .ok_or_else(|| format!("--uniq needs a unique id\n\n{USAGE}"))?;
+                    args.uniq = Some(uniq);
+                }
+                "--config-dir" => {
+                    let dir = arguments
+                        .next()
+                        .ok_or_else(|| format!("--config-dir needs a directory\n\n{USAGE}"))?;
+                    args.config_dir = Some(dir.into());
+                }
+                "--init" => args.init = true,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                "--foreground-log-timestamps" => args.log_timestamps = Timestamps::Rfc3339,
+                other if other.starts_with("--foreground-log-timestamps=") => {
+                    let (_, format) = other.split_once('=').unwrap();
+                    args.log_timestamps =
+                        format.parse().map_err(|err| format!("{err}\n\n{USAGE}"))?;
+                }
+                other => return Err(format!("Unknown argument: {other}\n\n{USAGE}")),
+            }
+        }
+        Ok(args)
+    }
+}
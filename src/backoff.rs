@@ -0,0 +1,47 @@
+//! Increasing delays between retries, e.g. reconnecting a device that was unplugged.
+//!
+//! Each delay doubles (up to a cap), and is randomized by up to half, so
+//! devices that fail together, like the interfaces of a loose USB keyboard,
+//! don't keep retrying in lockstep.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(250);
+
+pub struct Backoff {
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    /// Delays never exceed `max`.
+    pub fn new(max: Duration) -> Backoff {
+        Backoff {
+            max,
+            next: INITIAL_DELAY.min(max),
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay.mul_f64(0.5 + random() / 2.0)
+    }
+
+    /// Start over after an attempt worked.
+    pub fn reset(&mut self) {
+        self.next = INITIAL_DELAY.min(self.max);
+    }
+}
+
+/// A random number in `0.0..1.0`, good enough for jitter.
+fn random() -> f64 {
+    // Each `RandomState` is seeded differently.
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
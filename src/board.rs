@@ -0,0 +1,2540 @@
+//! The macro engine, see [`MacroBoard`].
+
+use crate::{
+    actions::{self, Action, Button, Sequence, Urgency},
+    chord::{self, Chords},
+    clock::{Clock, SystemClock},
+    config::{
+        self, AxisConfig, ChordConflict, Config, DevicePanic, Edge, IndicateRunning, KeyValues,
+        Limits, MacroConfig, QuitConfirm, RateLimit, Selector, Toggle, WhileHeld,
+    },
+    dbus::DbusService,
+    device::Input,
+    history::{self, History, Outcome},
+    latency::Latency,
+    log,
+    log_format::LogFormat,
+    mirror::Mirror,
+    privileges::AsUser,
+    process, profile,
+    rate_limit::Bucket,
+    schedule::Schedule,
+    session, source,
+    state::State,
+    uinput,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError};
+use evdev::{
+    uinput::VirtualDevice, AbsoluteAxisType, AttributeSet, EventType, InputEvent, InputEventKind,
+    Key, LedType, MiscType,
+};
+use notify_rust::{Notification, NotificationHandle, Timeout};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How long the same notification isn't shown again, see [`MacroBoard::notify_with`].
+const NOTIFICATION_THROTTLE: Duration = Duration::from_secs(1);
+
+/// How long the `on_release` actions of keys held when we exit, and the
+/// `on_stop` macro, get to run, see [`MacroBoard::shut_down`].
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long [`MacroBoard::reload`] waits for the macro directory (e.g. on a
+/// network file system that hangs) before it carries on with the old macros.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Macro scripts by key name.
+pub(crate) type Macros = HashMap<String, Vec<Script>>;
+
+/// A file in the macro directory.
+pub(crate) struct Script {
+    pub path: PathBuf,
+    /// Whether it can run by itself, checked when it's loaded rather than when
+    /// it runs, see [`MacroBoard::interpreter`].
+    pub runs_directly: bool,
+}
+
+/// Why [`MacroBoard::run`] returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The grabbing threads are stopping too, so join them and exit.
+    Exit,
+    /// The devices are still grabbed: hand them to [`device::regrab`](crate::device::regrab),
+    /// and run again. Running macros carry on meanwhile.
+    Regrab,
+}
+
+/// Requests from the control interface, handled by [`MacroBoard::run`].
+pub enum Command {
+    RunMacro(String),
+    Reload,
+    SetPaused(bool),
+    SetArmed(bool),
+    /// Switch to this profile, or to none.
+    SetProfile(Option<String>),
+    NextProfile,
+    /// Select and grab the devices again, see [`Stop::Regrab`].
+    Regrab,
+    History(Sender<Vec<String>>),
+    /// See `latency.rs`.
+    Latency(Sender<Vec<String>>),
+    /// The configuration the board was built with, as TOML.
+    Config(Sender<String>),
+    /// Whether each toggle is on, by macro name.
+    Toggles(Sender<HashMap<String, bool>>),
+    /// Make the macro run this shell command instead, see [`MacroBoard::bindings`].
+    Bind {
+        macro_name: String,
+        command: String,
+    },
+    Unbind(String),
+    /// A run of this macro with `indicate_running` (see `config.rs`) is done.
+    MacroFinished(String),
+    /// Show a desktop notification, for threads that can't switch users themselves.
+    Notify {
+        summary: String,
+        body: String,
+        urgency: Option<Urgency>,
+        icon: Option<String>,
+        buttons: Vec<Button>,
+    },
+    /// Does nothing, but makes [`MacroBoard::run`] check its [`Signals`] right away.
+    Wake,
+}
+
+/// The thread reading the macro directory, see [`MacroBoard::reload`].
+pub(crate) struct Loading {
+    receiver: Receiver<Result<Macros, String>>,
+    started: Instant,
+    /// Whether we said that it takes more than [`LOAD_TIMEOUT`].
+    warned: bool,
+    /// Whether there was another reload meanwhile, which reads the directory
+    /// again once this is done.
+    again: bool,
+}
+
+/// The `indicate_running` of a macro, while it's running.
+pub(crate) struct Indicator {
+    config: IndicateRunning,
+    /// How many runs of the macro are still going.
+    runs: usize,
+    notification: Option<NotificationHandle>,
+}
+
+/// Triggers of a macro with `coalesce` that haven't run yet.
+pub(crate) struct Coalescing {
+    count: usize,
+    /// When it runs, unless it's triggered again before.
+    due: Instant,
+    /// The extra variables of the last trigger, like those of axis zones.
+    env: Vec<(String, String)>,
+}
+
+/// Wakes up [`MacroBoard::run`] from another thread, see [`MacroBoard::waker`].
+#[derive(Clone)]
+pub struct Waker(Sender<Command>);
+
+impl Waker {
+    pub fn wake(&self) {
+        self.0.send(Command::Wake).ok();
+    }
+}
+
+/// The engine: turns key events and [`Command`]s into macro runs.
+///
+/// Created with [`MacroBoard::builder`] and driven by [`MacroBoard::run`].
+pub struct MacroBoard {
+    pub(crate) receiver: Receiver<Input>,
+    pub(crate) commands: Receiver<Command>,
+    /// For worker threads that report back to the main loop.
+    pub(crate) command_sender: Sender<Command>,
+    pub(crate) dbus: Option<DbusService>,
+
+    /// Macro scripts by key name, see [`MacroBoard::reload`].
+    pub(crate) macros: Macros,
+    /// A reload that is still reading the macro directory, see [`MacroBoard::reload`].
+    pub(crate) loading: Option<Loading>,
+    /// Shell commands that replace the scripts and actions of macros, by macro
+    /// name, until the next reload (unless `keep_bindings`). Made at runtime with
+    /// [`Command::Bind`], for trying out a binding without editing files.
+    pub(crate) bindings: HashMap<String, String>,
+    pub(crate) keep_bindings: bool,
+    /// Per-macro settings by macro name.
+    pub(crate) macro_configs: HashMap<String, MacroConfig>,
+    /// What to do about macros that run too long, unless overridden per macro.
+    pub(crate) macro_limits: Limits,
+    /// Who macros run as (unless they have a `run_as`), if we're root and have to switch.
+    pub(crate) user: Option<Arc<process::Identity>>,
+    /// Shared with the threads running configured `actions`.
+    pub(crate) action_context: actions::Context,
+    /// Threads waiting for scripts or running actions, pruned as they finish.
+    pub(crate) workers: Vec<JoinHandle<()>>,
+    /// The workers of the last run of each macro with `single_instance`, by name.
+    pub(crate) instances: HashMap<String, Vec<JoinHandle<()>>>,
+    /// Only log which macros would run.
+    pub(crate) dry_run: bool,
+    /// How much to log beyond the usual, see [`MacroBoardBuilder::verbosity`].
+    pub(crate) verbosity: u8,
+    /// Whether to show desktop notifications at all.
+    pub(crate) notifications: bool,
+    /// The last notification we showed, and when, see [`MacroBoard::notify_with`].
+    pub(crate) last_notification: RefCell<Option<(String, String, Instant)>>,
+    /// Whether to show one once the devices are grabbed.
+    pub(crate) startup_notification: bool,
+    /// Whether to show one when quitting with ESC.
+    pub(crate) quit_notification: bool,
+    /// See `on_start` and `on_stop` in `config.rs`.
+    pub(crate) on_start: Option<String>,
+    pub(crate) on_stop: Option<String>,
+    /// See `quiet_hours` in `config.rs`.
+    pub(crate) quiet_hours: Option<Schedule>,
+    pub(crate) quit_confirm: QuitConfirm,
+    pub(crate) quit_confirm_window: Duration,
+    pub(crate) esc_passthrough: bool,
+    /// When ESC was pressed last (for [`QuitConfirm::Hold`]), or released
+    /// without quitting (for [`QuitConfirm::DoubleTap`]).
+    pub(crate) quit_started: Option<SystemTime>,
+    /// The line logged for each key event.
+    pub(crate) log_format: LogFormat,
+    pub(crate) key_values: KeyValues,
+    pub(crate) ignore_repeats: bool,
+    pub(crate) history: History,
+    pub(crate) latency: RefCell<Latency>,
+    pub(crate) mirror: Option<Mirror>,
+    /// The config we were built with, as TOML, for the D-Bus interface.
+    pub(crate) config: String,
+    /// Keys that are currently held down.
+    pub(crate) pressed: AttributeSet<Key>,
+    /// Keys whose macro is repeating while they're held, see `repeat` in `config.rs`.
+    /// Dropping the sender stops the repeat.
+    pub(crate) repeating: HashMap<Key, Sender<()>>,
+    /// Whether the toggles that were triggered since we started are on.
+    pub(crate) toggles: HashMap<String, bool>,
+    /// Of the macros with `indicate_running` that are running, by name.
+    pub(crate) indicators: HashMap<String, Indicator>,
+    /// Of the macros with `coalesce` that were triggered, by name.
+    pub(crate) coalescing: HashMap<String, Coalescing>,
+    /// See [`MacroBoardBuilder::clock`].
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) chords: Chords,
+    /// See `env_clear` and `keep_env` in `config.rs`.
+    pub(crate) env_clear: bool,
+    pub(crate) keep_env: Vec<String>,
+    /// See `interpreters` in `config.rs`.
+    pub(crate) interpreters: BTreeMap<String, String>,
+    /// The variables of the user's session, see `session.rs`.
+    pub(crate) session: session::Shared,
+    /// Pass modifiers on to the virtual keyboard, see [`MacroBoard::forward_modifier`].
+    pub(crate) forward_modifiers: bool,
+    /// What to do when a key with its own macro completes a chord,
+    /// unless overridden per macro.
+    pub(crate) chord_conflicts: ChordConflict,
+    pub(crate) max_event_age: Option<Duration>,
+    pub(crate) rate_limit: RateLimit,
+    pub(crate) bucket: Bucket,
+    /// Triggers dropped by the `rate_limit` since the last run.
+    pub(crate) rate_limited: usize,
+    pub(crate) axes: Vec<(AbsoluteAxisType, AxisConfig)>,
+    /// The MSC_SCAN of the current report of each device, for `EVDEV_SCANCODE`.
+    pub(crate) scancodes: HashMap<String, i32>,
+    /// Current zone of each axis in `axes`, by axis code.
+    pub(crate) axis_zones: HashMap<u16, usize>,
+    /// If set, only these keys trigger macros.
+    pub(crate) allowed_keys: Option<Vec<Key>>,
+    /// These keys never trigger macros.
+    pub(crate) denied_keys: Vec<Key>,
+    /// These keys go to the passthrough device, see `passthrough.rs`.
+    pub(crate) forward_keys: Vec<Key>,
+    /// And these event types.
+    pub(crate) forward_types: Vec<config::ForwardType>,
+    pub(crate) reconnect_max_delay: Duration,
+    pub(crate) regrab_on_resume: bool,
+    pub(crate) flush_on_start: bool,
+    pub(crate) resync_interval: Option<Duration>,
+    pub(crate) on_device_panic: DevicePanic,
+
+    pub(crate) quit: bool,
+    /// Set by [`Command::Regrab`], makes [`MacroBoard::run`] return [`Stop::Regrab`].
+    pub(crate) regrab: bool,
+    pub(crate) paused: bool,
+    /// No macros at all, only ESC to quit. See [`crate::device::SAFE_MODE_KEY`].
+    pub(crate) safe_mode: bool,
+    /// Run scripts that others can modify, see `insecure`.
+    pub(crate) allow_insecure_scripts: bool,
+    /// Persisted across restarts, see [`MacroBoard::set_armed`].
+    pub(crate) state: State,
+    /// Toggles between armed and disarmed.
+    pub(crate) arm_key: Option<Key>,
+    /// Switches to the next profile.
+    pub(crate) profile_key: Option<Key>,
+
+    /// The devices to grab, in order of priority.
+    pub(crate) selectors: Vec<Selector>,
+}
+
+impl MacroBoard {
+    fn execute_script(
+        &self,
+        macro_name: &str,
+        script: &Script,
+        settings: &process::Settings,
+    ) -> io::Result<Option<JoinHandle<()>>> {
+        let started = self.clock.now();
+        let path = &script.path;
+        if self.dry_run {
+            log!("Dry run - not running macro: {path}", path = path.display());
+            return Ok(None);
+        }
+        let interpreter = self.interpreter(script);
+        match interpreter {
+            Some(interpreter) => log!(
+                "Running macro with {interpreter}: {path}",
+                path = path.display()
+            ),
+            None => log!("Running macro: {path}", path = path.display()),
+        }
+        process::log("resolved", macro_name, format_args!("script={path:?}"));
+
+        let mut command = match interpreter {
+            Some(interpreter) => {
+                let mut words = interpreter.split_whitespace();
+                let mut command = std::process::Command::new(words.next().unwrap_or_default());
+                command.args(words).arg(path);
+                command
+            }
+            None => std::process::Command::new(path),
+        };
+        settings.env.apply(&mut command);
+        process::prepare(&mut command, settings)?;
+        let result = command.spawn();
+        if result.is_ok() {
+            let spawned = self.clock.now().saturating_duration_since(started);
+            self.latency.borrow_mut().spawns.record(spawned);
+        }
+
+        result.map(|child| {
+            process::log(
+                "spawned",
+                macro_name,
+                format_args!("pid={pid} script={path:?}", pid = child.id()),
+            );
+            Some(process::watch(
+                child,
+                macro_name.to_string(),
+                settings.limits,
+                self.command_sender.clone(),
+            ))
+        })
+    }
+
+    /// The interpreter for `script`, if it can't run by itself and there's one
+    /// for its extension, see `interpreters` in `config.rs`.
+    fn interpreter(&self, script: &Script) -> Option<&str> {
+        let interpreter = self.interpreters.get(script.path.extension()?.to_str()?)?;
+        (!script.runs_directly).then_some(interpreter.as_str())
+    }
+
+    /// Whether `path` is executable, and a program or a script with a shebang.
+    fn runs_directly(path: &Path) -> bool {
+        use std::{io::Read, os::unix::fs::PermissionsExt};
+
+        let executable = std::fs::metadata(path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+        let mut magic = [0; 4];
+        executable
+            && std::fs::File::open(path)
+                .and_then(|mut file| file.read_exact(&mut magic))
+                .is_ok()
+            && (magic.starts_with(b"#!") || magic == *b"\x7fELF")
+    }
+
+    /// Why the script at `path` can't be trusted to run, if it can't.
+    ///
+    /// Like ssh with its keys, we only accept scripts that nobody but the user
+    /// (and root) can have put there.
+    fn insecure(path: &Path) -> Option<String> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => return Some(err.to_string()),
+        };
+        if metadata.mode() & 0o002 != 0 {
+            Some("it is world-writable".to_string())
+        } else if metadata.uid() != users::get_current_uid() && metadata.uid() != 0 {
+            Some(format!("it is owned by uid {uid}", uid = metadata.uid()))
+        } else {
+            None
+        }
+    }
+
+    /// The scripts in `dir`, named after their file names with `prefix` in front.
+    fn load_scripts(dir: &Path, prefix: &str, allow_insecure: bool) -> io::Result<Macros> {
+        let mut macros = Macros::new();
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let path = entry.path();
+            // The config file, and any files it includes.
+            if path.extension().and_then(|s| s.to_str()) == Some("toml") || path.is_dir() {
+                continue;
+            }
+            if let Some(reason) = Self::insecure(&path).filter(|_| !allow_insecure) {
+                log!(
+                    "Not using {path}: {reason} (see --allow-insecure-scripts)",
+                    path = path.display()
+                );
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                let name = format!("{prefix}{name}");
+                let script = Script {
+                    runs_directly: Self::runs_directly(&path),
+                    path,
+                };
+                macros.entry(name).or_default().push(script);
+            }
+        }
+        Ok(macros)
+    }
+
+    /// Add the scripts of `layers` (see `layer` in `config.rs`), in their directories in `dir`.
+    fn load_layers(dir: &Path, layers: &[String], allow_insecure: bool, macros: &mut Macros) {
+        for layer in layers {
+            match Self::load_scripts(&dir.join(layer), &format!("{layer}/"), allow_insecure) {
+                Ok(scripts) => macros.extend(scripts),
+                // Its macros may all be in the config.
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => log!(
+                    "Ignoring layer {layer} in {dir}: {err}",
+                    dir = dir.display()
+                ),
+            }
+        }
+    }
+
+    /// The shared macros, overridden by those of `profile`, each with their `layers`.
+    fn load_macros(
+        profile: Option<&str>,
+        layers: &[String],
+        allow_insecure: bool,
+    ) -> Result<Macros, String> {
+        let dir = config::config_dir().map_err(|err| err.to_string())?;
+        let mut macros =
+            Self::load_scripts(&dir, "", allow_insecure).map_err(|err| err.to_string())?;
+        Self::load_layers(&dir, layers, allow_insecure, &mut macros);
+        if let Some(profile) = profile {
+            let dir = profile::dir(profile).map_err(|err| err.to_string())?;
+            // Better the shared macros than none, if the profile was removed.
+            match Self::load_scripts(&dir, "", allow_insecure) {
+                Ok(scripts) => {
+                    macros.extend(scripts);
+                    Self::load_layers(&dir, layers, allow_insecure, &mut macros);
+                }
+                Err(err) => log!("Ignoring profile {profile}: {err}"),
+            }
+        }
+        Ok(macros)
+    }
+
+    /// Re-scan the macro directory.
+    ///
+    /// Macros are looked up in memory when a key is pressed, so new or removed
+    /// scripts only take effect after a reload (SIGHUP or the D-Bus `Reload` method).
+    /// Only starting them touches the file system.
+    ///
+    /// The directory is read on another thread, and the macros are replaced
+    /// once it's done: while [`MacroBoard::run`] or [`MacroBoard::process_pending`]
+    /// handle events, or with [`MacroBoard::wait_for_macros`]. Until then, we
+    /// keep the macros we have. A reload while the last one is still reading
+    /// only starts when it's done, so a hung file system only ever holds up
+    /// one thread.
+    pub fn reload(&mut self) {
+        if !self.keep_bindings && !self.bindings.is_empty() {
+            log!(
+                "Removing {count} binding(s) made at runtime",
+                count = self.bindings.len()
+            );
+            self.bindings.clear();
+        }
+        if let Some(loading) = &mut self.loading {
+            log!("Still waiting for the last reload to read the macro directory");
+            loading.again = true;
+            return;
+        }
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let profile = self.state.profile.clone();
+        let layers: Vec<String> = self
+            .selectors
+            .iter()
+            .filter_map(|selector| selector.layer.clone())
+            .collect();
+        let allow_insecure = self.allow_insecure_scripts;
+        std::thread::spawn(move || {
+            sender
+                .send(Self::load_macros(
+                    profile.as_deref(),
+                    &layers,
+                    allow_insecure,
+                ))
+                .ok();
+        });
+        self.loading = Some(Loading {
+            receiver,
+            started: Instant::now(),
+            warned: false,
+            again: false,
+        });
+    }
+
+    /// Apply the macros of the last reload if it's done, without waiting.
+    fn poll_macros(&mut self) {
+        let Some(loading) = &mut self.loading else {
+            return;
+        };
+        let result = match loading.receiver.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => {
+                if !loading.warned && loading.started.elapsed() >= LOAD_TIMEOUT {
+                    loading.warned = true;
+                    log!(
+                        "Reading the macro directory takes more than {LOAD_TIMEOUT:?}, \
+                         keeping the macros we have (is it on a file system that hangs?)"
+                    );
+                }
+                return;
+            }
+            Err(TryRecvError::Disconnected) => Err("reading them panicked".to_string()),
+        };
+        self.loaded(result);
+    }
+
+    /// Wait for the last reload to finish, up to [`LOAD_TIMEOUT`] after it
+    /// started, and apply its macros. For before handling events, e.g. on startup.
+    pub fn wait_for_macros(&mut self) {
+        let Some(loading) = &self.loading else {
+            return;
+        };
+        let left = LOAD_TIMEOUT.saturating_sub(loading.started.elapsed());
+        let result = match loading.receiver.recv_timeout(left) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                // Warns about it.
+                self.poll_macros();
+                return;
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("reading them panicked".to_string()),
+        };
+        self.loaded(result);
+    }
+
+    /// The last reload is done, with `result`.
+    fn loaded(&mut self, result: Result<Macros, String>) {
+        let again = self.loading.take().is_some_and(|loading| loading.again);
+        match result {
+            Ok(macros) => {
+                log!("Loaded macros for {} keys", macros.len());
+                self.macros = macros;
+            }
+            Err(err) => log!("Failed to load macros: {err}"),
+        }
+        // Chords in `[macros]` work without the directory.
+        self.chords
+            .set_macros(self.macros.keys().chain(self.macro_configs.keys()));
+        self.check_chord_conflicts();
+        if again {
+            self.reload();
+        }
+    }
+
+    /// The environment for the processes of `macro_name`, with `extra` variables.
+    fn macro_env(&self, macro_name: &str, extra: &[(&str, String)]) -> process::Env {
+        let clear = self
+            .macro_configs
+            .get(macro_name)
+            .and_then(|config| config.env_clear)
+            .unwrap_or(self.env_clear);
+        let session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        process::Env::new(clear, &self.keep_env, &session, extra)
+    }
+
+    /// How to start and supervise the processes of `macro_name`.
+    fn macro_settings(&self, macro_name: &str, extra: &[(&str, String)]) -> process::Settings {
+        let config = self.macro_configs.get(macro_name);
+        process::Settings {
+            env: self.macro_env(macro_name, extra),
+            limits: config.map_or(self.macro_limits, |config| config.limits(self.macro_limits)),
+            user: self.user.clone(),
+            run_as: config.and_then(|config| config.run_as.clone()),
+            nice: config.and_then(|config| config.nice),
+            ionice: config.and_then(|config| config.ionice),
+        }
+    }
+
+    /// Returns the number of scripts that were run, counting a configured
+    /// sequence of `actions` as one.
+    fn run_macro(
+        &mut self,
+        macro_name: &str,
+        env: &[(&str, String)],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        if self.still_running(macro_name) {
+            return Ok(0);
+        }
+        let bound = self.bindings.get(macro_name).map(|command| Sequence {
+            actions: vec![Action::Command(command.clone())],
+            continue_on_error: false,
+            on_success: Vec::new(),
+            on_failure: Vec::new(),
+        });
+        let is_bound = bound.is_some();
+        let actions = bound.or_else(|| self.macro_actions(macro_name));
+        let scripts = if is_bound {
+            &[][..]
+        } else {
+            self.macros.get(macro_name).map_or(&[][..], Vec::as_slice)
+        };
+        if scripts.is_empty() && actions.is_none() {
+            return Ok(0);
+        }
+        let settings = self.macro_settings(macro_name, env);
+        let mut workers = Vec::new();
+        // The scripts that did start are still tracked below.
+        let mut failed = None;
+        for script in scripts {
+            match self.execute_script(macro_name, script, &settings) {
+                Ok(worker) => workers.extend(worker),
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        let count = scripts.len() + usize::from(actions.is_some());
+        if let Some(sequence) = actions.filter(|_| failed.is_none()) {
+            if self.dry_run {
+                log!("Dry run - not running actions of macro {macro_name}: {sequence:?}");
+            } else {
+                log!("Running actions of macro: {macro_name}");
+                workers.push(actions::run(
+                    macro_name.to_string(),
+                    sequence,
+                    self.action_context.clone(),
+                    settings,
+                ));
+            }
+        }
+
+        if let Some(dbus) = &self.dbus {
+            dbus.macro_triggered(macro_name);
+        }
+        if !workers.is_empty() {
+            let indicate = self
+                .macro_configs
+                .get(macro_name)
+                .and_then(|config| config.indicate_running.clone());
+            if let Some(indicate) = indicate {
+                workers = vec![self.indicate_running(macro_name, indicate, workers)];
+            }
+        }
+        self.workers.retain(|worker| !worker.is_finished());
+        let single_instance = self
+            .macro_configs
+            .get(macro_name)
+            .is_some_and(|config| config.single_instance);
+        if single_instance {
+            self.instances.insert(macro_name.to_string(), workers);
+        } else {
+            self.workers.extend(workers);
+        }
+        match failed {
+            Some(err) => Err(err.into()),
+            None => Ok(count),
+        }
+    }
+
+    /// Whether `macro_name` has `single_instance` and is still running, so it
+    /// shouldn't run again.
+    fn still_running(&self, macro_name: &str) -> bool {
+        let running = self
+            .instances
+            .get(macro_name)
+            .is_some_and(|workers| !workers.iter().all(JoinHandle::is_finished));
+        if running {
+            log!("{macro_name} is still running - not running it again (single_instance)");
+        }
+        running
+    }
+
+    /// Whether all scripts and actions are done.
+    fn workers_finished(&self) -> bool {
+        self.workers
+            .iter()
+            .chain(self.instances.values().flatten())
+            .all(JoinHandle::is_finished)
+    }
+
+    /// The configured actions of `macro_name`, if it has any. For a toggle,
+    /// that's the ones for its next state, which it's switched to.
+    fn macro_actions(&mut self, macro_name: &str) -> Option<Sequence> {
+        let config = self.macro_configs.get(macro_name)?;
+        let sequence = |actions| Sequence {
+            actions,
+            continue_on_error: config.continue_on_error,
+            on_success: config.on_success.clone(),
+            on_failure: config.on_failure.clone(),
+        };
+        let Some(toggle) = config.toggle.clone() else {
+            return Some(config.actions.clone())
+                .filter(|actions| !actions.is_empty())
+                .map(sequence);
+        };
+        let sequence = sequence(Vec::new());
+        let on = !self.toggle_state(macro_name, &toggle);
+        log!("{macro_name} toggled {}", if on { "on" } else { "off" });
+        self.toggles.insert(macro_name.to_string(), on);
+        if toggle.persist && !self.dry_run {
+            self.state.toggles.insert(macro_name.to_string(), on);
+            self.save_state();
+        }
+        let mut actions = Vec::new();
+        if let Some(led) = toggle.led {
+            actions.push(Action::SetLed { led, on });
+        }
+        actions.extend(if on { toggle.on } else { toggle.off });
+        Some(Sequence {
+            actions,
+            ..sequence
+        })
+    }
+
+    /// Whether the toggle of `macro_name` is on.
+    fn toggle_state(&self, macro_name: &str, toggle: &Toggle) -> bool {
+        self.toggles
+            .get(macro_name)
+            .or_else(|| {
+                self.state
+                    .toggles
+                    .get(macro_name)
+                    .filter(|_| toggle.persist)
+            })
+            .copied()
+            .unwrap_or(toggle.initial)
+    }
+
+    /// All toggles and whether they're on, by macro name.
+    fn toggles(&self) -> HashMap<String, bool> {
+        self.macro_configs
+            .iter()
+            .filter_map(|(name, config)| {
+                let toggle = config.toggle.as_ref()?;
+                Some((name.clone(), self.toggle_state(name, toggle)))
+            })
+            .collect()
+    }
+
+    /// Light the LEDs of the toggles that are on (and switch off the others),
+    /// once the devices are grabbed.
+    pub(crate) fn show_toggles(&self) {
+        for (name, config) in &self.macro_configs {
+            let Some(toggle) = &config.toggle else {
+                continue;
+            };
+            let Some(led) = toggle.led else {
+                continue;
+            };
+            self.set_led(led, self.toggle_state(name, toggle), name);
+        }
+    }
+
+    /// Switch `led` of the grabbed keyboards for `macro_name`.
+    fn set_led(&self, led: LedType, on: bool, macro_name: &str) {
+        let events = [
+            InputEvent::new(EventType::LED, led.0, i32::from(on)),
+            InputEvent::new(EventType::SYNCHRONIZATION, 0, 0),
+        ];
+        for fd in self.action_context.leds.lock().unwrap().iter() {
+            if let Err(err) = source::write_events(fd, &events) {
+                log!("Failed to set {led:?} for {macro_name}: {err}");
+            }
+        }
+    }
+
+    /// Start the indicator of `macro_name`, unless another run already did.
+    /// Returns the worker that ends this run's share of it once `workers` finished.
+    fn indicate_running(
+        &mut self,
+        macro_name: &str,
+        config: IndicateRunning,
+        workers: Vec<JoinHandle<()>>,
+    ) -> JoinHandle<()> {
+        if !self.indicators.contains_key(macro_name) {
+            if let Some(led) = config.led {
+                self.set_led(led, true, macro_name);
+            }
+            let notification = config
+                .notification
+                .as_deref()
+                .filter(|summary| self.notifications && !self.is_quiet(summary, ""))
+                .and_then(|summary| {
+                    self.show_progress(summary)
+                        .map_err(|err| log!("Failed to show notification: {err}"))
+                        .ok()
+                });
+            self.indicators.insert(
+                macro_name.to_string(),
+                Indicator {
+                    config,
+                    runs: 0,
+                    notification,
+                },
+            );
+        }
+        if let Some(indicator) = self.indicators.get_mut(macro_name) {
+            indicator.runs += 1;
+        }
+        let macro_name = macro_name.to_string();
+        let commands = self.command_sender.clone();
+        std::thread::spawn(move || {
+            for worker in workers {
+                worker.join().ok();
+            }
+            commands.send(Command::MacroFinished(macro_name)).ok();
+        })
+    }
+
+    /// A run of `macro_name` is done, see [`MacroBoard::indicate_running`].
+    fn macro_finished(&mut self, macro_name: &str) {
+        let Some(indicator) = self.indicators.get_mut(macro_name) else {
+            return;
+        };
+        indicator.runs -= 1;
+        if indicator.runs > 0 {
+            return;
+        }
+        let Some(indicator) = self.indicators.remove(macro_name) else {
+            return;
+        };
+        if let Some(led) = indicator.config.led {
+            self.set_led(led, false, macro_name);
+            // In case it's also the LED of a toggle that is on.
+            self.show_toggles();
+        }
+        if let Some(notification) = indicator.notification {
+            notification.close();
+        }
+    }
+
+    /// The macro to run for `macro_name` right now: itself, or outside of its
+    /// `schedule` its `otherwise` macro (whatever that one's schedule) or none.
+    fn scheduled<'a>(&'a self, macro_name: &'a str) -> Option<&'a str> {
+        let config = self.macro_configs.get(macro_name);
+        match config.and_then(|config| config.schedule.as_ref()) {
+            Some(schedule) if !schedule.is_active() => {
+                config.and_then(|config| config.otherwise.as_deref())
+            }
+            _ => Some(macro_name),
+        }
+    }
+
+    fn trigger(&mut self, key_name: &str, env: &[(&str, String)]) -> Outcome {
+        let coalesce = self
+            .macro_configs
+            .get(key_name)
+            .and_then(|config| config.coalesce);
+        let Some(coalesce) = coalesce else {
+            return self.trigger_now(key_name, env);
+        };
+        let pending = self
+            .coalescing
+            .entry(key_name.to_string())
+            .or_insert_with(|| Coalescing {
+                count: 0,
+                due: self.clock.now(),
+                env: Vec::new(),
+            });
+        pending.count += 1;
+        pending.due = self.clock.now() + coalesce;
+        pending.env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        Outcome::Coalesced(pending.count)
+    }
+
+    /// Run the coalesced triggers of `key_name`, see `coalesce` in `config.rs`.
+    fn run_coalesced(&mut self, key_name: &str) -> Option<Outcome> {
+        let pending = self.coalescing.remove(key_name)?;
+        let mut env: Vec<_> = pending
+            .env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.clone()))
+            .collect();
+        env.push(("EVDEV_COUNT", pending.count.to_string()));
+        log!(
+            "{key_name} macro was triggered {count} time(s)",
+            count = pending.count
+        );
+        Some(
+            self.suspended()
+                .unwrap_or_else(|| self.trigger_now(key_name, &env)),
+        )
+    }
+
+    /// Run the coalesced triggers that are done waiting for more.
+    fn run_due_coalesced(&mut self) {
+        let now = self.clock.now();
+        let due: Vec<_> = self
+            .coalescing
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(key_name, _)| key_name.clone())
+            .collect();
+        for key_name in due {
+            // Failures are reported by `trigger_now`.
+            self.run_coalesced(&key_name);
+        }
+    }
+
+    fn trigger_now(&mut self, key_name: &str, env: &[(&str, String)]) -> Outcome {
+        let Some(macro_name) = self.scheduled(key_name).map(str::to_string) else {
+            log!("{key_name} macro is outside of its schedule");
+            return Outcome::Unscheduled;
+        };
+        if macro_name != key_name {
+            log!("{key_name} macro is outside of its schedule, running {macro_name}");
+        }
+        if self.still_running(&macro_name) {
+            return Outcome::StillRunning;
+        }
+        // Typing keys without macros mustn't use up the runs.
+        if self.runs_anything(&macro_name) && !self.within_rate_limit(&macro_name) {
+            return Outcome::RateLimited;
+        }
+        match self.run_macro(&macro_name, env) {
+            Ok(0) => Outcome::NoMacro,
+            Ok(scripts) => Outcome::Ran(scripts),
+            Err(err) => {
+                self.notify_error(&macro_name, err.as_ref());
+                Outcome::Failed(err.to_string())
+            }
+        }
+    }
+
+    /// Whether `macro_name` may run under the `rate_limit`. Only the first
+    /// trigger that's dropped is reported, and how many were once they run again.
+    fn within_rate_limit(&mut self, macro_name: &str) -> bool {
+        if self.bucket.take(self.clock.now()) {
+            if self.rate_limited > 0 {
+                log!(
+                    "Rate limit: dropped {count} trigger(s) before {macro_name}",
+                    count = self.rate_limited
+                );
+                self.rate_limited = 0;
+            }
+            return true;
+        }
+        self.rate_limited += 1;
+        if self.rate_limited > 1 {
+            return false;
+        }
+        let RateLimit { runs, per, pause } = self.rate_limit;
+        let summary = format!("evdev-macros: more than {runs} macro runs in {per:.0?}");
+        log!("{summary}, dropping {macro_name} and any more triggers");
+        let body = if pause && !self.paused {
+            log!("Paused");
+            self.paused = true;
+            "Macros are paused until they're resumed with the D-Bus SetPaused method"
+        } else {
+            "Triggers are dropped until it's below the rate_limit again"
+        };
+        self.notify_with(&summary, body, Some(Urgency::Critical), None, &[]);
+        false
+    }
+
+    fn notify(&self, summary: &str, body: &str) {
+        self.notify_with(summary, body, None, None, &[]);
+    }
+
+    /// Like [`MacroBoard::notify`], with an `urgency` and `icon` other than the
+    /// notification server's defaults.
+    ///
+    /// The same notification is only shown once per [`NOTIFICATION_THROTTLE`],
+    /// so e.g. a repeating macro doesn't flood the desktop.
+    fn notify_with(
+        &self,
+        summary: &str,
+        body: &str,
+        urgency: Option<Urgency>,
+        icon: Option<&str>,
+        buttons: &[Button],
+    ) {
+        if !self.notifications || self.is_quiet(summary, body) {
+            return;
+        }
+        let mut last = self.last_notification.borrow_mut();
+        if let Some((last_summary, last_body, shown)) = &*last {
+            if last_summary == summary
+                && last_body == body
+                && self.clock.now().duration_since(*shown) < NOTIFICATION_THROTTLE
+            {
+                return;
+            }
+        }
+        *last = Some((summary.to_string(), body.to_string(), self.clock.now()));
+        self.show_notification(summary, body, urgency, icon, buttons)
+            .ok();
+    }
+
+    /// Whether it's `quiet_hours`, in which case the notification is logged instead.
+    fn is_quiet(&self, summary: &str, body: &str) -> bool {
+        let quiet = self.quiet_hours.as_ref().is_some_and(Schedule::is_active);
+        if quiet {
+            let separator = if body.is_empty() { "" } else { ": " };
+            log!("Quiet hours - not showing notification: {summary}{separator}{body}");
+        }
+        quiet
+    }
+
+    /// Show a notification as the user, so it reaches their session bus.
+    fn show_notification(
+        &self,
+        summary: &str,
+        body: &str,
+        urgency: Option<Urgency>,
+        icon: Option<&str>,
+        buttons: &[Button],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _as_user = AsUser::switch()?;
+        let mut notification = Notification::new();
+        notification.summary(summary).body(body);
+        if let Some(urgency) = urgency {
+            notification.urgency(urgency.into());
+        }
+        if let Some(icon) = icon {
+            notification.icon(icon);
+        }
+        let mut buttons = buttons;
+        if !buttons.is_empty()
+            && !notify_rust::get_capabilities()
+                .is_ok_and(|capabilities| capabilities.iter().any(|name| name == "actions"))
+        {
+            log!(
+                "The notification server doesn't support buttons, showing {summary:?} without them"
+            );
+            buttons = &[];
+        }
+        // By index, as the server uses `default` for clicking the notification itself.
+        for (index, button) in buttons.iter().enumerate() {
+            notification.action(&index.to_string(), &button.label);
+        }
+        let handle = notification.show()?;
+        if !buttons.is_empty() {
+            self.wait_for_button(handle, summary, buttons.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Run the macro of the button of the notification that is clicked, if
+    /// any, on a thread that waits until the notification is closed.
+    fn wait_for_button(&self, handle: NotificationHandle, summary: &str, buttons: Vec<Button>) {
+        let commands = self.command_sender.clone();
+        let summary = summary.to_string();
+        std::thread::spawn(move || {
+            handle.wait_for_action(|action| {
+                let Some(button) = action
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| buttons.get(index))
+                else {
+                    return;
+                };
+                log!("Clicked {label:?} of {summary:?}", label = button.label);
+                if let Some(macro_name) = &button.run {
+                    commands.send(Command::RunMacro(macro_name.clone())).ok();
+                }
+            });
+        });
+    }
+
+    /// A notification for [`MacroBoard::indicate_running`], which stays until it's closed.
+    fn show_progress(
+        &self,
+        summary: &str,
+    ) -> Result<NotificationHandle, Box<dyn std::error::Error>> {
+        let _as_user = AsUser::switch()?;
+        Ok(Notification::new()
+            .summary(summary)
+            .urgency(Urgency::Low.into())
+            .timeout(Timeout::Never)
+            .show()?)
+    }
+
+    fn notify_error(&self, key_name: &str, err: &dyn std::error::Error) {
+        log!("Failed to execute macro: {err}");
+        self.notify(
+            format!("Error executing {key_name} macro").as_str(),
+            err.to_string().as_str(),
+        );
+    }
+
+    /// Save the state as the user, so they own the file. Not in a dry run,
+    /// which only shows what would happen.
+    fn save_state(&self) {
+        if self.dry_run {
+            return;
+        }
+        let result = AsUser::switch()
+            .map_err(Into::into)
+            .and_then(|_as_user| self.state.save());
+        if let Err(err) = result {
+            log!("Failed to save state: {err}");
+        }
+    }
+
+    /// Switch to another profile (or none) and remember it for the next start.
+    fn set_profile(&mut self, profile: Option<String>) {
+        if let Some(name) = &profile {
+            if !profile::dir(name).is_ok_and(|dir| dir.is_dir()) {
+                log!("Unknown profile: {name}");
+                self.notify("Unknown profile", name);
+                return;
+            }
+        }
+        let summary = match &profile {
+            Some(name) => format!("Switched to profile {name}"),
+            None => "Switched to shared macros only".to_string(),
+        };
+        log!("{summary}");
+        self.state.profile = profile;
+        self.save_state();
+        self.reload();
+        self.notify(&summary, "");
+    }
+
+    fn next_profile(&mut self) {
+        match profile::next(self.state.profile.as_deref()) {
+            Ok(Some(profile)) => self.set_profile(Some(profile)),
+            Ok(None) => log!("There are no profiles to switch to"),
+            Err(err) => log!("Failed to list profiles: {err}"),
+        }
+    }
+
+    /// Arm or disarm all macros and remember the choice for the next start.
+    ///
+    /// While disarmed, only the arm key and ESC do anything.
+    fn set_armed(&mut self, armed: bool) {
+        let summary = if armed {
+            "Macros armed"
+        } else {
+            "Macros disarmed"
+        };
+        log!("{summary}");
+        self.state.armed = armed;
+        self.save_state();
+        self.notify(summary, "");
+    }
+
+    /// Why key presses currently don't run macros, if they don't.
+    fn suspended(&self) -> Option<Outcome> {
+        if self.safe_mode {
+            log!("Safe mode - not running macro");
+            Some(Outcome::SafeMode)
+        } else if !self.state.armed {
+            log!("Disarmed - not running macro");
+            Some(Outcome::Disarmed)
+        } else if self.paused {
+            log!("Paused - not running macro");
+            Some(Outcome::Paused)
+        } else {
+            None
+        }
+    }
+
+    fn process_event(&mut self, event: InputEvent, device: &str, layer: Option<&str>) {
+        let age = self
+            .clock
+            .system_now()
+            .duration_since(event.timestamp())
+            .unwrap_or_default();
+        self.latency.get_mut().events.record(age);
+        if let Some(mirror) = &mut self.mirror {
+            mirror.write(&event, device);
+        }
+        if event.event_type() == EventType::MISC && event.code() == MiscType::MSC_SCAN.0 {
+            self.scancodes.insert(device.to_string(), event.value());
+        }
+        match event.kind() {
+            InputEventKind::Key(key) => self.process_key(event, key, device, layer),
+            InputEventKind::AbsAxis(axis) => self.process_axis(event, axis, device),
+            // Every batch of events ends with one, so they'd drown out everything else.
+            InputEventKind::Synchronization(_) => {
+                // A scancode only goes with the keys of its report.
+                self.scancodes.remove(device);
+            }
+            kind if self.verbosity >= 2 => log!(
+                at: event.timestamp(),
+                "Unhandled {kind:?} event (type {kind_code}, code {code}) - {value}",
+                kind_code = event.event_type().0,
+                code = event.code(),
+                value = event.value(),
+            ),
+            _ => (),
+        }
+    }
+
+    /// The `layer` (see `config.rs`) of the device named `device`, as far as
+    /// its name tells: only for devices selected by `name` or `name_regex`.
+    pub(crate) fn layer(&self, device: &str) -> Option<&str> {
+        // Without the `#1` of identical devices, see `device.rs`.
+        let name = device
+            .rsplit_once(" #")
+            .filter(|(_, number)| number.parse::<usize>().is_ok())
+            .map_or(device, |(name, _)| name);
+        self.selectors
+            .iter()
+            .find(|selector| {
+                selector.name.as_deref() == Some(name)
+                    || selector
+                        .name_regex
+                        .as_ref()
+                        .is_some_and(|regex| regex.0.is_match(name))
+            })?
+            .layer
+            .as_deref()
+    }
+
+    /// Whether `key` may trigger anything, including quitting with ESC.
+    fn is_allowed(&self, key: Key) -> bool {
+        !self.denied_keys.contains(&key)
+            && self
+                .allowed_keys
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&key))
+    }
+
+    fn has_macro(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+            || self.macro_configs.contains_key(name)
+            || self.bindings.contains_key(name)
+    }
+
+    /// Whether [`MacroBoard::run_macro`] would run anything for `name`: unlike
+    /// [`MacroBoard::has_macro`], a `[macros]` entry needs actions (or a toggle).
+    fn runs_anything(&self, name: &str) -> bool {
+        self.macros
+            .get(name)
+            .is_some_and(|scripts| !scripts.is_empty())
+            || self.bindings.contains_key(name)
+            || self
+                .macro_configs
+                .get(name)
+                .is_some_and(|config| !config.actions.is_empty() || config.toggle.is_some())
+    }
+
+    /// The macro name of `key`: its symbolic name (e.g. `KEY_F1`), unless only
+    /// its code has a macro (e.g. `code_704`), as for keys without a name.
+    /// Either one in `layer` (e.g. `pad/KEY_F1`) comes first.
+    fn key_macro_name(&self, key: Key, layer: Option<&str>) -> String {
+        let name = format!("{key:?}");
+        let code = format!("code_{}", key.code());
+        if let Some(layer) = layer {
+            let layered = [format!("{layer}/{name}"), format!("{layer}/{code}")];
+            if let Some(layered) = layered.into_iter().find(|name| self.has_macro(name)) {
+                return layered;
+            }
+        }
+        if !self.has_macro(&name) && self.has_macro(&code) {
+            code
+        } else {
+            name
+        }
+    }
+
+    /// What to do when `key_name` has its own macro, but completes a chord.
+    fn chord_conflict(&self, key_name: &str) -> ChordConflict {
+        self.macro_configs
+            .get(key_name)
+            .and_then(|config| config.chord_conflict)
+            .unwrap_or(self.chord_conflicts)
+    }
+
+    /// Point out keys that are ambiguous under [`ChordConflict::Explicit`].
+    fn check_chord_conflicts(&self) {
+        for (chord, keys) in self.chords.chords() {
+            for key in keys {
+                let key_name = format!("{key:?}");
+                if self.chord_conflict(&key_name) == ChordConflict::Explicit
+                    && self.has_macro(&key_name)
+                {
+                    log!(
+                        "{key_name} has a macro and is part of {chord}: set chord_conflict \
+                         in [macros.{key_name}], preferring the chord until then"
+                    );
+                }
+            }
+        }
+    }
+
+    fn process_key(&mut self, event: InputEvent, key: Key, device: &str, layer: Option<&str>) {
+        // Replaced by the chord's name if the key completes a chord.
+        let mut key_name = self.key_macro_name(key, layer);
+        let mut env = vec![
+            ("EVDEV_KEY_CODE", key.code().to_string()),
+            ("EVDEV_DEVICE", device.to_string()),
+        ];
+        if let Some(scancode) = self.scancodes.get(device) {
+            // As evtest shows them, e.g. 0x7003a.
+            env.push(("EVDEV_SCANCODE", format!("{:#x}", *scancode as u32)));
+        }
+        let autorepeat = event.value() == config::AUTOREPEAT && self.autorepeats(&key_name);
+        if event.value() == config::AUTOREPEAT && self.ignore_repeats && !autorepeat {
+            return;
+        }
+        log!(
+            at: event.timestamp(),
+            "{}",
+            self.log_format.format(&event, key, device)
+        );
+        let was_pressed = self.pressed.contains(key);
+        let edge = self.key_values.edge(event.value());
+        match edge {
+            Edge::Release => self.pressed.remove(key),
+            Edge::Press => {
+                self.pressed.insert(key);
+                self.chords.press(key, event.timestamp());
+                if key == Key::KEY_ESC && self.quit_confirm == QuitConfirm::Hold {
+                    self.quit_started = Some(event.timestamp());
+                }
+            }
+            Edge::Other => (),
+        }
+        if edge != Edge::Other {
+            self.forward_modifier(key, edge == Edge::Press);
+        }
+        let chord = if edge == Edge::Release && self.is_allowed(key) && !self.safe_mode {
+            self.chords.release(key, &self.pressed)
+        } else {
+            chord::Release::Single
+        };
+        let both = self.chord_conflict(&key_name) == ChordConflict::Both;
+
+        let repeats = edge == Edge::Press && self.repeats(key, &key_name);
+        let outcome = if edge == Edge::Release && self.repeating.remove(&key).is_some() {
+            // The macro already ran on press.
+            Outcome::Ignored
+        } else if (repeats || autorepeat || edge == Edge::Release)
+            && self.is_stale(&event, &key_name)
+        {
+            Outcome::Stale
+        } else if repeats {
+            self.start_repeat(key, &key_name, &env)
+        } else if edge != Edge::Release && !autorepeat {
+            Outcome::Ignored
+        } else if !self.is_allowed(key) {
+            log!("{key_name} is not allowed to trigger macros");
+            Outcome::Denied
+        } else if self.safe_mode && key != Key::KEY_ESC {
+            log!("Safe mode - ignoring {key_name}");
+            Outcome::SafeMode
+        } else if autorepeat {
+            self.suspended()
+                .unwrap_or_else(|| self.trigger(&key_name, &env))
+        } else if let chord::Release::Chord(name) = chord {
+            log!("Chord {name}");
+            if both && self.suspended().is_none() {
+                self.trigger(&key_name, &env);
+            }
+            key_name = name;
+            // The variables of the key that completed it.
+            self.suspended()
+                .unwrap_or_else(|| self.trigger(&key_name, &env))
+        } else if chord == chord::Release::Consumed && !both {
+            Outcome::Chorded
+        } else if Some(key) == self.arm_key {
+            self.set_armed(!self.state.armed);
+            Outcome::Armed(self.state.armed)
+        } else if Some(key) == self.profile_key {
+            self.next_profile();
+            Outcome::Profile(self.state.profile.clone())
+        } else if key == Key::KEY_ESC && self.esc_passthrough {
+            // Held, ESC quits, tapped it's a key like any other.
+            if !was_pressed {
+                Outcome::Ignored
+            } else if self.confirms_quit(&event) {
+                self.quit_on_esc();
+                Outcome::Quit
+            } else if !self.safe_mode && self.has_macro(&key_name) {
+                self.suspended()
+                    .unwrap_or_else(|| self.trigger(&key_name, &env))
+            } else {
+                self.type_key(key)
+            }
+        } else {
+            // If ESC was already held when we grabbed (e.g. to start in safe mode),
+            // its release isn't meant for us.
+            if key == Key::KEY_ESC && was_pressed && self.confirms_quit(&event) {
+                self.quit_on_esc();
+            }
+            self.suspended()
+                .unwrap_or_else(|| self.trigger(&key_name, &env))
+        };
+        self.history.push(history::Entry {
+            timestamp: event.timestamp(),
+            device: device.to_string(),
+            key: key_name,
+            value: event.value(),
+            outcome,
+        });
+    }
+
+    /// Whether `event` is older than `max_event_age`, so it shouldn't trigger anything.
+    fn is_stale(&self, event: &InputEvent, key_name: &str) -> bool {
+        let Some(max_age) = self.max_event_age else {
+            return false;
+        };
+        let age = self
+            .clock
+            .system_now()
+            .duration_since(event.timestamp())
+            .unwrap_or_default();
+        if age <= max_age {
+            return false;
+        }
+        log!(
+            at: event.timestamp(),
+            "Dropping {key_name}: it's {age:.1?} old, more than max_event_age"
+        );
+        true
+    }
+
+    fn quit_on_esc(&mut self) {
+        log!("Received ESC - exiting!");
+        self.quit = true;
+        if self.quit_notification {
+            self.notify(
+                "evdev-macros stopped",
+                "ESC was pressed, macros no longer run",
+            );
+        }
+    }
+
+    /// Whether this release of ESC is meant to quit, see `quit_confirm` in `config.rs`.
+    fn confirms_quit(&mut self, release: &InputEvent) -> bool {
+        let window = self.quit_confirm_window;
+        let within_window = |started: Option<SystemTime>| {
+            started.is_some_and(|started| {
+                release
+                    .timestamp()
+                    .duration_since(started)
+                    .is_ok_and(|since| since <= window)
+            })
+        };
+        match self.quit_confirm {
+            QuitConfirm::Single => true,
+            QuitConfirm::DoubleTap => {
+                if within_window(self.quit_started.take()) {
+                    return true;
+                }
+                self.quit_started = Some(release.timestamp());
+                let hint = format!("Press ESC again within {window:.1?} to exit");
+                log!("{hint}");
+                self.notify("evdev-macros is still running", &hint);
+                false
+            }
+            QuitConfirm::Hold => {
+                if !within_window(self.quit_started.take()) {
+                    return true;
+                }
+                // Tapping ESC is expected then.
+                if !self.esc_passthrough {
+                    log!("Hold ESC for {window:.1?} to exit");
+                }
+                false
+            }
+        }
+    }
+
+    /// Tap `key` on the virtual keyboard, for ESC with `esc_passthrough`.
+    fn type_key(&self, key: Key) -> Outcome {
+        if self.dry_run {
+            log!("Dry run - not typing {key:?}");
+            return Outcome::Typed;
+        }
+        let Some(keyboard) = &self.action_context.keyboard else {
+            return Outcome::Failed("there is no virtual keyboard".to_string());
+        };
+        let mut keyboard = keyboard.lock().unwrap();
+        for value in [1, 0] {
+            let event = InputEvent::new(EventType::KEY, key.code(), value);
+            if let Err(err) = keyboard.emit(&[event]) {
+                log!("Failed to type {key:?}: {err}");
+                return Outcome::Failed(err.to_string());
+            }
+        }
+        Outcome::Typed
+    }
+
+    /// Press or release `key` on the virtual keyboard too, if it's a modifier
+    /// and `forward_modifiers` is on.
+    ///
+    /// The grabbed keyboard's modifiers never reach anyone else, so without
+    /// this, keys sent by macros can't be combined with them.
+    fn forward_modifier(&self, key: Key, pressed: bool) {
+        let Some(keyboard) = &self.action_context.keyboard else {
+            return;
+        };
+        if !self.forward_modifiers || !actions::MODIFIERS.contains(&key) {
+            return;
+        }
+        let event = InputEvent::new(EventType::KEY, key.code(), i32::from(pressed));
+        if let Err(err) = keyboard.lock().unwrap().emit(&[event]) {
+            log!("Failed to forward {key:?}: {err}");
+        }
+    }
+
+    /// Whether the macro `key_name` runs on the keyboard's autorepeat, see
+    /// `autorepeat` in `config.rs`.
+    fn autorepeats(&self, key_name: &str) -> bool {
+        self.macro_configs
+            .get(key_name)
+            .is_some_and(|config| config.autorepeat)
+    }
+
+    /// Whether the macro of `key` runs on press and repeats (or runs
+    /// `while_held`) while it's held.
+    fn repeats(&self, key: Key, key_name: &str) -> bool {
+        self.is_allowed(key)
+            && !self.safe_mode
+            && self.scheduled(key_name) == Some(key_name)
+            && self.macro_configs.get(key_name).is_some_and(|config| {
+                config.repeat.is_some() && !config.actions.is_empty() || config.while_held.is_some()
+            })
+    }
+
+    fn start_repeat(&mut self, key: Key, key_name: &str, env: &[(&str, String)]) -> Outcome {
+        if let Some(outcome) = self.suspended() {
+            return outcome;
+        }
+        if let Some(held) = self.macro_configs[key_name].while_held.clone() {
+            return self.start_holding(key, key_name, held, env);
+        }
+        let outcome = self.trigger(key_name, env);
+        let config = &self.macro_configs[key_name];
+        if let (Outcome::Ran(_), Some(repeat), false) = (&outcome, config.repeat, self.dry_run) {
+            let (stop, stopped) = crossbeam_channel::bounded(0);
+            self.workers.push(actions::repeat(
+                key_name.to_string(),
+                config.actions.clone(),
+                repeat,
+                self.action_context.clone(),
+                self.macro_settings(key_name, env),
+                stopped,
+            ));
+            self.repeating.insert(key, stop);
+        }
+        outcome
+    }
+
+    /// Releasing `key` (i.e. dropping its sender in `repeating`) ends it.
+    fn start_holding(
+        &mut self,
+        key: Key,
+        key_name: &str,
+        held: WhileHeld,
+        env: &[(&str, String)],
+    ) -> Outcome {
+        if self.dry_run {
+            log!("Dry run - not running {key_name} macro while held: {held:?}");
+            return Outcome::Ran(1);
+        }
+        log!("Running actions of macro while held: {key_name}");
+        let (stop, stopped) = crossbeam_channel::bounded(0);
+        let settings = self.macro_settings(key_name, env);
+        self.workers.retain(|worker| !worker.is_finished());
+        self.workers.push(actions::while_held(
+            key_name.to_string(),
+            held,
+            self.action_context.clone(),
+            settings,
+            stopped,
+        ));
+        self.repeating.insert(key, stop);
+        if let Some(dbus) = &self.dbus {
+            dbus.macro_triggered(key_name);
+        }
+        Outcome::Ran(1)
+    }
+
+    /// Stop repeating, run the `on_release` actions of keys that are still held
+    /// and the `on_stop` macro, giving them [`RELEASE_TIMEOUT`] to finish before we exit.
+    fn shut_down(&mut self) {
+        let releasing = !self.repeating.is_empty();
+        self.repeating.clear();
+        let stopping = self
+            .on_stop
+            .clone()
+            .is_some_and(|name| self.run_hook("on_stop", &name));
+        if !releasing && !stopping {
+            return;
+        }
+        let deadline = Instant::now() + RELEASE_TIMEOUT;
+        while !self.workers_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Runs the zone macro when a configured axis moves into another zone.
+    ///
+    /// The first value we see only establishes the zone, as we can't tell where the axis came from.
+    fn process_axis(&mut self, event: InputEvent, axis: AbsoluteAxisType, device: &str) {
+        let Some((_, config)) = self.axes.iter().find(|(configured, _)| *configured == axis) else {
+            if self.verbosity >= 2 {
+                log!(
+                    at: event.timestamp(),
+                    "Unhandled {axis:?} event - {value}",
+                    value = event.value()
+                );
+            }
+            return;
+        };
+        let zone = config.zone(event.value());
+        if self
+            .axis_zones
+            .insert(axis.0, zone)
+            .is_none_or(|old| old == zone)
+        {
+            return;
+        }
+
+        let macro_name = format!("{axis:?}_ZONE{zone}");
+        log!(
+            at: event.timestamp(),
+            "{macro_name} - {value}",
+            value = event.value()
+        );
+        let outcome = self.suspended().unwrap_or_else(|| {
+            let env = [
+                ("EVDEV_AXIS_VALUE", event.value().to_string()),
+                ("EVDEV_AXIS_ZONE", zone.to_string()),
+            ];
+            self.trigger(&macro_name, &env)
+        });
+        self.history.push(history::Entry {
+            timestamp: event.timestamp(),
+            device: device.to_string(),
+            key: macro_name,
+            value: event.value(),
+            outcome,
+        });
+    }
+
+    fn resync(&mut self, pressed: AttributeSet<Key>) {
+        for key in self.pressed.iter().filter(|key| !pressed.contains(*key)) {
+            log!("Resync: {key:?} is no longer held");
+            self.forward_modifier(key, false);
+        }
+        for key in pressed.iter().filter(|key| !self.pressed.contains(*key)) {
+            log!("Resync: {key:?} is held");
+            self.forward_modifier(key, true);
+        }
+        self.repeating.retain(|key, _| pressed.contains(*key));
+        self.pressed = pressed;
+    }
+
+    /// The key state of `device` didn't match its events, see `resync_interval`
+    /// in `config.rs`. Unlike [`MacroBoard::resync`], this leaves the keys of
+    /// other devices alone.
+    fn correct(&mut self, device: &str, held: AttributeSet<Key>, released: AttributeSet<Key>) {
+        for key in released.iter() {
+            if !self.pressed.contains(key) {
+                continue;
+            }
+            log!("Resync: {key:?} of {device} is no longer held");
+            self.forward_modifier(key, false);
+            self.pressed.remove(key);
+            self.repeating.remove(&key);
+        }
+        for key in held.iter() {
+            if self.pressed.contains(key) {
+                continue;
+            }
+            log!("Resync: {key:?} of {device} is held");
+            self.forward_modifier(key, true);
+            self.pressed.insert(key);
+        }
+    }
+
+    /// The thread reading `device` panicked, see `panics.rs`. It already sent
+    /// a resync if it's going to reconnect, or set `terminate` if we're exiting.
+    fn device_panicked(&self, device: &str) {
+        let recovery = match self.on_device_panic {
+            DevicePanic::Reconnect => "reconnecting",
+            DevicePanic::Exit => "exiting",
+        };
+        log!("Reading {device} failed unexpectedly, {recovery}");
+        self.notify_with(
+            &format!("evdev-macros: reading {device} failed, {recovery}"),
+            "See the log for details",
+            Some(Urgency::Critical),
+            None,
+            &[],
+        );
+    }
+
+    /// The permissions of `device` changed under us, see `Ended::Denied` in `device.rs`.
+    fn device_denied(&self, device: &str, reconnecting: bool) {
+        let recovery = if reconnecting {
+            "trying to open it again"
+        } else {
+            "its macros won't work until evdev-macros is restarted"
+        };
+        log!("Lost access to {device}, {recovery}");
+        self.notify_with(
+            &format!("evdev-macros: lost access to {device}"),
+            &format!("Its permissions changed, e.g. by switching users - {recovery}"),
+            Some(Urgency::Critical),
+            None,
+            &[],
+        );
+    }
+
+    fn process_command(&mut self, command: Command) {
+        match command {
+            Command::RunMacro(key_name) => {
+                if self.safe_mode {
+                    log!("Safe mode - not running {key_name} macro");
+                } else if self.state.armed {
+                    self.trigger(&key_name, &[]);
+                } else {
+                    log!("Disarmed - not running {key_name} macro");
+                }
+            }
+            Command::Reload => self.reload(),
+            Command::SetPaused(paused) => {
+                log!("{}", if paused { "Paused" } else { "Resumed" });
+                self.paused = paused;
+            }
+            Command::SetArmed(armed) => self.set_armed(armed),
+            Command::SetProfile(profile) => self.set_profile(profile),
+            Command::NextProfile => self.next_profile(),
+            Command::Regrab => {
+                log!("Regrabbing the devices");
+                self.regrab = true;
+            }
+            Command::History(reply) => {
+                reply.send(self.history.lines()).ok();
+            }
+            Command::Latency(reply) => {
+                reply.send(self.latency.borrow().lines()).ok();
+            }
+            Command::Toggles(reply) => {
+                reply.send(self.toggles()).ok();
+            }
+            Command::Bind {
+                macro_name,
+                command,
+            } => {
+                log!("Bound {macro_name} to: {command}");
+                self.bindings.insert(macro_name, command);
+            }
+            Command::Unbind(macro_name) => {
+                if self.bindings.remove(&macro_name).is_some() {
+                    log!("Unbound {macro_name}");
+                } else {
+                    log!("{macro_name} isn't bound, not unbinding it");
+                }
+            }
+            Command::Config(reply) => {
+                reply.send(self.config.clone()).ok();
+            }
+            Command::Notify {
+                summary,
+                body,
+                urgency,
+                icon,
+                buttons,
+            } => self.notify_with(&summary, &body, urgency, icon.as_deref(), &buttons),
+            Command::MacroFinished(macro_name) => self.macro_finished(&macro_name),
+            Command::Wake => (),
+        }
+    }
+
+    fn process_events(&mut self) {
+        crossbeam_channel::select! {
+            recv(self.receiver) -> input => match input {
+                Ok(input) => self.process_input(input),
+                Err(_) => {
+                    log!("Disconnected - exiting!");
+                    self.quit = true;
+                }
+            },
+            recv(self.commands) -> command => {
+                if let Ok(command) = command {
+                    self.process_command(command);
+                }
+            },
+            // In case the signals were set without waking us up, and for
+            // coalesced macros that are due.
+            default(self.timeout()) => (),
+        }
+        self.poll_macros();
+        self.run_due_coalesced();
+    }
+
+    fn process_input(&mut self, input: Input) {
+        match input {
+            Input::Report {
+                events,
+                device,
+                layer,
+            } => {
+                for event in events {
+                    self.process_event(event, &device, layer.as_deref());
+                }
+            }
+            Input::Resync(pressed) => self.resync(pressed),
+            Input::Corrected {
+                device,
+                held,
+                released,
+            } => self.correct(&device, held, released),
+            Input::Panicked(device) => self.device_panicked(&device),
+            Input::Denied {
+                device,
+                reconnecting,
+            } => self.device_denied(&device, reconnecting),
+        }
+    }
+
+    /// Handle the events and commands that are already queued, apply a finished
+    /// reload and run the coalesced macros that are due, without waiting for anything.
+    ///
+    /// Together with a [`ManualClock`](crate::clock::ManualClock), this drives
+    /// the board step by step, e.g. to test timing.
+    pub fn process_pending(&mut self) {
+        self.poll_macros();
+        while let Ok(input) = self.receiver.try_recv() {
+            self.process_input(input);
+        }
+        while let Ok(command) = self.commands.try_recv() {
+            self.process_command(command);
+        }
+        self.run_due_coalesced();
+    }
+
+    /// How long to wait for events: until the next coalesced macro is due, at most 100ms.
+    fn timeout(&self) -> Duration {
+        let now = self.clock.now();
+        self.coalescing
+            .values()
+            .map(|pending| pending.due.saturating_duration_since(now))
+            .fold(Duration::from_millis(100), Duration::min)
+    }
+}
+
+/// Options for a [`MacroBoard`], see [`MacroBoard::builder`].
+pub struct MacroBoardBuilder {
+    config: Config,
+    dry_run: bool,
+    verbosity: u8,
+    safe_mode: bool,
+    notifications: bool,
+    dbus: bool,
+    uniq: Option<String>,
+    allow_insecure_scripts: bool,
+    clock: Arc<dyn Clock>,
+    fresh_state: bool,
+}
+
+impl MacroBoardBuilder {
+    /// A dry run on `clock`, without notifications, D-Bus or the saved state,
+    /// and with the config directory in a temporary directory (for the whole
+    /// process), so tests neither read nor change anything of the user's.
+    #[doc(hidden)]
+    pub fn for_tests(config: Config, clock: Arc<dyn Clock>) -> MacroBoardBuilder {
+        config::set_config_dir(std::env::temp_dir().join(format!(
+            "evdev-macros-tests-{pid}",
+            pid = std::process::id()
+        )));
+        MacroBoard::builder(config)
+            .dry_run(true)
+            .notifications(false)
+            .dbus(false)
+            .fresh_state(true)
+            .clock(clock)
+    }
+
+    /// Only log which macros would run.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Log more: at 2, also the events that are neither keys nor configured axes.
+    /// 0 by default.
+    pub fn verbosity(mut self, verbosity: u8) -> Self {
+        self.verbosity = verbosity;
+        self
+    }
+
+    /// Run no macros at all, only quit with ESC.
+    pub fn safe_mode(mut self, safe_mode: bool) -> Self {
+        self.safe_mode = safe_mode;
+        self
+    }
+
+    /// Also run scripts that are world-writable or owned by someone other than
+    /// the user (or root). Off by default.
+    pub fn allow_insecure_scripts(mut self, allow: bool) -> Self {
+        self.allow_insecure_scripts = allow;
+        self
+    }
+
+    /// Whether to show desktop notifications (errors, timeouts, ...). On by default.
+    pub fn notifications(mut self, notifications: bool) -> Self {
+        self.notifications = notifications;
+        self
+    }
+
+    /// Whether to offer the D-Bus control interface, see `dbus.rs`. On by default.
+    pub fn dbus(mut self, dbus: bool) -> Self {
+        self.dbus = dbus;
+        self
+    }
+
+    /// Only use the device with this unique id, instead of the configured one
+    /// (if `Some`).
+    pub fn uniq(mut self, uniq: Option<String>) -> Self {
+        if uniq.is_some() {
+            self.uniq = uniq;
+        }
+        self
+    }
+
+    /// Where to get the time from, the real one by default. See `clock.rs`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Start armed, without a profile and with the toggles off, instead of
+    /// with the state the last run saved (see `state.rs`).
+    pub fn fresh_state(mut self, fresh: bool) -> Self {
+        self.fresh_state = fresh;
+        self
+    }
+
+    /// Checks on the options as a whole, including the config
+    /// (which may not come from [`Config::load`]).
+    fn validate(&self) -> Result<(), String> {
+        self.config.validate()?;
+        if self.uniq.as_deref() == Some("") {
+            return Err("the unique id to look for can't be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Create the board and load the macros, unless the options are invalid.
+    ///
+    /// The returned sender is for the threads reading the devices,
+    /// see [`crate::device::grab_devices`].
+    pub fn build(self) -> Result<(MacroBoard, Sender<Input>), Box<dyn std::error::Error>> {
+        self.validate()?;
+        let mut config = self.config;
+        if let Some(uniq) = self.uniq {
+            config.override_uniq(uniq);
+        }
+        let macro_limits = config.macro_limits();
+        let user = if users::get_effective_uid() == 0 {
+            Some(Arc::new(process::Identity::current()?))
+        } else {
+            None
+        };
+        let selectors = config.selectors();
+        let config_toml = config
+            .to_toml()
+            .unwrap_or_else(|err| format!("# Failed to serialize config: {err}\n"));
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        // The board keeps a `command_sender` alive, so `commands` never disconnects.
+        let (command_sender, commands) = crossbeam_channel::unbounded();
+        let dbus = if self.dbus {
+            DbusService::start(command_sender.clone())
+                .map_err(|err| log!("Failed to start D-Bus service: {err}"))
+                .ok()
+        } else {
+            None
+        };
+        // Only macros with `send_keys` actions need a virtual keyboard (or pointer).
+        let mut keys =
+            actions::keys_used(config.macros.values().flat_map(MacroConfig::all_actions));
+        let mut buttons = AttributeSet::new();
+        if config.virtual_pointer {
+            for key in keys.iter().filter(|key| actions::is_pointer_button(*key)) {
+                buttons.insert(key);
+            }
+            for button in buttons.iter() {
+                keys.remove(button);
+            }
+        }
+        if config.esc_passthrough {
+            keys.insert(Key::KEY_ESC);
+        }
+        let sends_keys = keys.iter().chain(buttons.iter()).next().is_some();
+        if config.forward_modifiers && sends_keys {
+            for modifier in actions::MODIFIERS {
+                keys.insert(modifier);
+            }
+        }
+        let virtual_devices = !sends_keys || uinput::available();
+        if !virtual_devices {
+            log!("send_keys actions are disabled");
+        }
+        let keyboard = if virtual_devices && keys.iter().next().is_some() {
+            shared_device(actions::create_keyboard(&keys), "keyboard")
+        } else {
+            None
+        };
+        let pointer = if virtual_devices && buttons.iter().next().is_some() {
+            shared_device(actions::create_pointer(&buttons), "pointer")
+        } else {
+            None
+        };
+        let mirror = match config.mirror {
+            Some(path) => config::config_dir()
+                .and_then(|dir| Mirror::new(dir.join(path)))
+                .map_err(|err| log!("Failed to mirror events: {err}"))
+                .ok(),
+            None => None,
+        };
+        let mut board = MacroBoard {
+            clock: self.clock,
+            receiver,
+            commands,
+            command_sender: command_sender.clone(),
+            dbus,
+            macros: HashMap::new(),
+            loading: None,
+            bindings: HashMap::new(),
+            keep_bindings: config.keep_bindings,
+            macro_configs: config.macros,
+            macro_limits,
+            user,
+            action_context: actions::Context {
+                keyboard,
+                pointer,
+                syn_reports: config.syn_reports,
+                leds: Arc::default(),
+                commands: command_sender,
+            },
+            workers: Vec::new(),
+            instances: HashMap::new(),
+            dry_run: self.dry_run,
+            verbosity: self.verbosity,
+            notifications: self.notifications,
+            last_notification: RefCell::new(None),
+            startup_notification: config.startup_notification,
+            quit_notification: config.quit_notification,
+            on_start: config.on_start,
+            on_stop: config.on_stop,
+            quiet_hours: config.quiet_hours,
+            quit_confirm: config.quit_confirm,
+            quit_confirm_window: config.quit_confirm_window,
+            esc_passthrough: config.esc_passthrough,
+            quit_started: None,
+            log_format: config.log_format,
+            key_values: config.key_values,
+            ignore_repeats: config.ignore_repeats,
+            history: History::new(config.history_size),
+            latency: RefCell::new(Latency::new()),
+            mirror,
+            config: config_toml,
+            pressed: AttributeSet::new(),
+            repeating: HashMap::new(),
+            toggles: HashMap::new(),
+            indicators: HashMap::new(),
+            coalescing: HashMap::new(),
+            chords: Chords::new(config.combo_window),
+            env_clear: config.env_clear,
+            keep_env: config.keep_env,
+            interpreters: config.interpreters,
+            session: session::watch(users::get_current_uid()),
+            forward_modifiers: config.forward_modifiers,
+            chord_conflicts: config.chord_conflict,
+            max_event_age: config.max_event_age,
+            rate_limit: config.rate_limit,
+            bucket: Bucket::new(config.rate_limit),
+            rate_limited: 0,
+            axes: config.axes,
+            scancodes: HashMap::new(),
+            axis_zones: HashMap::new(),
+            allowed_keys: config.allowed_keys,
+            denied_keys: config.denied_keys,
+            forward_keys: config.forward_keys,
+            forward_types: config.forward_types,
+            reconnect_max_delay: config.reconnect_max_delay,
+            regrab_on_resume: config.regrab_on_resume,
+            flush_on_start: config.flush_on_start,
+            resync_interval: config.resync_interval,
+            on_device_panic: config.on_device_panic,
+            selectors,
+            quit: false,
+            regrab: false,
+            paused: false,
+            safe_mode: self.safe_mode,
+            allow_insecure_scripts: self.allow_insecure_scripts,
+            state: if self.fresh_state {
+                State::default()
+            } else {
+                State::load().unwrap_or_else(|err| {
+                    log!("Failed to load state: {err}");
+                    State::default()
+                })
+            },
+            arm_key: config.arm_key,
+            profile_key: config.profile_key,
+        };
+        // In case reading the macro directory takes too long.
+        board.chords.set_macros(board.macro_configs.keys());
+        board.reload();
+        board.wait_for_macros();
+        if !board.state.armed {
+            log!("Macros are disarmed");
+        }
+        if let Some(profile) = &board.state.profile {
+            log!("Using profile {profile}");
+        }
+        Ok((board, sender))
+    }
+}
+
+/// A newly created virtual device, for [`actions::Context`].
+fn shared_device(
+    device: std::io::Result<VirtualDevice>,
+    kind: &str,
+) -> Option<Arc<Mutex<VirtualDevice>>> {
+    device
+        .map(|device| Arc::new(Mutex::new(device)))
+        .map_err(|err| log!("Failed to create virtual {kind}: {err}"))
+        .ok()
+}
+
+/// Flags set by signal handlers, checked by [`MacroBoard::run`].
+#[derive(Clone, Default)]
+pub struct Signals {
+    /// Stop running (also set by [`MacroBoard::run`] when it returns).
+    pub terminate: Arc<AtomicBool>,
+    /// Re-scan the macro directory.
+    pub reload: Arc<AtomicBool>,
+    /// Print the history and the latency (see `latency.rs`) to stderr.
+    pub dump_history: Arc<AtomicBool>,
+    /// Switch to the next profile.
+    pub next_profile: Arc<AtomicBool>,
+}
+
+impl MacroBoard {
+    pub fn builder(config: Config) -> MacroBoardBuilder {
+        MacroBoardBuilder {
+            uniq: None,
+            config,
+            dry_run: false,
+            verbosity: 0,
+            safe_mode: false,
+            notifications: true,
+            dbus: true,
+            allow_insecure_scripts: false,
+            clock: Arc::new(SystemClock),
+            fresh_state: false,
+        }
+    }
+
+    /// Report that the daemon is up, reading `devices` devices, and run the `on_start` macro.
+    pub fn announce_start(&mut self, devices: usize) {
+        let summary = format!("evdev-macros started, reading {devices} device(s)");
+        log!("{summary}");
+        if self.startup_notification {
+            self.notify(&summary, "");
+        }
+        if let Some(name) = self.on_start.clone() {
+            self.run_hook("on_start", &name);
+        }
+    }
+
+    /// Run the macro `name` for `hook` (`on_start` or `on_stop`). Returns whether it started.
+    ///
+    /// Unlike keys, hooks aren't held back by pausing or arming, only by safe mode.
+    fn run_hook(&mut self, hook: &str, name: &str) -> bool {
+        if self.safe_mode {
+            log!("Safe mode - not running {hook} macro {name}");
+            return false;
+        }
+        log!("Running {hook} macro {name}");
+        match self.run_macro(name, &[]) {
+            Ok(0) => {
+                log!("{hook} macro {name} doesn't exist");
+                false
+            }
+            Ok(_) => true,
+            Err(err) => {
+                self.notify_error(name, err.as_ref());
+                false
+            }
+        }
+    }
+
+    /// Show a sample of the notifications we send, see `--test-notify`.
+    ///
+    /// Unlike the real ones, this reports when they couldn't be shown,
+    /// even if notifications are turned off.
+    pub fn test_notifications(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.show_notification("Switched to profile example", "", None, None, &[])?;
+        self.show_notification(
+            "Error executing KEY_F1 macro",
+            "An example of what a failing macro looks like",
+            None,
+            None,
+            &[],
+        )
+    }
+
+    /// For signal handlers (or other threads) setting [`Signals`]: without waking
+    /// the board up, it may take up to 100ms to notice. With it, the daemon exits
+    /// within a few milliseconds of SIGTERM (about 2ms when measured, in a debug build).
+    pub fn waker(&self) -> Waker {
+        Waker(self.command_sender.clone())
+    }
+
+    /// Handle events and commands until `signals.terminate` is set or ESC is
+    /// pressed, or until [`Command::Regrab`].
+    pub fn run(&mut self, signals: &Signals) -> Stop {
+        while !signals.terminate.load(Ordering::Relaxed) && !self.quit {
+            if signals.reload.swap(false, Ordering::Relaxed) {
+                self.reload();
+            }
+            if signals.next_profile.swap(false, Ordering::Relaxed) {
+                self.next_profile();
+            }
+            if signals.dump_history.swap(false, Ordering::Relaxed) {
+                log!("Recent events:");
+                for line in self.history.lines() {
+                    log!("  {line}");
+                }
+                for line in self.latency.borrow().lines() {
+                    log!("{line}");
+                }
+            }
+            self.process_events();
+            if std::mem::take(&mut self.regrab) {
+                return Stop::Regrab;
+            }
+        }
+        if !self.quit {
+            log!("Terminated - exiting!");
+        }
+        self.shut_down();
+        // Also stops the grabbing threads when quitting with ESC.
+        signals.terminate.store(true, Ordering::Relaxed);
+        Stop::Exit
+    }
+
+    /// For `--once-per-device`: handle events until `keys` keys were released,
+    /// `timeout` passed, `signals.terminate` is set or ESC is pressed, and
+    /// `report` for each key event which device it came from and the macro
+    /// it resolved to. Meant for a dry run, without starting or stopping
+    /// anything like [`MacroBoard::run`] does.
+    pub fn attribute(
+        &mut self,
+        signals: &Signals,
+        keys: usize,
+        timeout: Duration,
+        mut report: impl FnMut(String),
+    ) {
+        let deadline = Instant::now() + timeout;
+        // Events only reach us through the history, even with `history_size = 0`.
+        self.history = History::new(64);
+        let mut released = 0;
+        while released < keys
+            && Instant::now() < deadline
+            && !signals.terminate.load(Ordering::Relaxed)
+            && !self.quit
+        {
+            self.process_events();
+            for entry in self.history.take() {
+                let layer = self
+                    .layer(&entry.device)
+                    .map(|layer| format!(" (layer {layer})"))
+                    .unwrap_or_default();
+                report(format!(
+                    "{device}{layer}: {key} - {value}: {outcome}",
+                    device = entry.device,
+                    key = entry.key,
+                    value = entry.value,
+                    outcome = entry.outcome,
+                ));
+                released += usize::from(entry.value == 0);
+            }
+        }
+        signals.terminate.store(true, Ordering::Relaxed);
+    }
+
+    /// Run the macro for `key_name` as if the key was released, and wait for it to finish.
+    pub fn run_once(&mut self, key_name: &str) -> Outcome {
+        let mut outcome = self
+            .suspended()
+            .unwrap_or_else(|| self.trigger(key_name, &[]));
+        // There won't be any more triggers to wait for.
+        if let Some(ran) = self.run_coalesced(key_name) {
+            outcome = ran;
+        }
+        // Workers report timeouts and failed actions through the command channel.
+        while !self.workers_finished() {
+            if let Ok(command) = self.commands.recv_timeout(Duration::from_millis(100)) {
+                self.process_command(command);
+            }
+        }
+        while let Ok(command) = self.commands.try_recv() {
+            self.process_command(command);
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn board(config: Config, clock: &Arc<ManualClock>) -> (MacroBoard, Sender<Input>) {
+        MacroBoardBuilder::for_tests(config, Arc::clone(clock) as Arc<dyn Clock>)
+            .build()
+            .unwrap()
+    }
+
+    /// A key event that happens now on `clock`.
+    fn key(clock: &ManualClock, key: Key, value: i32) -> InputEvent {
+        let since_epoch = clock
+            .system_now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        InputEvent::from(libc::input_event {
+            time: libc::timeval {
+                tv_sec: since_epoch.as_secs() as libc::time_t,
+                tv_usec: since_epoch.subsec_micros() as libc::suseconds_t,
+            },
+            type_: EventType::KEY.0,
+            code: key.code(),
+            value,
+        })
+    }
+
+    fn report(sender: &Sender<Input>, events: Vec<InputEvent>) {
+        sender
+            .send(Input::Report {
+                events,
+                device: Arc::from("test"),
+                layer: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn two_key_report_triggers_chord() {
+        let clock = Arc::new(ManualClock::new());
+        let mut config = Config::default();
+        config
+            .macros
+            .insert("KEY_LEFTCTRL+KEY_A".to_string(), MacroConfig::default());
+        let (mut board, sender) = board(config, &clock);
+
+        report(
+            &sender,
+            vec![
+                key(&clock, Key::KEY_LEFTCTRL, 1),
+                key(&clock, Key::KEY_A, 1),
+            ],
+        );
+        clock.advance(Duration::from_millis(50));
+        report(
+            &sender,
+            vec![
+                key(&clock, Key::KEY_A, 0),
+                key(&clock, Key::KEY_LEFTCTRL, 0),
+            ],
+        );
+        board.process_pending();
+
+        let releases: Vec<_> = board
+            .history
+            .take()
+            .into_iter()
+            .filter(|entry| entry.value == 0)
+            .collect();
+        assert_eq!(releases.len(), 2);
+        assert_eq!(releases[0].key, "KEY_LEFTCTRL+KEY_A");
+        assert!(!matches!(releases[0].outcome, Outcome::Chorded));
+        assert_eq!(releases[1].key, "KEY_LEFTCTRL");
+        assert!(matches!(releases[1].outcome, Outcome::Chorded));
+    }
+
+    /// Press `key`, hold it for `held` and release it, in separate reports.
+    fn tap(
+        board: &mut MacroBoard,
+        sender: &Sender<Input>,
+        clock: &ManualClock,
+        key: Key,
+        held: Duration,
+    ) {
+        report(sender, vec![self::key(clock, key, 1)]);
+        clock.advance(held);
+        report(sender, vec![self::key(clock, key, 0)]);
+        board.process_pending();
+    }
+
+    /// The outcome of the last release in the history.
+    fn last_release(board: &mut MacroBoard) -> Outcome {
+        board
+            .history
+            .take()
+            .into_iter()
+            .rfind(|entry| entry.value == 0)
+            .unwrap()
+            .outcome
+    }
+
+    #[test]
+    fn slow_reload_does_not_hold_up_events() {
+        let clock = Arc::new(ManualClock::new());
+        let (mut board, sender) = board(Config::default(), &clock);
+        let (loaded, receiver) = crossbeam_channel::bounded(1);
+        board.loading = Some(Loading {
+            receiver,
+            started: clock.now(),
+            warned: false,
+            again: false,
+        });
+
+        tap(
+            &mut board,
+            &sender,
+            &clock,
+            Key::KEY_F1,
+            Duration::from_millis(50),
+        );
+        assert!(matches!(last_release(&mut board), Outcome::NoMacro));
+        let script = Script {
+            path: PathBuf::from("/bin/true"),
+            runs_directly: true,
+        };
+        loaded
+            .send(Ok(HashMap::from([("KEY_F1".to_string(), vec![script])])))
+            .unwrap();
+        tap(
+            &mut board,
+            &sender,
+            &clock,
+            Key::KEY_F1,
+            Duration::from_millis(50),
+        );
+        assert!(matches!(last_release(&mut board), Outcome::Ran(1)));
+        assert!(board.loading.is_none());
+    }
+
+    #[test]
+    fn double_tap_quits_within_the_window() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config {
+            quit_confirm: QuitConfirm::DoubleTap,
+            quit_confirm_window: Duration::from_millis(500),
+            ..Config::default()
+        };
+        let (mut board, sender) = board(config, &clock);
+        let tapped = Duration::from_millis(50);
+
+        tap(&mut board, &sender, &clock, Key::KEY_ESC, tapped);
+        assert!(!board.quit);
+        clock.advance(Duration::from_millis(600));
+        tap(&mut board, &sender, &clock, Key::KEY_ESC, tapped);
+        assert!(!board.quit, "the second tap came too late");
+        clock.advance(Duration::from_millis(300));
+        tap(&mut board, &sender, &clock, Key::KEY_ESC, tapped);
+        assert!(board.quit);
+    }
+
+    #[test]
+    fn holding_esc_quits_and_tapping_types_it() {
+        let clock = Arc::new(ManualClock::new());
+        let config = Config {
+            quit_confirm: QuitConfirm::Hold,
+            quit_confirm_window: Duration::from_millis(500),
+            esc_passthrough: true,
+            ..Config::default()
+        };
+        let (mut board, sender) = board(config, &clock);
+
+        tap(
+            &mut board,
+            &sender,
+            &clock,
+            Key::KEY_ESC,
+            Duration::from_millis(100),
+        );
+        assert!(matches!(last_release(&mut board), Outcome::Typed));
+        assert!(!board.quit);
+        tap(
+            &mut board,
+            &sender,
+            &clock,
+            Key::KEY_ESC,
+            Duration::from_millis(600),
+        );
+        assert!(matches!(last_release(&mut board), Outcome::Quit));
+        assert!(board.quit);
+    }
+
+    #[test]
+    fn rate_limit_cools_down_as_the_clock_advances() {
+        let clock = Arc::new(ManualClock::new());
+        let mut config = Config {
+            rate_limit: RateLimit {
+                runs: 2,
+                per: Duration::from_secs(10),
+                pause: false,
+            },
+            ..Config::default()
+        };
+        let actions = MacroConfig {
+            actions: vec![Action::Command("true".to_string())],
+            ..MacroConfig::default()
+        };
+        config.macros.insert("KEY_F1".to_string(), actions);
+        let (mut board, sender) = board(config, &clock);
+        let tapped = Duration::from_millis(50);
+
+        // Keys without a macro don't count.
+        for _ in 0..5 {
+            tap(&mut board, &sender, &clock, Key::KEY_F2, tapped);
+            assert!(matches!(last_release(&mut board), Outcome::NoMacro));
+        }
+        for _ in 0..2 {
+            tap(&mut board, &sender, &clock, Key::KEY_F1, tapped);
+            assert!(matches!(last_release(&mut board), Outcome::Ran(1)));
+        }
+        tap(&mut board, &sender, &clock, Key::KEY_F1, tapped);
+        assert!(matches!(last_release(&mut board), Outcome::RateLimited));
+        // A run comes back every 5s.
+        clock.advance(Duration::from_secs(5));
+        tap(&mut board, &sender, &clock, Key::KEY_F1, tapped);
+        assert!(matches!(last_release(&mut board), Outcome::Ran(1)));
+        tap(&mut board, &sender, &clock, Key::KEY_F1, tapped);
+        assert!(matches!(last_release(&mut board), Outcome::RateLimited));
+    }
+}
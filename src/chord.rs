@@ -0,0 +1,107 @@
+//! Macros for keys pressed together, named after their keys joined with `+`,
+//! e.g. a script called `KEY_LEFTCTRL+KEY_A`.
+//!
+//! A chord runs when one of its keys is released while the others are still held,
+//! or were pressed within `combo_window` of it. That way fast typists don't need
+//! perfectly overlapping key presses. The releases of the other keys of the chord
+//! are then swallowed. If no chord matches, keys run their own macros as usual.
+//!
+//! Whether a key that completes a chord also runs its own macro is up to
+//! `chord_conflict`, see `config.rs`. By default it doesn't.
+//!
+//! Mouse buttons work like keys, e.g. `KEY_LEFTALT+BTN_SIDE`, as long as the
+//! mouse is read too (see `mouse` and `always` in `config.rs`). Keys held on
+//! one device count for chords with the buttons of another.
+
+use evdev::{AttributeSet, Key};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Release {
+    /// Run this chord.
+    Chord(String),
+    /// The key was part of a chord that already ran.
+    Consumed,
+    /// Not part of a chord, handle the key on its own.
+    Single,
+}
+
+pub struct Chords {
+    /// Chord names and their keys, longest first.
+    chords: Vec<(String, Vec<Key>)>,
+    window: Duration,
+    /// When each key was last pressed.
+    press_times: HashMap<Key, SystemTime>,
+    /// Keys whose release is swallowed, because their chord already ran.
+    consumed: AttributeSet<Key>,
+}
+
+impl Chords {
+    pub fn new(window: Duration) -> Chords {
+        Chords {
+            chords: Vec::new(),
+            window,
+            press_times: HashMap::new(),
+            consumed: AttributeSet::new(),
+        }
+    }
+
+    /// Pick out the chords among the macro names.
+    pub fn set_macros<'a>(&mut self, names: impl IntoIterator<Item = &'a String>) {
+        self.chords = names
+            .into_iter()
+            .filter(|name| name.contains('+'))
+            .filter_map(|name| {
+                let keys: Option<Vec<Key>> = name.split('+').map(|key| key.parse().ok()).collect();
+                keys.map(|keys| (name.clone(), keys))
+            })
+            .collect();
+        self.chords
+            .sort_by_key(|(_, keys)| std::cmp::Reverse(keys.len()));
+    }
+
+    /// All chords, by name.
+    pub fn chords(&self) -> impl Iterator<Item = (&str, &[Key])> {
+        self.chords
+            .iter()
+            .map(|(name, keys)| (name.as_str(), keys.as_slice()))
+    }
+
+    pub fn press(&mut self, key: Key, at: SystemTime) {
+        self.press_times.insert(key, at);
+        self.consumed.remove(key);
+    }
+
+    /// `held` are the keys that are still held after releasing `key`.
+    pub fn release(&mut self, key: Key, held: &AttributeSet<Key>) -> Release {
+        if self.consumed.contains(key) {
+            self.consumed.remove(key);
+            return Release::Consumed;
+        }
+        let Some(pressed_at) = self.press_times.get(&key).copied() else {
+            return Release::Single;
+        };
+        let near = |other: &Key| {
+            self.press_times.get(other).is_some_and(|at| {
+                at.duration_since(pressed_at)
+                    .or_else(|_| pressed_at.duration_since(*at))
+                    .is_ok_and(|apart| apart <= self.window)
+            })
+        };
+        let Some((name, keys)) = self.chords.iter().find(|(_, keys)| {
+            keys.contains(&key)
+                && keys
+                    .iter()
+                    .all(|other| *other == key || held.contains(*other) || near(other))
+        }) else {
+            return Release::Single;
+        };
+        for other in keys.iter().filter(|other| held.contains(**other)) {
+            self.consumed.insert(*other);
+        }
+        Release::Chord(name.clone())
+    }
+}
@@ -0,0 +1,72 @@
+//! Where the [`MacroBoard`](crate::MacroBoard) gets the current time from, see
+//! [`MacroBoardBuilder::clock`](crate::MacroBoardBuilder::clock).
+//!
+//! Timing (`coalesce`, `max_event_age`, the notification throttle) is measured
+//! with it, and chords with the timestamps of the events themselves. So with a
+//! [`ManualClock`] and events with made-up timestamps, everything time-dependent
+//! can be driven step by step, without sleeping.
+
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    /// The wall clock time, for comparing with the timestamps of input events.
+    fn system_now(&self) -> SystemTime;
+}
+
+/// The real time, the default.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Starts at the time it's created, and then only moves with [`ManualClock::advance`].
+pub struct ManualClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) += by;
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.system_start + self.elapsed()
+    }
+}
@@ -0,0 +1,1544 @@
+//! Settings read from `~/.config/evdev-macros/config.toml`.
+//!
+//! The config file lives next to the macro scripts and is optional;
+//! every setting has a default.
+
+use crate::{actions::Action, log, log_format::LogFormat, privileges, process, schedule::Schedule};
+use evdev::{AbsoluteAxisType, BusType, EventType, Key, LedType};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// USB vendor id of the keyboard to grab.
+    pub vendor: u16,
+    /// USB product id of the keyboard to grab.
+    pub product: u16,
+    /// Unique id (usually the serial number) of the keyboard to grab, to tell
+    /// identical keyboards apart. Not all devices report one.
+    pub uniq: Option<String>,
+    /// Devices to grab instead of `vendor`, `product` and `uniq`, in order of
+    /// priority: we use the first one that is plugged in (or, if none is, the
+    /// first one to be plugged in later), e.g.
+    ///
+    /// ```toml
+    /// [[devices]]
+    /// name = "Work Keyboard"
+    /// [[devices]]
+    /// vendor = 0x046d
+    /// product = 0xc52b
+    /// # And a mouse, for chords like `KEY_LEFTALT+BTN_SIDE`.
+    /// [[devices]]
+    /// name = "Logitech G305"
+    /// mouse = true
+    /// always = true
+    /// ```
+    pub devices: Vec<Selector>,
+    /// Names for devices, to refer to them in `devices` with `alias`, instead of
+    /// repeating their ids, e.g.
+    ///
+    /// ```toml
+    /// [device_aliases]
+    /// macropad = { vendor = 0x413c, product = 0x2011, layer = "pad" }
+    ///
+    /// [[devices]]
+    /// name = "Work Keyboard"
+    /// [[devices]]
+    /// alias = "macropad"
+    /// always = true
+    /// ```
+    ///
+    /// An alias takes everything an entry of `devices` does, and the entries
+    /// using it get all of it, with what they set themselves taking precedence.
+    /// Aliases are resolved when the config is loaded, so `--print-config`
+    /// shows the entries as if they had been written out.
+    pub device_aliases: BTreeMap<String, Selector>,
+    /// Number of recent events kept for debugging, see `history.rs`.
+    pub history_size: usize,
+    /// A named pipe to copy the events to, relative to the config directory.
+    /// See `mirror.rs`.
+    pub mirror: Option<PathBuf>,
+    /// A file to log to as well (e.g. without journald), relative to the config
+    /// directory. See `log.rs`.
+    pub log_file: Option<PathBuf>,
+    /// The size in bytes at which the `log_file` is rotated.
+    pub log_max_size: u64,
+    /// How many rotated log files to keep. With 0, the `log_file` starts over
+    /// once it's full.
+    pub log_max_files: usize,
+    /// Which key event values mean pressed and released, for devices that don't
+    /// use the standard 1 and 0.
+    pub key_values: KeyValues,
+    /// Drop the keyboards' autorepeat events (value 2) right away, so they
+    /// aren't logged or kept in the history, except for the keys of macros with
+    /// `autorepeat`. Doesn't affect `repeat` and `while_held`, which keep time
+    /// themselves and only look at the press and release.
+    pub ignore_repeats: bool,
+    /// Show a desktop notification once the devices are grabbed.
+    pub startup_notification: bool,
+    /// Show a desktop notification when ESC stops the daemon (but not when
+    /// e.g. systemd does), so it's clear the macros are gone.
+    pub quit_notification: bool,
+    /// A macro to run once the devices are grabbed, e.g. `on_start = "setup"`
+    /// for the script `setup` or `[macros.setup]`, to set LEDs or a profile.
+    /// It runs like any other macro, as the user and with the same environment.
+    pub on_start: Option<String>,
+    /// A macro to run when the daemon exits, whether ESC or a signal stops it.
+    /// It gets two seconds, after which we exit anyway. It can't run if the daemon
+    /// is stopped with SIGKILL, or crashes.
+    pub on_stop: Option<String>,
+    /// When not to show desktop notifications, e.g.
+    /// `quiet_hours = { from = "22:00", to = "07:00" }` (see `schedule.rs`).
+    /// Macros still run, and what the notifications would have said is logged.
+    pub quiet_hours: Option<Schedule>,
+    /// How ESC has to be pressed to stop the daemon, so brushing it doesn't.
+    pub quit_confirm: QuitConfirm,
+    /// Seconds within which the second press of `double-tap` has to follow the
+    /// first, or that ESC has to be held for with `hold`.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub quit_confirm_window: Duration,
+    /// With `quit_confirm = "hold"`, ESC is a key like any other when it's
+    /// tapped (released within `quit_confirm_window`): it runs its macro, or is
+    /// typed on the virtual keyboard if it has none. Only holding it quits, e.g.
+    ///
+    /// ```toml
+    /// quit_confirm = "hold"
+    /// quit_confirm_window = 2
+    /// esc_passthrough = true
+    /// ```
+    ///
+    /// ESC is only typed once it's released, so it doesn't repeat in applications.
+    pub esc_passthrough: bool,
+    /// Template for the line logged for every key event, see `log_format.rs`.
+    pub log_format: LogFormat,
+    /// Absolute axes (e.g. `[axes.ABS_WHEEL]`) that trigger macros when they change zones.
+    #[serde(deserialize_with = "named_map", serialize_with = "serialize_named_map")]
+    pub axes: Vec<(AbsoluteAxisType, AxisConfig)>,
+    /// If set, only these keys may trigger macros (or quit, in the case of ESC).
+    #[serde(
+        deserialize_with = "optional_named_list",
+        serialize_with = "serialize_optional_named_list"
+    )]
+    pub allowed_keys: Option<Vec<Key>>,
+    /// Keys that never trigger macros (or quit, in the case of ESC).
+    /// Takes precedence over `allowed_keys`.
+    #[serde(
+        deserialize_with = "named_list",
+        serialize_with = "serialize_named_list"
+    )]
+    pub denied_keys: Vec<Key>,
+    /// Keys of the grabbed devices that still reach other applications (through
+    /// a virtual device) instead of triggering macros, e.g. the volume keys of
+    /// a keyboard. They're forwarded whatever state the board is in (paused,
+    /// disarmed or in safe mode). Needs `/dev/uinput`: without it, they're
+    /// grabbed like all other keys. See `passthrough.rs`.
+    ///
+    /// Also accepted as `always_passthrough`.
+    #[serde(
+        alias = "always_passthrough",
+        deserialize_with = "named_list",
+        serialize_with = "serialize_named_list"
+    )]
+    pub forward_keys: Vec<Key>,
+    /// Like `forward_keys`, for whole event types of the grabbed devices that
+    /// would otherwise be swallowed by the grab, e.g. `["EV_REL"]` for the
+    /// touchpad or trackpoint of a keyboard. Forwarding `EV_MSC` also hides the
+    /// scancodes from the board (see `EVDEV_SCANCODE` in `macros`). Other types
+    /// (like `EV_ABS`, whose virtual axes would need the ranges of the real
+    /// ones) can't be forwarded: they're only seen by us.
+    pub forward_types: Vec<ForwardType>,
+    /// Key that arms/disarms all macros, see `state.rs`.
+    #[serde(
+        deserialize_with = "optional_name",
+        serialize_with = "serialize_optional_name"
+    )]
+    pub arm_key: Option<Key>,
+    /// Key that switches to the next profile, see `profile.rs`.
+    #[serde(
+        deserialize_with = "optional_name",
+        serialize_with = "serialize_optional_name"
+    )]
+    pub profile_key: Option<Key>,
+    /// Log a warning about macros that run longer than this many seconds.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub macro_warn_after: Option<Duration>,
+    /// Send SIGTERM to macros (and everything they started) after this many seconds.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub macro_terminate_after: Option<Duration>,
+    /// Kill macros (and everything they started) after this many seconds.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub macro_timeout: Option<Duration>,
+    /// How many seconds apart keys may be pressed to still form a chord, see `chord.rs`.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub combo_window: Duration,
+    /// The longest wait, in seconds, between attempts to reconnect a device that
+    /// went away (or to read it again after an error). See `backoff.rs`.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub reconnect_max_delay: Duration,
+    /// Grab the devices again and catch up on their key state after the system
+    /// resumes from suspend, when grabs may have been lost. See `resume.rs`.
+    pub regrab_on_resume: bool,
+    /// Discard the events the devices buffered before we started reading them,
+    /// so keys pressed before the daemon started don't run macros.
+    pub flush_on_start: bool,
+    /// Every this many seconds, compare the keys each device says are held with
+    /// the presses and releases we got from it, and correct the keys we think
+    /// are held (logging it), in case an event ever went missing. Held keys
+    /// matter to chords, `repeat`, `while_held` and `forward_modifiers`.
+    ///
+    /// Off by default: the kernel already tells us when it drops events (see
+    /// `device.rs`), so this only guards against what it doesn't tell us, at the
+    /// cost of reading the key state of each device every interval. Checks only
+    /// happen while the device is idle, so keys that are being pressed don't
+    /// look like drift.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub resync_interval: Option<Duration>,
+    /// What happens when the thread reading a device panics, see `panics.rs`.
+    pub on_device_panic: DevicePanic,
+    /// What happens when a key that has its own macro completes a chord.
+    pub chord_conflict: ChordConflict,
+    /// Key events that are older than this many seconds by the time we get to
+    /// them (e.g. after the daemon stalled) don't trigger anything. Off if unset.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub max_event_age: Option<Duration>,
+    /// At most this many macro runs, across all macros, see `rate_limit.rs`.
+    /// By default, 500 every 10 seconds, which only a runaway device gets to.
+    /// Triggers beyond it are dropped, e.g. with
+    /// `rate_limit = { runs = 20, per = 10, pause = true }`. Runs of hooks,
+    /// `repeat` and `while_held` don't count.
+    pub rate_limit: RateLimit,
+    /// Run macros in a minimal environment (`PATH`, `HOME`, `USER`, `LOGNAME` and
+    /// the `EVDEV_*` variables) instead of the daemon's.
+    pub env_clear: bool,
+    /// Variables of the daemon's environment to keep with `env_clear`.
+    pub keep_env: Vec<String>,
+    /// Programs to run macro scripts with by their extension, e.g.
+    /// `interpreters = { py = "python3", js = "node" }`, for scripts that
+    /// can't run by themselves: they aren't executable, or have no shebang.
+    /// The script's path is added to the arguments.
+    pub interpreters: BTreeMap<String, String>,
+    /// Send mouse buttons from `send_keys` actions on a virtual pointer of their
+    /// own, instead of the virtual keyboard. Some compositors ignore buttons
+    /// from devices that look like keyboards.
+    pub virtual_pointer: bool,
+    /// Pass the modifier keys (Shift, Ctrl, Alt, Meta) of the grabbed keyboards
+    /// on to the virtual keyboard, so that keys sent while holding them combine
+    /// with them (e.g. holding Ctrl while a macro sends KEY_C copies).
+    pub forward_modifiers: bool,
+    /// Where `send_keys` actions end input reports (SYN_REPORT), for applications
+    /// that drop or reorder keys sent in a single report.
+    pub syn_reports: SynReports,
+    /// Keep the bindings made with the D-Bus `Bind` method (see `dbus.rs`)
+    /// when the macros are reloaded, including by switching profiles.
+    /// They never outlive the daemon either way.
+    pub keep_bindings: bool,
+    /// Settings for individual macros, by macro name (e.g. `[macros.KEY_F1]`).
+    /// Keys are looked up by their name first, then by their code (e.g.
+    /// `[macros.code_704]`), for keys the evdev crate has no name for. Either way,
+    /// key macros get the code in `EVDEV_KEY_CODE`, and the name of the device
+    /// in `EVDEV_DEVICE` (with `#1`, `#2`, … for identical devices, see `device.rs`).
+    /// Chords get those of the key whose release completed them.
+    ///
+    /// If the device reported a scancode for the key (MSC_SCAN), it's in
+    /// `EVDEV_SCANCODE`, in hex (e.g. `0x7003a`), for keys that only differ by
+    /// scancode. The kernel sends it right before the key event, in the same
+    /// report, so that's where we take it from: if a device sends it after the
+    /// key, or several keys in one report, the scancode may be missing or
+    /// belong to another key. Devices without scancodes don't get the variable.
+    ///
+    /// Keys of devices with a `layer` are looked up in it first, then like any
+    /// other key. E.g. for a numpad that is a macro pad, without taking the digits
+    /// away from the numpad of the keyboard:
+    ///
+    /// ```toml
+    /// [[devices]]
+    /// name = "AT Translated Set 2 keyboard"
+    /// # Only monitored, so its numpad keeps typing.
+    /// grab = false
+    /// [[devices]]
+    /// name = "USB Numpad"
+    /// always = true
+    /// layer = "pad"
+    /// ```
+    ///
+    /// Then `KEY_KP1` on the pad runs the script `pad/KEY_KP1` in the config
+    /// directory (or `[macros."pad/KEY_KP1"]`), and on the keyboard types a 1,
+    /// as long as there is no macro for `KEY_KP1` itself.
+    #[serde(serialize_with = "serialize_sorted")]
+    pub macros: HashMap<String, MacroConfig>,
+    /// More config files to merge into this one, as glob patterns relative to
+    /// the config directory (e.g. `["layers/*.toml"]`), which they can't
+    /// leave. See [`Config::load`].
+    pub include: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MacroConfig {
+    /// Overrides the global `macro_warn_after`.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub warn_after: Option<Duration>,
+    /// Overrides the global `macro_terminate_after`.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub terminate_after: Option<Duration>,
+    /// Overrides the global `macro_timeout`.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub timeout: Option<Duration>,
+    /// Run in order when the macro is triggered, in addition to any script.
+    pub actions: Vec<Action>,
+    /// Carry on with the next action when one fails, instead of stopping.
+    pub continue_on_error: bool,
+    /// Run after the `actions` (or those of the `toggle`) if they all succeeded,
+    /// e.g. to report that a backup worked.
+    pub on_success: Vec<Action>,
+    /// Run instead of `on_success` if one of them failed.
+    pub on_failure: Vec<Action>,
+    /// Run the actions when the key is pressed, and again while it's held,
+    /// like keyboard autorepeat. Without this, macros run on release.
+    pub repeat: Option<Repeat>,
+    /// Run on the keyboard's own autorepeat events as well, even with the global
+    /// `ignore_repeats`, e.g. for volume keys.
+    pub autorepeat: bool,
+    /// Niceness of the macro's scripts and `command` actions, from -20 (highest
+    /// priority) to 19. By default, they inherit the daemon's.
+    pub nice: Option<i32>,
+    /// I/O scheduling class of the macro's scripts and `command` actions, see ionice(1).
+    pub ionice: Option<IoClass>,
+    /// Overrides the global `chord_conflict` for this key.
+    pub chord_conflict: Option<ChordConflict>,
+    /// Run the macro scripts as this user instead of the one who started the daemon.
+    /// Only works if the daemon runs as root.
+    pub run_as: Option<String>,
+    /// Overrides the global `env_clear`.
+    pub env_clear: Option<bool>,
+    /// When the macro may run, see `schedule.rs`.
+    pub schedule: Option<Schedule>,
+    /// The macro to run instead outside of the `schedule`.
+    pub otherwise: Option<String>,
+    /// Alternate between two lists of actions, instead of running `actions`.
+    pub toggle: Option<Toggle>,
+    /// Run actions when the key is pressed, while it's held and when it's
+    /// released, instead of running `actions`.
+    pub while_held: Option<WhileHeld>,
+    /// Show that the macro is running, until its scripts and actions are done.
+    pub indicate_running: Option<IndicateRunning>,
+    /// Wait this many seconds after a trigger for more, and run once for all of
+    /// them, with their number in `EVDEV_COUNT` (e.g. to skip that many tracks).
+    /// Each trigger restarts the wait. Off if unset.
+    ///
+    /// What counts as a trigger is up to the other settings: releases older
+    /// than `max_event_age` and keys that are part of a chord don't count, and
+    /// triggers while paused or disarmed are dropped. Whether the macro is in its
+    /// `schedule` is decided when it runs, and a toggle only switches once.
+    /// Can't be combined with `repeat`, which runs on press.
+    #[serde(
+        deserialize_with = "optional_seconds",
+        serialize_with = "serialize_optional_seconds"
+    )]
+    pub coalesce: Option<Duration>,
+    /// Skip triggers while the scripts and actions of an earlier run of this
+    /// macro are still going, e.g. for a toggle script that mustn't run twice
+    /// at once. By default, runs can overlap.
+    pub single_instance: bool,
+}
+
+/// A macro that is either on or off, e.g. to start and stop a recording:
+///
+/// ```toml
+/// [macros.KEY_F9.toggle]
+/// on = [{ command = "start-recording" }]
+/// off = [{ command = "stop-recording" }]
+/// led = "LED_SCROLLL"
+/// persist = true
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Toggle {
+    /// Run when the toggle turns on.
+    pub on: Vec<Action>,
+    /// Run when it turns off again.
+    pub off: Vec<Action>,
+    /// Whether it's on when the daemon starts, so the first trigger runs `off`.
+    pub initial: bool,
+    /// Remember whether it's on across restarts, see `state.rs`.
+    pub persist: bool,
+    /// An LED of the grabbed keyboards to light while it's on.
+    #[serde(
+        deserialize_with = "optional_name",
+        serialize_with = "serialize_optional_name"
+    )]
+    pub led: Option<LedType>,
+}
+
+/// A macro for as long as its key is held, e.g. to scroll:
+///
+/// ```toml
+/// [macros.KEY_F8.while_held]
+/// on_press = [{ command = "start-scrolling" }]
+/// on_release = [{ command = "stop-scrolling" }]
+/// interval = { every = 0.1, actions = [{ send_keys = "KEY_DOWN" }] }
+/// ```
+///
+/// `on_release` runs once the others are done, even if they failed, and for
+/// keys still held when the daemon stops (as far as it gets to before exiting).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WhileHeld {
+    /// Run when the key is pressed.
+    pub on_press: Vec<Action>,
+    /// Run again and again after `on_press`, until the key is released.
+    pub interval: Option<Interval>,
+    /// Run when the key is released.
+    pub on_release: Vec<Action>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Interval {
+    /// Seconds to wait before each run.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub every: Duration,
+    pub actions: Vec<Action>,
+}
+
+/// Feedback while a macro runs, for scripts without a visible effect of their own:
+///
+/// ```toml
+/// [macros.KEY_F5]
+/// indicate_running = { led = "LED_SCROLLL", notification = "Backing up" }
+/// ```
+///
+/// Several runs of the macro at once share one indication, which ends with the last.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndicateRunning {
+    /// An LED of the grabbed keyboards to light.
+    #[serde(
+        deserialize_with = "optional_name",
+        serialize_with = "serialize_optional_name"
+    )]
+    pub led: Option<LedType>,
+    /// The summary of a low-urgency notification, closed when the macro is done.
+    /// Not shown with `--no-notify`.
+    pub notification: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Repeat {
+    /// Seconds the key has to be held before the first repeat.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub delay: Duration,
+    /// Repeats per second after that.
+    pub rate: f64,
+}
+
+impl Default for Repeat {
+    fn default() -> Self {
+        Repeat {
+            delay: Duration::from_millis(500),
+            rate: 25.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SynReports {
+    /// Every key event is its own report, like a real keyboard sends them (the default).
+    PerEvent,
+    /// All key events of a `send_keys` action are one report.
+    PerAction,
+    /// Like `per-action`, but consecutive `send_keys` actions share a report.
+    Batched,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChordConflict {
+    /// Only run the chord (the default).
+    PreferChord,
+    /// Run the chord and the key's own macros.
+    Both,
+    /// Like `prefer-chord`, but warn about every such key until it has its own
+    /// `chord_conflict` setting.
+    Explicit,
+}
+
+/// An event type `forward_types` can forward, by its name in
+/// `linux/input-event-codes.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ForwardType {
+    #[serde(rename = "EV_REL")]
+    Relative,
+    #[serde(rename = "EV_MSC")]
+    Misc,
+    #[serde(rename = "EV_SW")]
+    Switch,
+}
+
+impl ForwardType {
+    pub fn event_type(self) -> EventType {
+        match self {
+            ForwardType::Relative => EventType::RELATIVE,
+            ForwardType::Misc => EventType::MISC,
+            ForwardType::Switch => EventType::SWITCH,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuitConfirm {
+    /// Releasing ESC once is enough (the default).
+    Single,
+    /// Twice within `quit_confirm_window`.
+    DoubleTap,
+    /// Releasing it after holding it for `quit_confirm_window`.
+    Hold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub runs: u32,
+    /// Seconds.
+    #[serde(deserialize_with = "seconds", serialize_with = "serialize_seconds")]
+    pub per: Duration,
+    /// Also pause the macros when the limit is hit, until they're resumed
+    /// with the D-Bus `SetPaused` method.
+    pub pause: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            runs: 500,
+            per: Duration::from_secs(10),
+            pause: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DevicePanic {
+    /// Release the device and open it again, like after it was unplugged (the default).
+    Reconnect,
+    /// Stop reading all devices and exit with an error, e.g. for systemd to restart us.
+    Exit,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyValues {
+    pub press: Vec<i32>,
+    pub release: Vec<i32>,
+}
+
+impl Default for KeyValues {
+    fn default() -> Self {
+        KeyValues {
+            press: vec![1],
+            release: vec![0],
+        }
+    }
+}
+
+/// The value of the events that keyboards send while a key is held.
+pub const AUTOREPEAT: i32 = 2;
+
+/// What a key event means, see [`KeyValues`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Press,
+    Release,
+    /// Auto-repeat, or anything else we don't know.
+    Other,
+}
+
+impl KeyValues {
+    pub fn edge(&self, value: i32) -> Edge {
+        if self.release.contains(&value) {
+            Edge::Release
+        } else if self.press.contains(&value) {
+            Edge::Press
+        } else {
+            Edge::Other
+        }
+    }
+}
+
+impl MacroConfig {
+    /// The `actions`, those of the `toggle`, and `on_success` and `on_failure`.
+    pub fn all_actions(&self) -> impl Iterator<Item = &Action> {
+        let toggle = self
+            .toggle
+            .iter()
+            .flat_map(|toggle| toggle.on.iter().chain(&toggle.off));
+        let while_held = self.while_held.iter().flat_map(|held| {
+            let interval = held.interval.iter().flat_map(|interval| &interval.actions);
+            held.on_press.iter().chain(interval).chain(&held.on_release)
+        });
+        self.actions
+            .iter()
+            .chain(toggle)
+            .chain(while_held)
+            .chain(&self.on_success)
+            .chain(&self.on_failure)
+    }
+
+    /// The `global` limits, with the ones set for this macro replacing them.
+    pub fn limits(&self, global: Limits) -> Limits {
+        Limits {
+            warn: self.warn_after.or(global.warn),
+            terminate: self.terminate_after.or(global.terminate),
+            kill: self.timeout.or(global.kill),
+        }
+    }
+}
+
+/// When to step in on a macro that runs too long, as durations since it started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Log a warning.
+    pub warn: Option<Duration>,
+    /// Ask the macro to stop, with SIGTERM.
+    pub terminate: Option<Duration>,
+    /// Kill the macro, with SIGKILL.
+    pub kill: Option<Duration>,
+}
+
+impl Limits {
+    /// Whether the limits that are set come in the order warn, terminate, kill.
+    pub fn is_ordered(&self) -> bool {
+        let set: Vec<Duration> = [self.warn, self.terminate, self.kill]
+            .into_iter()
+            .flatten()
+            .collect();
+        set.windows(2).all(|pair| pair[0] < pair[1])
+    }
+}
+
+/// Which devices to grab, see `devices`. Every criterion that is set has to match.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Selector {
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    /// How the device is connected, as `--interactive` lists it, e.g. only USB
+    /// keyboards with `BUS_USB`, and not the one built into a laptop. The names
+    /// are those of the kernel, with their numbers: `BUS_PCI` (0x01),
+    /// `BUS_ISAPNP` (0x02), `BUS_USB` (0x03), `BUS_HIL` (0x04), `BUS_BLUETOOTH`
+    /// (0x05), `BUS_VIRTUAL` (0x06, e.g. uinput), `BUS_ISA` (0x10), `BUS_I8042`
+    /// (0x11, most built-in keyboards), `BUS_XTKBD` (0x12), `BUS_RS232` (0x13),
+    /// `BUS_GAMEPORT` (0x14), `BUS_PARPORT` (0x15), `BUS_AMIGA` (0x16), `BUS_ADB`
+    /// (0x17), `BUS_I2C` (0x18), `BUS_HOST` (0x19), `BUS_GSC` (0x1a), `BUS_ATARI`
+    /// (0x1b), `BUS_SPI` (0x1c), `BUS_RMI` (0x1d), `BUS_CEC` (0x1e) and
+    /// `BUS_INTEL_ISHTP` (0x1f).
+    #[serde(
+        deserialize_with = "optional_name",
+        serialize_with = "serialize_optional_name"
+    )]
+    pub bus_type: Option<BusType>,
+    /// The name the device reports, e.g. `AT Translated Set 2 keyboard`.
+    pub name: Option<String>,
+    /// A regular expression the name has to match, anywhere in it unless it's
+    /// anchored with `^` and `$`, e.g. `^Keychron K[0-9]+`. For names that vary
+    /// between firmware revisions. With `name` set as well, both have to match.
+    pub name_regex: Option<NameRegex>,
+    /// E.g. a link in `/dev/input/by-id/`.
+    pub path: Option<PathBuf>,
+    pub uniq: Option<String>,
+    /// Also match mice, not only keyboards. Mice are read without grabbing them
+    /// (unless `grab` says otherwise), so the pointer keeps working, but that
+    /// means their buttons also still reach other applications.
+    pub mouse: Option<bool>,
+    /// Grab the matching devices, so only we get their keys, or only monitor
+    /// them. Keys of monitored devices trigger macros and reach applications as
+    /// well, so their macros shouldn't be on keys that do something already.
+    /// By default, keyboards are grabbed and mice aren't. `--no-grab` overrides
+    /// this for all devices.
+    pub grab: Option<bool>,
+    /// Use the matching devices in addition to the first entry that matches,
+    /// instead of taking part in the priority order. If none is plugged in at
+    /// the start, we wait for one.
+    pub always: Option<bool>,
+    /// Look up the macros of the matching devices in this layer first, e.g.
+    /// `pad/KEY_KP1` before `KEY_KP1`, so their keys can have macros of their
+    /// own. See `macros`.
+    pub layer: Option<String>,
+    /// Take the settings of this entry of `device_aliases`, see there.
+    pub alias: Option<String>,
+}
+
+impl Selector {
+    /// Whether there is anything to match on.
+    pub fn has_criteria(&self) -> bool {
+        self.vendor.is_some()
+            || self.product.is_some()
+            || self.bus_type.is_some()
+            || self.name.is_some()
+            || self.name_regex.is_some()
+            || self.path.is_some()
+            || self.uniq.is_some()
+    }
+
+    /// Whether it matches mice as well, see `mouse`.
+    pub fn mouse(&self) -> bool {
+        self.mouse.unwrap_or_default()
+    }
+
+    /// Whether the devices it matches are used in addition to the others, see `always`.
+    pub fn always(&self) -> bool {
+        self.always.unwrap_or_default()
+    }
+
+    /// This selector, with what it doesn't set taken from `aliased`.
+    fn or(self, aliased: &Selector) -> Selector {
+        Selector {
+            vendor: self.vendor.or(aliased.vendor),
+            product: self.product.or(aliased.product),
+            bus_type: self.bus_type.or(aliased.bus_type),
+            name: self.name.or_else(|| aliased.name.clone()),
+            name_regex: self.name_regex.or_else(|| aliased.name_regex.clone()),
+            path: self.path.or_else(|| aliased.path.clone()),
+            uniq: self.uniq.or_else(|| aliased.uniq.clone()),
+            mouse: self.mouse.or(aliased.mouse),
+            grab: self.grab.or(aliased.grab),
+            always: self.always.or(aliased.always),
+            layer: self.layer.or_else(|| aliased.layer.clone()),
+            alias: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut criteria = Vec::new();
+        if let Some(vendor) = self.vendor {
+            criteria.push(format!("vendor {vendor:04x}"));
+        }
+        if let Some(product) = self.product {
+            criteria.push(format!("product {product:04x}"));
+        }
+        if let Some(bus_type) = self.bus_type {
+            criteria.push(format!("bus {bus_type:?}"));
+        }
+        if let Some(name) = &self.name {
+            criteria.push(format!("name {name:?}"));
+        }
+        if let Some(regex) = &self.name_regex {
+            criteria.push(format!("name matching {:?}", regex.0.as_str()));
+        }
+        if let Some(path) = &self.path {
+            criteria.push(format!("path {path}", path = path.display()));
+        }
+        if let Some(uniq) = &self.uniq {
+            criteria.push(format!("unique id {uniq}"));
+        }
+        write!(f, "{}", criteria.join(", "))
+    }
+}
+
+/// A `name_regex` of a [`Selector`], compiled when the config is loaded.
+#[derive(Clone, Debug)]
+pub struct NameRegex(pub regex::Regex);
+
+impl PartialEq for NameRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for NameRegex {}
+
+impl<'de> Deserialize<'de> for NameRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        regex::Regex::new(&pattern)
+            .map(NameRegex)
+            .map_err(|err| D::Error::custom(format!("invalid name_regex: {err}")))
+    }
+}
+
+impl Serialize for NameRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+/// Zone `n` covers the values from the `n`th threshold (inclusive) up to the next one,
+/// zone 0 everything below the first threshold. Moving into zone `n` runs the
+/// `<AXIS>_ZONE<n>` macro, e.g. `ABS_WHEEL_ZONE1`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AxisConfig {
+    /// Values separating the zones, in ascending order.
+    pub thresholds: Vec<i32>,
+}
+
+impl AxisConfig {
+    pub fn zone(&self, value: i32) -> usize {
+        self.thresholds
+            .iter()
+            .take_while(|threshold| **threshold <= value)
+            .count()
+    }
+}
+
+pub(crate) fn parse_name<K: FromStr, E: Error>(name: &str) -> Result<K, E> {
+    name.parse()
+        .map_err(|_| E::custom(format!("unknown name `{name}`")))
+}
+
+/// Deserialize a (possibly fractional) number of seconds.
+pub(crate) fn seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| D::Error::custom(format!("invalid duration: {seconds}")))
+}
+
+fn optional_seconds<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    seconds(deserializer).map(Some)
+}
+
+pub(crate) fn name<'de, D, K>(deserializer: D) -> Result<K, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr,
+{
+    parse_name(&String::deserialize(deserializer)?)
+}
+
+fn optional_name<'de, D, K>(deserializer: D) -> Result<Option<K>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr,
+{
+    name(deserializer).map(Some)
+}
+
+/// Deserialize a list of evdev names (e.g. `KEY_F1`), rejecting unknown names.
+fn named_list<'de, D, K>(deserializer: D) -> Result<Vec<K>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| parse_name(name))
+        .collect()
+}
+
+fn optional_named_list<'de, D, K>(deserializer: D) -> Result<Option<Vec<K>>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr,
+{
+    named_list(deserializer).map(Some)
+}
+
+/// Deserialize a table keyed by evdev names (e.g. `ABS_X`), rejecting unknown names.
+fn named_map<'de, D, K, V>(deserializer: D) -> Result<Vec<(K, V)>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr,
+    V: Deserialize<'de>,
+{
+    BTreeMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| parse_name(&name).map(|key| (key, value)))
+        .collect()
+}
+
+// The counterparts of the above, for `--print-config`.
+
+pub(crate) fn serialize_seconds<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn serialize_optional_seconds<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serialize_seconds(duration, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// evdev types print their names with `Debug`, e.g. `KEY_F1`.
+pub(crate) fn serialize_name<S: Serializer, K: std::fmt::Debug>(
+    name: &K,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{name:?}"))
+}
+
+fn serialize_optional_name<S: Serializer, K: std::fmt::Debug>(
+    name: &Option<K>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match name {
+        Some(name) => serialize_name(name, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_named_list<S: Serializer, K: std::fmt::Debug>(
+    names: &[K],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(names.iter().map(|name| format!("{name:?}")))
+}
+
+fn serialize_optional_named_list<S: Serializer, K: std::fmt::Debug>(
+    names: &Option<Vec<K>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match names {
+        Some(names) => serialize_named_list(names, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_named_map<S: Serializer, K: std::fmt::Debug, V: Serialize>(
+    map: &[(K, V)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().map(|(name, value)| (format!("{name:?}"), value)))
+}
+
+fn serialize_sorted<S: Serializer, V: Serialize>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            vendor: 0xa5c,
+            product: 0x4502,
+            uniq: None,
+            devices: Vec::new(),
+            device_aliases: BTreeMap::new(),
+            history_size: 100,
+            mirror: None,
+            log_file: None,
+            log_max_size: 10 * 1024 * 1024,
+            log_max_files: 3,
+            startup_notification: false,
+            quit_notification: false,
+            on_start: None,
+            on_stop: None,
+            quiet_hours: None,
+            quit_confirm: QuitConfirm::Single,
+            quit_confirm_window: Duration::from_millis(500),
+            esc_passthrough: false,
+            log_format: LogFormat::default(),
+            key_values: KeyValues::default(),
+            ignore_repeats: true,
+            axes: Vec::new(),
+            allowed_keys: None,
+            denied_keys: Vec::new(),
+            forward_keys: Vec::new(),
+            forward_types: Vec::new(),
+            arm_key: None,
+            profile_key: None,
+            macro_warn_after: None,
+            macro_terminate_after: None,
+            macro_timeout: None,
+            combo_window: Duration::from_millis(50),
+            reconnect_max_delay: Duration::from_secs(30),
+            regrab_on_resume: true,
+            flush_on_start: true,
+            resync_interval: None,
+            on_device_panic: DevicePanic::Reconnect,
+            chord_conflict: ChordConflict::PreferChord,
+            max_event_age: None,
+            rate_limit: RateLimit::default(),
+            env_clear: false,
+            keep_env: Vec::new(),
+            interpreters: BTreeMap::new(),
+            virtual_pointer: true,
+            forward_modifiers: false,
+            keep_bindings: false,
+            syn_reports: SynReports::PerEvent,
+            macros: HashMap::new(),
+            include: Vec::new(),
+        }
+    }
+}
+
+/// The home directory of the user we run macros for, i.e. who started the daemon,
+/// from their passwd entry.
+///
+/// If that's root, we have no way of telling whose macros to run:
+/// running everything as root is what the setuid binary is meant to avoid.
+pub(crate) fn home_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    use users::os::unix::UserExt;
+
+    check_user()?;
+    let user = users::get_user_by_uid(users::get_current_uid()).ok_or("User no longer exists!")?;
+    Ok(user.home_dir().to_path_buf())
+}
+
+fn check_user() -> Result<(), Box<dyn std::error::Error>> {
+    if users::get_current_uid() == 0 {
+        return Err(
+            "evdev-macros was started by root, so there is no user to run macros for. \
+            Start evdev-macros as your own user (it is installed setuid root), \
+            and use `run_as` for macros that need another user"
+                .into(),
+        );
+    }
+    Ok(())
+}
+
+/// Overrides the config directory, see [`config_dir`].
+pub const CONFIG_DIR_VAR: &str = "EVDEV_MACROS_CONFIG_DIR";
+
+static CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` instead of the default config directory, e.g. for `--config-dir`.
+/// Only the first call has an effect.
+pub fn set_config_dir(dir: PathBuf) {
+    CONFIG_DIR.set(dir).ok();
+}
+
+/// The directory containing the macro scripts and the config file: the one
+/// passed to [`set_config_dir`], else the one in [`CONFIG_DIR_VAR`], else
+/// `~/.config/evdev-macros/`.
+pub fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(dir) = overridden_config_dir() {
+        check_user()?;
+        return Ok(std::path::absolute(dir)?);
+    }
+    Ok(home_dir()?.join(".config/evdev-macros/"))
+}
+
+/// The config directory, if it isn't the default one.
+pub fn overridden_config_dir() -> Option<PathBuf> {
+    CONFIG_DIR.get().cloned().or_else(|| {
+        std::env::var_os(CONFIG_DIR_VAR)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+impl Config {
+    /// The devices to grab, in order of priority.
+    pub fn selectors(&self) -> Vec<Selector> {
+        if !self.devices.is_empty() {
+            return self.devices.clone();
+        }
+        vec![Selector {
+            vendor: Some(self.vendor),
+            product: Some(self.product),
+            uniq: self.uniq.clone(),
+            ..Selector::default()
+        }]
+    }
+
+    /// Only use devices with the unique id `uniq`, e.g. from `--uniq`.
+    pub fn override_uniq(&mut self, uniq: String) {
+        for selector in &mut self.devices {
+            selector.uniq = Some(uniq.clone());
+        }
+        self.uniq = Some(uniq);
+    }
+
+    /// The runtime limits for macros without settings of their own.
+    pub fn macro_limits(&self) -> Limits {
+        Limits {
+            warn: self.macro_warn_after,
+            terminate: self.macro_terminate_after,
+            kill: self.macro_timeout,
+        }
+    }
+
+    pub fn path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+        Ok(config_dir()?.join(CONFIG_FILE))
+    }
+
+    /// Load the config file, falling back to the defaults if it doesn't exist.
+    ///
+    /// Included files are merged in order after the file including them, so later
+    /// files override earlier ones. Tables (like `[macros.KEY_F1]`) are merged
+    /// key by key, everything else is replaced.
+    ///
+    /// The files are read with the user's file system ids: the daemon is setuid
+    /// root, and parse errors quote the files.
+    pub fn load() -> Result<Config, Box<dyn std::error::Error>> {
+        let path = Self::path()?;
+        if !privileges::as_user_fs(|| path.exists()) {
+            return Ok(Config::default());
+        }
+        let mut merged = toml::Table::new();
+        Self::load_file(&path, &mut Vec::new(), &mut merged)?;
+        let mut config = Config::deserialize(merged)
+            .map_err(|err| format!("{path}: {err}", path = path.display()))?;
+        config
+            .resolve_device_aliases()
+            .map_err(|err| format!("{path}: {err}", path = path.display()))?;
+        config
+            .validate()
+            .map_err(|err| format!("{path}: {err}", path = path.display()))?;
+        Ok(config)
+    }
+
+    /// Merge the file at `path` and everything it includes into `merged`.
+    ///
+    /// `stack` holds the files currently being loaded, to detect include cycles.
+    fn load_file(
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        merged: &mut toml::Table,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (canonical, contents) = privileges::as_user_fs(|| {
+            Ok::<_, std::io::Error>((path.canonicalize()?, std::fs::read_to_string(path)?))
+        })
+        .map_err(|err| format!("{path}: {err}", path = path.display()))?;
+        if stack.contains(&canonical) {
+            return Err(format!("{path}: include cycle", path = path.display()).into());
+        }
+        // Every file has to be a valid config on its own (all settings are optional),
+        // which gives us errors pointing at the right line.
+        let config: Config =
+            toml::from_str(&contents).map_err(|err| describe_error(path, &contents, &err))?;
+        log!("Loading config: {path}", path = path.display());
+        merge(merged, toml::from_str(&contents)?);
+
+        stack.push(canonical);
+        let dir = config_dir()?;
+        let canonical_dir = privileges::as_user_fs(|| dir.canonicalize())
+            .map_err(|err| format!("{dir}: {err}", dir = dir.display()))?;
+        for pattern in &config.include {
+            let pattern = dir.join(pattern);
+            let included = privileges::as_user_fs(|| Self::included(&pattern, &canonical_dir))
+                .map_err(|err| {
+                    format!(
+                        "{path}: include `{pattern}`: {err}",
+                        path = path.display(),
+                        pattern = pattern.display()
+                    )
+                })?;
+            for included in &included {
+                Self::load_file(included, stack, merged)?;
+            }
+            if included.is_empty() {
+                log!(
+                    "Include `{pattern}` matched no files",
+                    pattern = pattern.display()
+                );
+            }
+        }
+        stack.pop();
+        Ok(())
+    }
+
+    /// The files `pattern` matches, which all have to be in `dir` (canonical).
+    fn included(pattern: &Path, dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let mut included = Vec::new();
+        for path in glob::glob(&pattern.to_string_lossy())? {
+            let path = path?;
+            if !path.canonicalize()?.starts_with(dir) {
+                return Err(format!(
+                    "{path} is outside of the config directory",
+                    path = path.display()
+                )
+                .into());
+            }
+            included.push(path);
+        }
+        Ok(included)
+    }
+
+    /// Replace the `alias` of the entries of `devices` with what it stands for.
+    fn resolve_device_aliases(&mut self) -> Result<(), String> {
+        for (name, aliased) in &self.device_aliases {
+            if aliased.alias.is_some() {
+                return Err(format!("device alias `{name}` can't use another alias"));
+            }
+        }
+        for selector in &mut self.devices {
+            let Some(alias) = selector.alias.take() else {
+                continue;
+            };
+            let Some(aliased) = self.device_aliases.get(&alias) else {
+                let defined: Vec<&str> = self.device_aliases.keys().map(String::as_str).collect();
+                return Err(if defined.is_empty() {
+                    format!(
+                        "unknown device alias `{alias}` in devices, there is no [device_aliases]"
+                    )
+                } else {
+                    format!(
+                        "unknown device alias `{alias}` in devices, the defined ones are: {}",
+                        defined.join(", ")
+                    )
+                });
+            };
+            *selector = std::mem::take(selector).or(aliased);
+        }
+        Ok(())
+    }
+
+    /// Checks that can't be expressed in the types.
+    pub fn validate(&self) -> Result<(), String> {
+        for (hook, name) in [("on_start", &self.on_start), ("on_stop", &self.on_stop)] {
+            if name.as_deref() == Some("") {
+                return Err(format!("{hook} needs the name of a macro"));
+            }
+        }
+        for (axis, config) in &self.axes {
+            if !config.thresholds.windows(2).all(|pair| pair[0] < pair[1]) {
+                return Err(format!("thresholds of {axis:?} must be ascending"));
+            }
+        }
+        if let Some(value) = self
+            .key_values
+            .press
+            .iter()
+            .find(|value| self.key_values.release.contains(value))
+        {
+            return Err(format!(
+                "key value {value} can't mean both press and release"
+            ));
+        }
+        if self.ignore_repeats
+            && (self.key_values.press.contains(&AUTOREPEAT)
+                || self.key_values.release.contains(&AUTOREPEAT))
+        {
+            return Err(format!(
+                "key value {AUTOREPEAT} (autorepeat) is dropped, set ignore_repeats = false to use it"
+            ));
+        }
+        for (extension, interpreter) in &self.interpreters {
+            let Some(program) = interpreter.split_whitespace().next() else {
+                return Err(format!("interpreter for .{extension} scripts is empty"));
+            };
+            if !process::is_installed(program, self.env_clear) {
+                return Err(format!(
+                    "interpreter `{program}` for .{extension} scripts isn't installed"
+                ));
+            }
+        }
+        let quit_key = Some(Key::KEY_ESC);
+        if self.arm_key == quit_key || self.profile_key == quit_key {
+            return Err("KEY_ESC quits, it can't be the arm_key or profile_key".to_string());
+        }
+        if let Some(key) = self.forward_keys.iter().find(|key| {
+            Some(**key) == quit_key
+                || Some(**key) == self.arm_key
+                || Some(**key) == self.profile_key
+        }) {
+            return Err(format!(
+                "{key:?} can't be forwarded, evdev-macros needs it itself"
+            ));
+        }
+        if self.arm_key.is_some() && self.arm_key == self.profile_key {
+            return Err("arm_key and profile_key must be different keys".to_string());
+        }
+        if self.esc_passthrough && self.quit_confirm != QuitConfirm::Hold {
+            return Err("esc_passthrough needs quit_confirm = \"hold\"".to_string());
+        }
+        if self.quit_confirm_window.is_zero() {
+            return Err("quit_confirm_window must be positive".to_string());
+        }
+        if self.log_max_size == 0 {
+            return Err("log_max_size must be positive".to_string());
+        }
+        if self.rate_limit.runs == 0 || self.rate_limit.per.is_zero() {
+            return Err("rate_limit needs positive runs and per".to_string());
+        }
+        if self
+            .resync_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err("resync_interval must be positive".to_string());
+        }
+        if self.reconnect_max_delay.is_zero() {
+            return Err("reconnect_max_delay must be positive".to_string());
+        }
+        if self.uniq.as_deref() == Some("") {
+            return Err("uniq can't be empty".to_string());
+        }
+        for (name, aliased) in &self.device_aliases {
+            if !aliased.has_criteria() {
+                return Err(format!(
+                    "device alias `{name}` needs at least one criterion"
+                ));
+            }
+        }
+        for selector in &self.devices {
+            if !selector.has_criteria() {
+                return Err("every entry of devices needs at least one criterion".to_string());
+            }
+            if selector.uniq.as_deref() == Some("") {
+                return Err(format!("uniq of devices entry {selector} can't be empty"));
+            }
+            if let Some(layer) = &selector.layer {
+                if layer.is_empty() || layer.contains('/') || layer == "profiles" {
+                    return Err(format!(
+                        "invalid layer `{layer}` of devices entry {selector}, \
+                         it has to be a directory name other than profiles"
+                    ));
+                }
+            }
+        }
+        if !self.macro_limits().is_ordered() {
+            return Err("macro_warn_after, macro_terminate_after and macro_timeout \
+                        must be ascending"
+                .to_string());
+        }
+        for (name, config) in &self.macros {
+            if let Some(user) = &config.run_as {
+                if users::get_user_by_name(user).is_none() {
+                    return Err(format!("run_as of {name}: unknown user {user}"));
+                }
+            }
+            if !config.limits(self.macro_limits()).is_ordered() {
+                return Err(format!(
+                    "warn_after, terminate_after and timeout of {name} must be ascending"
+                ));
+            }
+            if config.nice.is_some_and(|nice| !(-20..=19).contains(&nice)) {
+                return Err(format!("nice of {name} must be between -20 and 19"));
+            }
+            if config.otherwise.is_some() && config.schedule.is_none() {
+                return Err(format!("otherwise of {name} needs a schedule"));
+            }
+            if config.otherwise.as_ref() == Some(name) {
+                return Err(format!("otherwise of {name} can't be {name} itself"));
+            }
+            if config.toggle.is_some() && !config.actions.is_empty() {
+                return Err(format!("{name} can have either actions or a toggle"));
+            }
+            if (!config.on_success.is_empty() || !config.on_failure.is_empty())
+                && config.toggle.is_none()
+                && config.actions.is_empty()
+            {
+                return Err(format!(
+                    "on_success and on_failure of {name} need actions to follow"
+                ));
+            }
+            if let Some(indicate) = &config.indicate_running {
+                if indicate.led.is_none() && indicate.notification.is_none() {
+                    return Err(format!(
+                        "indicate_running of {name} needs a led or a notification"
+                    ));
+                }
+            }
+            if config.coalesce.is_some_and(|coalesce| coalesce.is_zero()) {
+                return Err(format!("coalesce of {name} must be positive"));
+            }
+            if let Some(held) = &config.while_held {
+                if !config.actions.is_empty()
+                    || config.toggle.is_some()
+                    || config.repeat.is_some()
+                    || config.coalesce.is_some()
+                {
+                    return Err(format!(
+                        "{name} can't have actions, a toggle, repeat or coalesce with while_held"
+                    ));
+                }
+                if !config.on_success.is_empty() || !config.on_failure.is_empty() {
+                    return Err(format!(
+                        "{name} can't have on_success or on_failure with while_held"
+                    ));
+                }
+                if held.on_press.is_empty() && held.interval.is_none() && held.on_release.is_empty()
+                {
+                    return Err(format!("while_held of {name} needs actions"));
+                }
+                if let Some(interval) = &held.interval {
+                    if interval.every.is_zero() {
+                        return Err(format!("interval of {name} must be positive"));
+                    }
+                    if interval.actions.is_empty() {
+                        return Err(format!("interval of {name} needs actions to repeat"));
+                    }
+                }
+            }
+            if config.autorepeat && (config.repeat.is_some() || config.while_held.is_some()) {
+                return Err(format!(
+                    "{name} can't have autorepeat with repeat or while_held"
+                ));
+            }
+            if let Some(repeat) = config.repeat {
+                if config.coalesce.is_some() {
+                    return Err(format!("{name} can have either repeat or coalesce"));
+                }
+                if config.actions.is_empty() {
+                    return Err(format!("repeat of {name} needs actions to repeat"));
+                }
+                if !config.on_success.is_empty() || !config.on_failure.is_empty() {
+                    return Err(format!(
+                        "{name} can't have on_success or on_failure with repeat"
+                    ));
+                }
+                if !(repeat.rate > 0.0 && repeat.rate.is_finite()) {
+                    return Err(format!("repeat rate of {name} must be positive"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The config as TOML, with all defaults filled in and includes merged.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+
+    /// Store the device selection in the config file, keeping the rest of it
+    /// (including comments) intact.
+    ///
+    /// Must be called with the user's effective uid, so the file is owned by them.
+    pub fn save_device(vendor: u16, product: u16) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = Self::path()?;
+        let mut document = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.parse::<toml_edit::DocumentMut>()?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err.into()),
+        };
+        document["vendor"] = toml_edit::value(i64::from(vendor));
+        document["product"] = toml_edit::value(i64::from(product));
+
+        std::fs::create_dir_all(config_dir()?)?;
+        std::fs::write(&path, document.to_string())?;
+        Ok(path)
+    }
+}
+
+/// Merge `other` into `table`, recursing into tables that exist in both.
+fn merge(table: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                merge(existing, value)
+            }
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Format `err` as `path:line:column: message`, followed by the offending line and,
+/// for unknown names, the closest valid one.
+fn describe_error(path: &Path, contents: &str, err: &toml::de::Error) -> String {
+    let message = err.message().trim_end();
+    let mut description = match err.span() {
+        Some(span) => {
+            let line_start = contents[..span.start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = contents[span.start..]
+                .find('\n')
+                .map_or(contents.len(), |i| span.start + i);
+            let line_number = contents[..span.start].matches('\n').count() + 1;
+            let column = contents[line_start..span.start].chars().count() + 1;
+            let width = contents[span.start..span.end.min(line_end)]
+                .chars()
+                .count()
+                .max(1);
+            let gutter = " ".repeat(line_number.to_string().len());
+            format!(
+                "{path}:{line_number}:{column}: {message}\n\
+                 {gutter} |\n\
+                 {line_number} | {line}\n\
+                 {gutter} | {marker:>offset$}",
+                path = path.display(),
+                line = &contents[line_start..line_end],
+                marker = "^".repeat(width),
+                offset = column - 1 + width,
+            )
+        }
+        None => format!("{path}: {message}", path = path.display()),
+    };
+    if let Some(suggestion) = suggest(message) {
+        description.push_str(&format!("\ndid you mean `{suggestion}`?"));
+    }
+    description
+}
+
+/// For serde's "unknown field `x`, expected one of `a`, `b`" style messages,
+/// the expected name closest to `x`, if any is close enough to be a typo.
+fn suggest(message: &str) -> Option<&str> {
+    let (unknown, expected) = message.split_once("expected")?;
+    let unknown = unknown.split('`').nth(1)?;
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (edit_distance(unknown, candidate), candidate))
+        .filter(|(distance, _)| *distance <= (unknown.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,153 @@
+//! D-Bus control interface.
+//!
+//! The daemon runs with root as its effective user (it needs that to grab the
+//! keyboard), but the macros it runs belong to the user who started it.
+//! We therefore register on that user's *session* bus, the same bus their
+//! desktop automation tools talk to, rather than on the system bus, which would
+//! need a policy file and would expose the service to every user on the machine.
+//!
+//! The session bus only accepts connections from its owner, so the connection
+//! is established with the effective uid/gid temporarily switched to the user.
+//!
+//! Method calls are forwarded to the main loop as [`Command`]s, so they go
+//! through exactly the same code paths as key presses.
+
+use crate::{log, privileges::AsUser, Command};
+use crossbeam_channel::Sender;
+use std::{collections::HashMap, time::Duration};
+use zbus::{blocking::Connection, dbus_interface, fdo, SignalContext};
+
+pub const BUS_NAME: &str = "io.github.LeonMatthes.EvdevMacros";
+pub const OBJECT_PATH: &str = "/io/github/LeonMatthes/EvdevMacros";
+
+struct Service {
+    commands: Sender<Command>,
+}
+
+impl Service {
+    fn send(&self, command: Command) -> fdo::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| fdo::Error::Failed("Daemon is shutting down".to_string()))
+    }
+}
+
+#[dbus_interface(name = "io.github.LeonMatthes.EvdevMacros1")]
+impl Service {
+    /// Run the macro for `key` (e.g. "KEY_F1"), as if that key was released.
+    /// This also works while key presses are paused.
+    fn run_macro(&self, key: String) -> fdo::Result<()> {
+        self.send(Command::RunMacro(key))
+    }
+
+    /// Re-read the macro directory.
+    fn reload(&self) -> fdo::Result<()> {
+        self.send(Command::Reload)
+    }
+
+    /// Release the devices, and select and grab them again (e.g. after
+    /// swapping keyboards), without touching the macros.
+    fn regrab(&self) -> fdo::Result<()> {
+        self.send(Command::Regrab)
+    }
+
+    /// Stop (or resume) running macros on key presses.
+    fn set_paused(&self, paused: bool) -> fdo::Result<()> {
+        self.send(Command::SetPaused(paused))
+    }
+
+    /// Arm or disarm all macros, including `RunMacro`. This is remembered across restarts.
+    fn set_armed(&self, armed: bool) -> fdo::Result<()> {
+        self.send(Command::SetArmed(armed))
+    }
+
+    /// Switch to the named profile, or to only the shared macros with an empty name.
+    fn set_profile(&self, profile: String) -> fdo::Result<()> {
+        self.send(Command::SetProfile(
+            Some(profile).filter(|name| !name.is_empty()),
+        ))
+    }
+
+    /// Make the macro for `key` (e.g. "KEY_F1", or any other macro name) run
+    /// the shell `command`, in place of its scripts and actions. Its other
+    /// settings (e.g. `schedule`) still apply. Bindings aren't saved: they're
+    /// gone after a restart, and after a reload unless `keep_bindings` is set.
+    fn bind(&self, key: String, command: String) -> fdo::Result<()> {
+        if key.is_empty() || command.trim().is_empty() {
+            return Err(fdo::Error::InvalidArgs(
+                "Both the key and the command are needed".to_string(),
+            ));
+        }
+        self.send(Command::Bind {
+            macro_name: key,
+            command,
+        })
+    }
+
+    /// Remove the binding of `Bind` for `key`, so its own macro runs again.
+    fn unbind(&self, key: String) -> fdo::Result<()> {
+        self.send(Command::Unbind(key))
+    }
+
+    /// The most recent key events and what was done about them, oldest first.
+    fn history(&self) -> fdo::Result<Vec<String>> {
+        let (reply, history) = crossbeam_channel::bounded(1);
+        self.send(Command::History(reply))?;
+        history
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|_| fdo::Error::Failed("Daemon did not respond".to_string()))
+    }
+
+    /// How long events took to reach us and scripts to start, as percentiles.
+    fn latency(&self) -> fdo::Result<Vec<String>> {
+        let (reply, latency) = crossbeam_channel::bounded(1);
+        self.send(Command::Latency(reply))?;
+        latency
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|_| fdo::Error::Failed("Daemon did not respond".to_string()))
+    }
+
+    /// The configuration in effect, as TOML, like `--print-config`.
+    fn config(&self) -> fdo::Result<String> {
+        let (reply, config) = crossbeam_channel::bounded(1);
+        self.send(Command::Config(reply))?;
+        config
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|_| fdo::Error::Failed("Daemon did not respond".to_string()))
+    }
+
+    /// Whether each macro with a `toggle` is on, by macro name.
+    fn toggles(&self) -> fdo::Result<HashMap<String, bool>> {
+        let (reply, toggles) = crossbeam_channel::bounded(1);
+        self.send(Command::Toggles(reply))?;
+        toggles
+            .recv_timeout(Duration::from_secs(1))
+            .map_err(|_| fdo::Error::Failed("Daemon did not respond".to_string()))
+    }
+
+    #[dbus_interface(signal)]
+    async fn macro_triggered(ctxt: &SignalContext<'_>, key: &str) -> zbus::Result<()>;
+}
+
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    pub fn start(commands: Sender<Command>) -> Result<DbusService, Box<dyn std::error::Error>> {
+        let _as_user = AsUser::switch()?;
+        let connection = zbus::blocking::ConnectionBuilder::session()?
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, Service { commands })?
+            .build()?;
+        Ok(DbusService { connection })
+    }
+
+    pub fn macro_triggered(&self, key: &str) {
+        let result = SignalContext::new(self.connection.inner(), OBJECT_PATH)
+            .and_then(|ctxt| zbus::block_on(Service::macro_triggered(&ctxt, key)));
+        if let Err(err) = result {
+            log!("Failed to emit MacroTriggered signal: {err}");
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! The `dbus` action, e.g. for controlling media players:
+//!
+//! ```toml
+//! [macros.KEY_F8]
+//! actions = [{ dbus = { destination = "org.mpris.MediaPlayer2.spotify", path = "/org/mpris/MediaPlayer2", interface = "org.mpris.MediaPlayer2.Player", method = "PlayPause" } }]
+//!
+//! [macros.KEY_F9]
+//! actions = [{ dbus = { destination = "org.mpris.MediaPlayer2.spotify", path = "/org/mpris/MediaPlayer2", interface = "org.freedesktop.DBus.Properties", method = "Set", args = ["org.mpris.MediaPlayer2.Player", "Volume", { variant = 0.5 }] } }]
+//! ```
+//!
+//! Calls go to the user's session bus (see `session.rs`) or, with
+//! `bus = "system"`, to the system bus, as the user either way. The reply is
+//! only checked for being an error, its contents are ignored. For anything
+//! more, run `busctl` or `gdbus` with a `command`.
+
+use crate::{config, privileges, process};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use std::{fmt, time::Duration};
+use zbus::{
+    names::{BusName, InterfaceName, MemberName},
+    zvariant::{ObjectPath, StructureBuilder, Value},
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Call {
+    #[serde(default)]
+    pub bus: Bus,
+    #[serde(deserialize_with = "destination")]
+    pub destination: String,
+    #[serde(deserialize_with = "path")]
+    pub path: String,
+    #[serde(deserialize_with = "interface")]
+    pub interface: String,
+    #[serde(deserialize_with = "method")]
+    pub method: String,
+    #[serde(default)]
+    pub args: Vec<Arg>,
+    /// Seconds to wait for connecting and the reply, together.
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "config::seconds",
+        serialize_with = "config::serialize_seconds"
+    )]
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    /// The user's session bus (the default).
+    #[default]
+    Session,
+    System,
+}
+
+/// An argument of a method call. Only these types are supported: TOML strings
+/// are sent as D-Bus strings (`s`), booleans as `b`, integers as 64-bit
+/// integers (`x`), floats as doubles (`d`), and `{ variant = … }` as a variant
+/// (`v`) holding one of them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Arg {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Variant { variant: Box<Arg> },
+}
+
+impl Arg {
+    fn value(&self) -> Value<'_> {
+        match self {
+            Arg::Bool(value) => Value::Bool(*value),
+            Arg::Integer(value) => Value::I64(*value),
+            Arg::Float(value) => Value::F64(*value),
+            Arg::String(value) => Value::from(value.as_str()),
+            Arg::Variant { variant } => Value::Value(Box::new(variant.value())),
+        }
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Deserialize a string that has to pass `is_valid`, e.g. as a [`BusName`].
+fn valid<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    what: &str,
+    is_valid: impl FnOnce(&str) -> bool,
+) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    if !is_valid(&name) {
+        return Err(D::Error::custom(format!("`{name}` isn't a valid {what}")));
+    }
+    Ok(name)
+}
+
+fn destination<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    valid(deserializer, "bus name", |name| {
+        BusName::try_from(name).is_ok()
+    })
+}
+
+fn path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    valid(deserializer, "object path", |path| {
+        ObjectPath::try_from(path).is_ok()
+    })
+}
+
+fn interface<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    valid(deserializer, "interface name", |name| {
+        InterfaceName::try_from(name).is_ok()
+    })
+}
+
+fn method<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    valid(deserializer, "method name", |name| {
+        MemberName::try_from(name).is_ok()
+    })
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{destination} {path} {interface}.{method}",
+            destination = self.destination,
+            path = self.path,
+            interface = self.interface,
+            method = self.method
+        )
+    }
+}
+
+impl Call {
+    /// Make the call, on another thread so we can give up after the timeout.
+    pub fn send(&self, env: &process::Env) -> Result<(), String> {
+        let address =
+            match self.bus {
+                Bus::Session => Some(session_address(env).ok_or_else(|| {
+                    format!("Failed to call {self}: the session bus wasn't found")
+                })?),
+                Bus::System => None,
+            };
+        let (sender, reply) = crossbeam_channel::bounded(1);
+        let call = self.clone();
+        std::thread::spawn(move || {
+            sender.send(call.exchange(address.as_deref())).ok();
+        });
+        reply
+            .recv_timeout(self.timeout)
+            .unwrap_or_else(|_| Err(format!("timed out after {:.1?}", self.timeout)))
+            .map_err(|err| format!("Failed to call {self}: {err}"))
+    }
+
+    fn exchange(&self, address: Option<&str>) -> Result<(), String> {
+        // The buses only let the user do what they could do anyway.
+        let connection = privileges::as_user_thread(|| match address {
+            Some(address) => zbus::blocking::ConnectionBuilder::address(address)?.build(),
+            None => zbus::blocking::Connection::system(),
+        })
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())?;
+        let result = if self.args.is_empty() {
+            connection.call_method(
+                Some(self.destination.as_str()),
+                self.path.as_str(),
+                Some(self.interface.as_str()),
+                self.method.as_str(),
+                &(),
+            )
+        } else {
+            let body = self
+                .args
+                .iter()
+                .fold(StructureBuilder::new(), |body, arg| {
+                    body.append_field(arg.value())
+                })
+                .build();
+            connection.call_method(
+                Some(self.destination.as_str()),
+                self.path.as_str(),
+                Some(self.interface.as_str()),
+                self.method.as_str(),
+                &body,
+            )
+        };
+        // Error messages of D-Bus errors may end with a newline.
+        result
+            .map(|_| ())
+            .map_err(|err| err.to_string().trim_end().to_string())
+    }
+}
+
+/// Where the session bus of the user is, as found by `session.rs` or in our environment.
+fn session_address(env: &process::Env) -> Option<String> {
+    env.vars
+        .iter()
+        .find(|(name, _)| name == "DBUS_SESSION_BUS_ADDRESS")
+        .map(|(_, address)| address.clone())
+        .or_else(|| std::env::var("DBUS_SESSION_BUS_ADDRESS").ok())
+}
@@ -0,0 +1,1444 @@
+//! Finding the devices to read from, and the threads reading them.
+
+use crate::{
+    actions,
+    backoff::Backoff,
+    config::{DevicePanic, Edge, KeyValues, Selector},
+    held_keys, log,
+    passthrough::Passthrough,
+    resume::Resume,
+    source::{self, EventSource},
+    systemd::FdDevice,
+    uinput, MacroBoard,
+};
+use crossbeam_channel::Sender;
+use evdev::{
+    raw_stream::RawDevice, AbsoluteAxisType, AttributeSet, Device, InputEvent, InputEventKind, Key,
+    Synchronization,
+};
+use std::{
+    collections::HashSet,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// What the grabbing threads send to the [`MacroBoard`].
+pub enum Input {
+    /// The events of one report, up to and including its SYN_REPORT, which are
+    /// handled together so e.g. the keys of a chord pressed at once aren't split
+    /// up by other input.
+    Report {
+        events: Vec<InputEvent>,
+        /// Name of the device they came from.
+        device: Arc<str>,
+        /// The `layer` of the device, see `config.rs`.
+        layer: Option<Arc<str>>,
+    },
+    /// The kernel dropped events (SYN_DROPPED), these are the keys that are actually held now.
+    Resync(AttributeSet<Key>),
+    /// The periodic check of `resync_interval` (see `config.rs`) found that
+    /// these keys of `device` are `held` or were `released`, unlike its events said.
+    Corrected {
+        device: Arc<str>,
+        held: AttributeSet<Key>,
+        released: AttributeSet<Key>,
+    },
+    /// The thread reading this device panicked, see `panics.rs`.
+    Panicked(Arc<str>),
+    /// We're no longer allowed to read `device`, see [`Ended::Denied`].
+    Denied {
+        device: Arc<str>,
+        /// Whether we try to open it again.
+        reconnecting: bool,
+    },
+}
+
+/// Holding this key while the daemon starts has the same effect as `--safe-mode`.
+///
+/// Every device we grab has it, see [`is_keyboard`].
+pub const SAFE_MODE_KEY: Key = Key::KEY_ESC;
+
+/// Whether `device` looks like a keyboard, i.e. has an ESC key to quit with.
+pub fn is_keyboard(device: &Device) -> bool {
+    device
+        .supported_keys()
+        .map(|keys| keys.contains(Key::KEY_ESC))
+        .unwrap_or_default()
+}
+
+/// Whether to grab `device`, which `selector` selected, see `grab` in `config.rs`.
+///
+/// Grabbing a mouse would take the pointer away from everyone else, so we don't by default.
+fn grabs(selector: &Selector, device: &Device) -> bool {
+    selector.grab.unwrap_or(!is_mouse(device))
+}
+
+/// Whether `device` is a mouse, i.e. has a left button but no keys.
+fn is_mouse(device: &Device) -> bool {
+    !is_keyboard(device)
+        && device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(Key::BTN_LEFT))
+}
+
+/// Whether `device` has the unique id `uniq`, if one is required.
+fn matches_uniq(device: &Device, uniq: Option<&str>) -> bool {
+    let Some(uniq) = uniq else {
+        return true;
+    };
+    match device.unique_name().filter(|name| !name.is_empty()) {
+        Some(name) => name == uniq,
+        None => {
+            log!(
+                "Skipping {name}: looking for unique id {uniq}, but it doesn't report one",
+                name = device.name().unwrap_or("unnamed device")
+            );
+            false
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Wait {
+    Readable,
+    Stopped,
+    TimedOut,
+}
+
+/// Wait until `device` has events to read or `stop` is closed, for at most `timeout`.
+///
+/// Blocking in `fetch_events` instead would keep the thread from noticing shutdown
+/// until the next key press.
+fn wait_readable(device: &impl AsRawFd, stop: &OwnedFd, timeout: Duration) -> io::Result<Wait> {
+    let mut fds = [device.as_raw_fd(), stop.as_raw_fd()].map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    // SAFETY: `fds` is a valid array of pollfds that outlives the call.
+    match unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout.as_millis() as libc::c_int) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ if fds[1].revents != 0 => Ok(Wait::Stopped),
+        _ if fds[0].revents != 0 => Ok(Wait::Readable),
+        _ => Ok(Wait::TimedOut),
+    }
+}
+
+/// The threads reading the devices, see [`grab_devices`].
+pub struct Grabbers {
+    /// Each returns whether it gave up after a panic, see `on_device_panic` in `config.rs`.
+    threads: Vec<JoinHandle<bool>>,
+    /// The names of the devices, as they were first opened.
+    names: Vec<Arc<str>>,
+    /// Whether systemd passed the devices, so we can't [`regrab`] them.
+    passed: bool,
+    /// The write end of the pipe the threads poll along with their device.
+    /// Closing it wakes them all up at once.
+    stop: OwnedFd,
+    /// The read end, for new threads.
+    stopped: OwnedFd,
+}
+
+impl Grabbers {
+    fn new() -> io::Result<Grabbers> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 just opened these, and nothing else owns them.
+        let (stopped, stop) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Grabbers {
+            threads: Vec::new(),
+            names: Vec::new(),
+            passed: false,
+            stop,
+            stopped,
+        })
+    }
+
+    /// The number of devices we read, not counting those we're waiting for.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Stop reading, release the grabs and wait for the threads to finish.
+    ///
+    /// The threads wake up right away, so this only takes as long as the
+    /// ungrabbing does (well under a millisecond without held keys).
+    ///
+    /// Returns whether all threads ended cleanly, rather than exiting after a
+    /// panic (see `on_device_panic` in `config.rs`).
+    pub fn join(self) -> bool {
+        drop(self.stop);
+        let mut clean = true;
+        for thread in self.threads {
+            // Panics are caught in the threads, so this is only a safety net.
+            clean &= thread.join().is_ok_and(|failed| !failed);
+        }
+        clean
+    }
+}
+
+/// Whether `err` just means we should try again.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Whether `err` means we're no longer allowed to read the device, e.g. because
+/// udev took away the ACL of the user when switching to another session.
+fn is_denied(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied
+}
+
+/// Sleep for `delay`, unless `stop` is closed first. Returns whether it was.
+fn sleep_unless_stopped(stop: &OwnedFd, delay: Duration) -> bool {
+    let mut fd = libc::pollfd {
+        fd: stop.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a valid pollfd that outlives the call.
+    unsafe { libc::poll(&mut fd, 1, delay.as_millis() as libc::c_int) > 0 }
+}
+
+/// Why a grabbing thread stopped reading its device.
+enum Ended {
+    Stopped,
+    /// The device went away, e.g. it was unplugged.
+    Lost,
+    /// Reading the device was denied, e.g. after udev changed its ACL on fast
+    /// user switching or a seat change. Reconnected like [`Ended::Lost`], as
+    /// opening it again may work once the devices are re-enumerated under the
+    /// new session.
+    Denied,
+}
+
+/// Reopens a device after it was [`Ended::Lost`].
+type Reopen<D> = Box<dyn FnMut() -> io::Result<D> + Send>;
+
+/// The longest [`Reader::flush`] takes.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Some devices keep saying they're readable, but have no events. After this
+/// many reads in a row without any, we wait [`EMPTY_READ_DELAY`] between reads,
+/// instead of spinning.
+const EMPTY_READS_BEFORE_DELAY: u32 = 10;
+const EMPTY_READ_DELAY: Duration = Duration::from_millis(10);
+/// After this many (about 5s), we treat the device as lost, and reconnect it.
+const EMPTY_READS_BEFORE_STALLED: u32 = 500;
+
+/// How a grabbing thread reads its device, see [`grab_inputs`].
+struct Reader {
+    name: Arc<str>,
+    layer: Option<Arc<str>>,
+    sender: Sender<Input>,
+    terminate: Arc<AtomicBool>,
+    stop: OwnedFd,
+    grab: bool,
+    passthrough: Option<Passthrough>,
+    /// Between retries after errors, shared between read errors and reconnecting.
+    backoff: Backoff,
+    /// With `regrab_on_resume`, see `resume.rs`.
+    resume: Option<Resume>,
+    /// Whether to [`Reader::flush`] before reading, which only happens once.
+    flush: bool,
+    on_panic: DevicePanic,
+    /// See `resync_interval` in `config.rs`.
+    resync_interval: Option<Duration>,
+    /// The keys that are held, as far as the events we sent tell.
+    held: AttributeSet<Key>,
+    /// Which events of `held` are presses and releases, as for the board.
+    key_values: KeyValues,
+    /// Set while we are discarding events after a SYN_DROPPED.
+    dropped: bool,
+    /// The events of the current report until its SYN_REPORT, even if that
+    /// only comes with the next read.
+    report: Vec<InputEvent>,
+}
+
+impl Reader {
+    /// Grab `device` and read it until we're stopped or it goes away.
+    fn read(&mut self, device: &mut impl EventSource) -> Ended {
+        let held = if self.grab {
+            match held_keys::grab(device) {
+                Ok(held) => held,
+                Err(err) => {
+                    log!("Failed to grab {name}: {err}", name = self.name);
+                    return Ended::Lost;
+                }
+            }
+        } else {
+            AttributeSet::new()
+        };
+        self.backoff.reset();
+        if std::mem::take(&mut self.flush) {
+            self.flush(device);
+        }
+        self.dropped = false;
+        self.report.clear();
+        let mut empty_reads = 0;
+        let mut stalled = false;
+        // Keys held while grabbing don't reach the board as presses either.
+        self.held = device.get_key_state().unwrap_or_default();
+        let mut last_check = Instant::now();
+        while !self.terminate.load(Ordering::Relaxed) {
+            if let Some(suspended) = self.resume.as_mut().and_then(Resume::check) {
+                log!(
+                    "Resumed after {suspended:.0?} of suspend, re-grabbing {name}",
+                    name = self.name
+                );
+                if let Err(err) = self.regrab(device) {
+                    log!("{name} didn't survive the suspend: {err}", name = self.name);
+                    return Ended::Lost;
+                }
+            }
+            let result =
+                wait_readable(device, &self.stop, Duration::from_millis(100)).and_then(|wait| {
+                    match wait {
+                        Wait::Readable => self.process_events(device).map(Some),
+                        Wait::Stopped => Err(io::ErrorKind::BrokenPipe.into()),
+                        Wait::TimedOut => Ok(None),
+                    }
+                });
+            let idle = matches!(result, Ok(None));
+            match result {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => return Ended::Lost,
+                Err(e) if is_denied(&e) => {
+                    log!(
+                        "No longer allowed to read {name} ({e}): its permissions changed, \
+                         probably because of a session switch",
+                        name = self.name
+                    );
+                    return Ended::Denied;
+                }
+                Err(e) if is_transient(&e) => (),
+                Err(e) => {
+                    let delay = self.backoff.delay();
+                    log!("Error: {e} - retrying in {delay:.1?}");
+                    if sleep_unless_stopped(&self.stop, delay) {
+                        break;
+                    }
+                }
+                Ok(Some(0)) => {
+                    empty_reads += 1;
+                    if empty_reads >= EMPTY_READS_BEFORE_STALLED {
+                        log!(
+                            "{name} stalled: it keeps being readable without any events",
+                            name = self.name
+                        );
+                        // Unlike a device that is gone, it's still grabbed.
+                        stalled = true;
+                        break;
+                    }
+                    if empty_reads >= EMPTY_READS_BEFORE_DELAY
+                        && sleep_unless_stopped(&self.stop, EMPTY_READ_DELAY)
+                    {
+                        break;
+                    }
+                }
+                Ok(_) => {
+                    self.backoff.reset();
+                    empty_reads = 0;
+                }
+            }
+            if let Some(interval) = self.resync_interval {
+                if idle && !self.dropped && last_check.elapsed() >= interval {
+                    self.check_key_state(device);
+                    last_check = Instant::now();
+                }
+            }
+        }
+        if self.grab {
+            if let Err(err) = held_keys::ungrab(device, &held) {
+                log!("Failed to release held keys: {err}");
+            }
+        }
+        if stalled {
+            Ended::Lost
+        } else {
+            Ended::Stopped
+        }
+    }
+
+    /// Returns how many events the device had.
+    fn process_events(&mut self, device: &mut impl EventSource) -> io::Result<usize> {
+        let events = device.fetch_events()?;
+        let count = events.len();
+        for ev in events {
+            if let (InputEventKind::Key(key), false) = (ev.kind(), self.dropped) {
+                match self.key_values.edge(ev.value()) {
+                    Edge::Release => self.held.remove(key),
+                    Edge::Press => self.held.insert(key),
+                    Edge::Other => (),
+                }
+            }
+            match ev.kind() {
+                InputEventKind::Synchronization(Synchronization::SYN_DROPPED) => {
+                    log!("Kernel dropped events - resynchronizing");
+                    self.dropped = true;
+                    // What we have of the report still happened, the resync corrects the rest.
+                    self.send_report();
+                }
+                // After SYN_DROPPED, everything up to and including the next SYN_REPORT
+                // is incomplete and must be discarded. Then the device state can be re-read.
+                // See: https://www.kernel.org/doc/html/latest/input/event-codes.html#ev-syn
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) if self.dropped => {
+                    self.dropped = false;
+                    self.held = device.get_key_state()?;
+                    self.sender.send(Input::Resync(self.held.clone())).ok();
+                }
+                _ if self.dropped => (),
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                    if let Some(passthrough) = &mut self.passthrough {
+                        passthrough.flush();
+                    }
+                    self.report.push(ev);
+                    self.send_report();
+                }
+                _ if self
+                    .passthrough
+                    .as_mut()
+                    .is_some_and(|passthrough| passthrough.forward(ev)) => {}
+                _ => self.report.push(ev),
+            }
+        }
+        Ok(count)
+    }
+
+    /// Send the events collected in [`Reader::report`] to the board, if there are any.
+    fn send_report(&mut self) {
+        if self.report.is_empty() {
+            return;
+        }
+        self.sender
+            .send(Input::Report {
+                events: std::mem::take(&mut self.report),
+                device: Arc::clone(&self.name),
+                layer: self.layer.clone(),
+            })
+            .ok();
+    }
+
+    /// Discard the events `device` buffered before we started, see `flush_on_start`
+    /// in `config.rs`. Gives up after [`FLUSH_TIMEOUT`], in case events keep coming.
+    fn flush(&mut self, device: &mut impl EventSource) {
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+        let mut discarded = 0;
+        while Instant::now() < deadline {
+            match wait_readable(device, &self.stop, Duration::ZERO) {
+                Ok(Wait::Readable) => match device.fetch_events() {
+                    Ok(events) => discarded += events.len(),
+                    Err(err) if is_transient(&err) => (),
+                    Err(_) => break,
+                },
+                _ => break,
+            }
+        }
+        if discarded > 0 {
+            log!(
+                "Discarded {discarded} event(s) {name} buffered before we started",
+                name = self.name
+            );
+        }
+    }
+
+    /// Grab `device` again (the grab may not have survived a suspend), and
+    /// catch up on the keys that were pressed or released meanwhile.
+    ///
+    /// Fails if the device is gone, even if the kernel hasn't told us yet.
+    fn regrab(&mut self, device: &mut impl EventSource) -> io::Result<()> {
+        let keys = device.get_key_state()?;
+        if self.grab {
+            // Grabbing twice fails, so we have to let go first.
+            device.ungrab().ok();
+            device.grab()?;
+        }
+        self.held = keys.clone();
+        self.sender.send(Input::Resync(keys)).ok();
+        Ok(())
+    }
+
+    /// Compare the keys the kernel says are held with [`Reader::held`], and
+    /// tell the board about any difference, see `resync_interval` in `config.rs`.
+    fn check_key_state(&mut self, device: &mut impl EventSource) {
+        let keys = match device.get_key_state() {
+            Ok(keys) => keys,
+            Err(err) => {
+                log!("Failed to read the keys of {name}: {err}", name = self.name);
+                return;
+            }
+        };
+        // If events came in since, the state is ahead of what we've read.
+        if !matches!(
+            wait_readable(device, &self.stop, Duration::ZERO),
+            Ok(Wait::TimedOut)
+        ) {
+            return;
+        }
+        let mut held = AttributeSet::new();
+        for key in keys.iter().filter(|key| !self.held.contains(*key)) {
+            held.insert(key);
+        }
+        let mut released = AttributeSet::new();
+        for key in self.held.iter().filter(|key| !keys.contains(*key)) {
+            released.insert(key);
+        }
+        self.held = keys;
+        if held.iter().next().is_none() && released.iter().next().is_none() {
+            return;
+        }
+        self.sender
+            .send(Input::Corrected {
+                device: Arc::clone(&self.name),
+                held,
+                released,
+            })
+            .ok();
+    }
+}
+
+/// Reads events until `terminate` is set or `stop` is closed, then releases the grab.
+///
+/// The raw device is used instead of [`Device`], because [`Device`] hides SYN_DROPPED
+/// behind synthesized events, which would trigger macros for keys that were never pressed.
+///
+/// Without `grab`, the device is only monitored: its events still reach
+/// other applications, in addition to triggering macros.
+///
+/// If the device goes away (or we're no longer allowed to read it) and we can
+/// `reopen` it, we keep trying to, with increasing delays (see `backoff.rs`)
+/// of at most `reconnect_max_delay` in `config.rs`.
+/// `set_led` actions don't reach a reopened device.
+///
+/// The thread is named after the device, for the panic messages of `panics.rs`.
+/// After a panic, it reconnects as if the device was lost, or sets `terminate`,
+/// depending on `on_device_panic` in `config.rs`.
+///
+/// See `held_keys.rs` for how keys held while grabbing are handled,
+/// and `passthrough.rs` for the `passthrough` of the `reader`.
+fn grab_inputs<D: EventSource>(
+    mut device: D,
+    mut reopen: Option<Reopen<D>>,
+    mut reader: Reader,
+) -> io::Result<JoinHandle<bool>> {
+    let name = format!("grabbing {name}", name = reader.name);
+    std::thread::Builder::new().name(name).spawn(move || loop {
+        // `read` leaves nothing behind that is used after a panic, except the
+        // grab, which we release.
+        match panic::catch_unwind(AssertUnwindSafe(|| reader.read(&mut device))) {
+            Ok(Ended::Stopped) => return false,
+            Ok(Ended::Lost) => (),
+            Ok(Ended::Denied) => {
+                reader
+                    .sender
+                    .send(Input::Denied {
+                        device: Arc::clone(&reader.name),
+                        reconnecting: reopen.is_some(),
+                    })
+                    .ok();
+            }
+            Err(_) => {
+                device.ungrab().ok();
+                let exit = reader.on_panic == DevicePanic::Exit;
+                // Before waking the board up, so it exits right away.
+                if exit {
+                    reader.terminate.store(true, Ordering::Relaxed);
+                }
+                reader
+                    .sender
+                    .send(Input::Panicked(Arc::clone(&reader.name)))
+                    .ok();
+                if exit {
+                    return true;
+                }
+            }
+        }
+        // Its keys were released along with it.
+        reader.sender.send(Input::Resync(AttributeSet::new())).ok();
+        let Some(reopen) = &mut reopen else {
+            log!("Lost {name}", name = reader.name);
+            return false;
+        };
+        loop {
+            let delay = reader.backoff.delay();
+            log!(
+                "Lost {name} - reconnecting in {delay:.1?}",
+                name = reader.name
+            );
+            if sleep_unless_stopped(&reader.stop, delay) || reader.terminate.load(Ordering::Relaxed)
+            {
+                return false;
+            }
+            match reopen() {
+                Ok(reopened) => {
+                    log!("Reconnected {name}", name = reader.name);
+                    device = reopened;
+                    break;
+                }
+                Err(err) => log!("Failed to reopen {name}: {err}", name = reader.name),
+            }
+        }
+    })
+}
+
+/// When our device doesn't come back as it was, opens the best one that is
+/// plugged in instead (and not read by another thread already).
+///
+/// Devices selected by an entry with `always` are only replaced by that entry.
+fn reopen(
+    selection: Selection,
+    in_use: Arc<Mutex<HashSet<PathBuf>>>,
+    mut path: PathBuf,
+    selected_by: usize,
+) -> Reopen<RawDevice> {
+    Box::new(move || {
+        let mut in_use = in_use.lock().unwrap();
+        in_use.remove(&path);
+        let always = selection.selectors[selected_by].always();
+        let (rank, found, _) = evdev::enumerate()
+            .filter(|(path, _)| !in_use.contains(path))
+            .filter_map(|(path, device)| Some((selection.rank(&path, &device)?, path, device)))
+            .filter(|(rank, _, _)| {
+                if always {
+                    *rank == selected_by
+                } else {
+                    !selection.selectors[*rank].always()
+                }
+            })
+            .min_by_key(|(rank, _, _)| *rank)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no configured device is plugged in",
+                )
+            })?;
+        let device = RawDevice::open(&found)?;
+        log!(
+            "Using {found}, selected by {selector}",
+            found = found.display(),
+            selector = selection.selectors[rank]
+        );
+        in_use.insert(found.clone());
+        path = found;
+        Ok(device)
+    })
+}
+
+/// Whether `device` at `path` matches all criteria of `selector`.
+fn matches(selector: &Selector, path: &Path, device: &Device) -> bool {
+    let ids = device.input_id();
+    selector.vendor.is_none_or(|vendor| vendor == ids.vendor())
+        && selector
+            .product
+            .is_none_or(|product| product == ids.product())
+        && selector
+            .bus_type
+            .is_none_or(|bus_type| bus_type == ids.bus_type())
+        && selector
+            .name
+            .as_deref()
+            .is_none_or(|name| device.name() == Some(name))
+        && selector
+            .name_regex
+            .as_ref()
+            .is_none_or(|regex| device.name().is_some_and(|name| regex.0.is_match(name)))
+        && selector.path.as_ref().is_none_or(|selected| {
+            std::fs::canonicalize(selected).is_ok_and(|selected| selected == path)
+        })
+        && matches_uniq(device, selector.uniq.as_deref())
+}
+
+/// The devices configured for a board, see `devices` in `config.rs`.
+#[derive(Clone)]
+struct Selection {
+    selectors: Arc<[Selector]>,
+    axes: Vec<AbsoluteAxisType>,
+}
+
+impl Selection {
+    fn new(board: &MacroBoard) -> Selection {
+        Selection {
+            selectors: board.selectors.clone().into(),
+            axes: board.axes.iter().map(|(axis, _)| *axis).collect(),
+        }
+    }
+
+    /// The index of the first selector that matches `device` at `path`, if any.
+    fn rank(&self, path: &Path, device: &Device) -> Option<usize> {
+        let has_axes = device
+            .supported_absolute_axes()
+            .is_some_and(|supported| self.axes.iter().any(|axis| supported.contains(*axis)));
+        let mouse = is_mouse(device);
+        if !is_keyboard(device) && !has_axes && !mouse {
+            return None;
+        }
+        self.selectors.iter().position(|selector| {
+            (!mouse || selector.mouse() || has_axes) && matches(selector, path, device)
+        })
+    }
+}
+
+/// Check that we can read and grab the configured devices, and create virtual
+/// devices if any macro needs them, for `--check`.
+///
+/// Prints what works and what doesn't (with hints on how to fix it),
+/// and returns whether everything works.
+pub fn check(board: &MacroBoard) -> bool {
+    let selection = Selection::new(board);
+    let mut ok = true;
+    let mut found = 0;
+    let mut unreadable = 0;
+    let mut paths: Vec<_> = std::fs::read_dir("/dev/input")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    paths.sort();
+    for path in paths {
+        let mut device = match Device::open(&path) {
+            Ok(device) => device,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::PermissionDenied {
+                    unreadable += 1;
+                } else {
+                    println!("Can't open {path}: {err}", path = path.display());
+                }
+                continue;
+            }
+        };
+        let Some(rank) = selection.rank(&path, &device) else {
+            continue;
+        };
+        found += 1;
+        let name = device.name().unwrap_or("unnamed device").to_string();
+        println!(
+            "Found {name} at {path}, selected by {selector}",
+            path = path.display(),
+            selector = selection.selectors[rank]
+        );
+        if !grabs(&selection.selectors[rank], &device) {
+            if is_mouse(&device) {
+                println!("  It's a mouse, so it's read without grabbing it");
+            } else {
+                println!("  It's only monitored (grab = false), not grabbed");
+            }
+            continue;
+        }
+        match device.grab().and_then(|()| device.ungrab()) {
+            Ok(()) => println!("  It can be grabbed"),
+            Err(err) => {
+                ok = false;
+                println!("  It can't be grabbed: {err}");
+                if err.raw_os_error() == Some(libc::EBUSY) {
+                    println!("  Another program (maybe evdev-macros itself) has grabbed it");
+                }
+            }
+        }
+    }
+    if found == 0 {
+        ok = false;
+        for selector in selection.selectors.iter() {
+            println!("No device matches {selector}");
+        }
+        if unreadable == 0 {
+            println!("  Pick one with --interactive");
+        }
+    }
+    if unreadable > 0 {
+        println!(
+            "{unreadable} input device(s) can't be read{maybe}. \
+             evdev-macros has to run as root (installed setuid root), \
+             or as a member of the `input` group",
+            maybe = if found == 0 {
+                ", it may be one of them"
+            } else {
+                ""
+            }
+        );
+    }
+
+    let sends_keys = board.macro_configs.values().any(|config| {
+        actions::keys_used(config.all_actions())
+            .iter()
+            .next()
+            .is_some()
+    });
+    let forwards = !board.forward_keys.is_empty() || !board.forward_types.is_empty();
+    if sends_keys || forwards {
+        if uinput::available() {
+            println!("Virtual devices for send_keys and forwarding can be created");
+        } else {
+            // `uinput::available` has already explained why.
+            println!("Virtual devices for send_keys and forwarding can't be created");
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// What grabbing threads are started with, see [`Readers::reader`].
+struct Readers {
+    sender: Sender<Input>,
+    terminate: Arc<AtomicBool>,
+    /// The read end of [`Grabbers::stop`].
+    stopped: OwnedFd,
+    passthrough: Option<Passthrough>,
+    reconnect_max_delay: Duration,
+    regrab_on_resume: bool,
+    flush_on_start: bool,
+    on_panic: DevicePanic,
+    resync_interval: Option<Duration>,
+    key_values: KeyValues,
+}
+
+impl Readers {
+    fn reader(&self, name: Arc<str>, grab: bool, layer: Option<Arc<str>>) -> io::Result<Reader> {
+        Ok(Reader {
+            name,
+            layer,
+            sender: self.sender.clone(),
+            terminate: Arc::clone(&self.terminate),
+            stop: self.stopped.try_clone()?,
+            grab,
+            // Without a grab, forwarding would duplicate the keys.
+            passthrough: self.passthrough.clone().filter(|_| grab),
+            backoff: Backoff::new(self.reconnect_max_delay),
+            resume: self.regrab_on_resume.then(Resume::new),
+            flush: self.flush_on_start,
+            on_panic: self.on_panic,
+            resync_interval: self.resync_interval,
+            held: AttributeSet::new(),
+            key_values: self.key_values.clone(),
+            dropped: false,
+            report: Vec::new(),
+        })
+    }
+}
+
+/// What the devices we read so far have in common, also for those that
+/// appear later, see [`wait_for_pending`].
+struct Opened {
+    selection: Selection,
+    in_use: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Second handles to the grabbed devices, for `set_led` actions.
+    leds: Arc<Mutex<Vec<OwnedFd>>>,
+    /// Whether to grab them at all, see [`grab_devices`].
+    grab: bool,
+}
+
+impl Opened {
+    /// Start reading `device` at `path`, which the selector `rank` selected.
+    /// Returns `None` if it can't be opened.
+    fn start(
+        &self,
+        readers: &Readers,
+        rank: usize,
+        path: PathBuf,
+        device: &Device,
+        name: Arc<str>,
+    ) -> io::Result<Option<JoinHandle<bool>>> {
+        let raw = match RawDevice::open(&path) {
+            Ok(raw) => raw,
+            Err(err) => {
+                log!("Failed to open {path}: {err}", path = path.display());
+                return Ok(None);
+            }
+        };
+        self.leds
+            .lock()
+            .unwrap()
+            .extend(source::duplicate(&raw).ok());
+        self.in_use.lock().unwrap().insert(path.clone());
+        let selector = &self.selection.selectors[rank];
+        let reopen = reopen(self.selection.clone(), Arc::clone(&self.in_use), path, rank);
+        // A reconnected device is grabbed (or not) like this one, and
+        // keeps its layer, even if it was selected by another entry.
+        let grabbed = self.grab && grabs(selector, device);
+        if self.grab && !grabbed {
+            log!("Not grabbing {name}: its keys also reach other applications");
+        }
+        let layer = selector.layer.as_deref().map(Arc::from);
+        let reader = readers.reader(name, grabbed, layer)?;
+        grab_inputs(raw, Some(reopen), reader).map(Some)
+    }
+}
+
+/// Start reading the devices passed by systemd or, if there are none, the ones
+/// matching the configured ids. Set `terminate` or call [`Grabbers::join`] to stop
+/// the returned threads.
+///
+/// Entries of `devices` that match nothing yet are pending: their devices are
+/// read once they're plugged in, see [`wait_for_pending`].
+///
+/// Without `grab`, the devices are only monitored: their events also reach other applications.
+pub fn grab_devices(
+    board: &mut MacroBoard,
+    passed: Vec<FdDevice>,
+    sender: Sender<Input>,
+    terminate: &Arc<AtomicBool>,
+    grab: bool,
+) -> io::Result<Grabbers> {
+    let mut grabbers = Grabbers::new()?;
+    let selection = Selection::new(board);
+    // Without a grab, the keys reach everyone anyway.
+    let forwards = !board.forward_keys.is_empty() || !board.forward_types.is_empty();
+    let passthrough = if grab && forwards && uinput::available() {
+        Passthrough::new(&board.forward_keys, &board.forward_types)
+            .map_err(|err| log!("Failed to create passthrough device: {err}"))
+            .ok()
+    } else {
+        None
+    };
+    let readers = Readers {
+        sender,
+        terminate: Arc::clone(terminate),
+        stopped: grabbers.stopped.try_clone()?,
+        passthrough,
+        reconnect_max_delay: board.reconnect_max_delay,
+        regrab_on_resume: board.regrab_on_resume,
+        flush_on_start: board.flush_on_start,
+        on_panic: board.on_device_panic,
+        resync_interval: board.resync_interval,
+        key_values: board.key_values.clone(),
+    };
+    let opened = Opened {
+        selection,
+        in_use: Arc::new(Mutex::new(HashSet::new())),
+        leds: Arc::default(),
+        grab,
+    };
+    let selection = &opened.selection;
+    for device in passed {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(err) => {
+                log!("Ignoring file descriptor passed by systemd: {err}");
+                continue;
+            }
+        };
+        log!("Using device passed by systemd: {name}");
+        let name: Arc<str> = name.into();
+        grabbers.passed = true;
+        grabbers.names.push(Arc::clone(&name));
+        if device
+            .get_key_state()
+            .is_ok_and(|keys| keys.contains(SAFE_MODE_KEY))
+        {
+            board.safe_mode = true;
+        }
+        opened
+            .leds
+            .lock()
+            .unwrap()
+            .extend(source::duplicate(&device).ok());
+        // We can't open these ourselves.
+        grabbers.threads.push(grab_inputs(
+            device,
+            None,
+            readers.reader(name, grab, None)?,
+        )?);
+    }
+    // Devices passed by systemd replace our own device selection.
+    let mut enumerated = Vec::new();
+    if grabbers.is_empty() {
+        enumerated
+            .extend(evdev::enumerate().filter_map(|(path, device)| {
+                Some((selection.rank(&path, &device)?, path, device))
+            }));
+    }
+    // Only the devices of the first selector that matches any, and those of
+    // the `always` selectors.
+    let best = enumerated
+        .iter()
+        .map(|(rank, _, _)| *rank)
+        .filter(|rank| !selection.selectors[*rank].always())
+        .min();
+    if let Some(best) = best {
+        log!(
+            "Selected devices by {selector}{priority}",
+            selector = selection.selectors[best],
+            priority = if selection.selectors.len() > 1 {
+                format!(
+                    " (entry {} of {} in devices)",
+                    best + 1,
+                    selection.selectors.len()
+                )
+            } else {
+                String::new()
+            }
+        );
+    }
+    let pending: Vec<usize> = if grabbers.passed {
+        Vec::new()
+    } else {
+        (0..selection.selectors.len())
+            .filter(|rank| {
+                if selection.selectors[*rank].always() {
+                    !enumerated.iter().any(|(found, _, _)| found == rank)
+                } else {
+                    best.is_none()
+                }
+            })
+            .collect()
+    };
+    enumerated.retain(|(rank, _, _)| Some(*rank) == best || selection.selectors[*rank].always());
+    let names = device_names(&enumerated);
+    for ((rank, path, device), name) in enumerated.into_iter().zip(names) {
+        if selection.selectors[rank].always() {
+            log!(
+                "Also selected by {selector}:",
+                selector = selection.selectors[rank]
+            );
+        }
+        log!("Found device:\n{device}");
+        if device
+            .get_key_state()
+            .is_ok_and(|keys| keys.contains(SAFE_MODE_KEY))
+        {
+            board.safe_mode = true;
+        }
+        if let Some(thread) = opened.start(&readers, rank, path, &device, Arc::clone(&name))? {
+            grabbers.names.push(name);
+            grabbers.threads.push(thread);
+        }
+    }
+    board.action_context.leds = Arc::clone(&opened.leds);
+    if !pending.is_empty() {
+        grabbers
+            .threads
+            .push(wait_for_pending(readers, opened, pending)?);
+    }
+    board.show_toggles();
+    if board.safe_mode {
+        log!("*** SAFE MODE: all macros are disabled, press ESC to quit ***");
+    }
+    Ok(grabbers)
+}
+
+/// How often we look for pending devices if we can't watch `/dev/input`.
+const PENDING_POLL: Duration = Duration::from_secs(2);
+
+/// Start reading the devices of the `pending` entries of `devices` (by index)
+/// as they're plugged in, on a thread that also waits for the threads it starts.
+///
+/// The entries that take part in the priority order are pending together, if
+/// none of them matched anything: the first one to match is used, as if its
+/// device had been plugged in at the start.
+fn wait_for_pending(
+    readers: Readers,
+    opened: Opened,
+    mut pending: Vec<usize>,
+) -> io::Result<JoinHandle<bool>> {
+    for rank in &pending {
+        log!(
+            "Waiting for a device matching {selector} to be plugged in",
+            selector = opened.selection.selectors[*rank]
+        );
+    }
+    let stop = readers.stopped.try_clone()?;
+    let watch = watch_devices()
+        .map_err(|err| {
+            log!("Can't watch /dev/input ({err}), looking for devices every {PENDING_POLL:?}")
+        })
+        .ok();
+    std::thread::Builder::new()
+        .name("waiting for devices".to_string())
+        .spawn(move || {
+            let mut threads = Vec::new();
+            while !pending.is_empty() && !readers.terminate.load(Ordering::Relaxed) {
+                let changed = match &watch {
+                    Some(watch) => match wait_readable(watch, &stop, PENDING_POLL) {
+                        Ok(Wait::Stopped) => break,
+                        Ok(Wait::Readable) => {
+                            drain(watch);
+                            true
+                        }
+                        Ok(Wait::TimedOut) | Err(_) => false,
+                    },
+                    None if sleep_unless_stopped(&stop, PENDING_POLL) => break,
+                    None => true,
+                };
+                // udev only makes new devices readable right after they appear.
+                if !changed || sleep_unless_stopped(&stop, Duration::from_millis(100)) {
+                    continue;
+                }
+                threads.extend(start_pending(&readers, &opened, &mut pending));
+            }
+            // Stopped along with the others, by the pipe or `terminate`.
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap_or(true))
+                .fold(false, |failed, thread_failed| failed | thread_failed)
+        })
+}
+
+/// Start reading the devices that match `pending` entries now, and remove those entries.
+fn start_pending(
+    readers: &Readers,
+    opened: &Opened,
+    pending: &mut Vec<usize>,
+) -> Vec<JoinHandle<bool>> {
+    let selection = &opened.selection;
+    let found: Vec<_> = {
+        let in_use = opened.in_use.lock().unwrap();
+        evdev::enumerate()
+            .filter(|(path, _)| !in_use.contains(path))
+            .filter_map(|(path, device)| Some((selection.rank(&path, &device)?, path, device)))
+            .filter(|(rank, _, _)| pending.contains(rank))
+            .collect()
+    };
+    let best = found
+        .iter()
+        .map(|(rank, _, _)| *rank)
+        .filter(|rank| !selection.selectors[*rank].always())
+        .min();
+    let mut threads = Vec::new();
+    let mut started = HashSet::new();
+    for (rank, path, device) in found {
+        if !selection.selectors[rank].always() && Some(rank) != best {
+            continue;
+        }
+        let name: Arc<str> = device.name().unwrap_or("unnamed device").into();
+        log!(
+            "{name} was plugged in, selected by {selector}",
+            selector = selection.selectors[rank]
+        );
+        match opened.start(readers, rank, path, &device, name) {
+            Ok(Some(thread)) => {
+                threads.push(thread);
+                started.insert(rank);
+            }
+            Ok(None) => (),
+            Err(err) => log!("Failed to start reading it: {err}"),
+        }
+    }
+    pending.retain(|rank| {
+        let priority_found = best.is_some() && !selection.selectors[*rank].always();
+        !started.contains(rank) && !priority_found
+    });
+    threads
+}
+
+/// An inotify watch for devices appearing in `/dev/input` (or their permissions
+/// changing, which udev does right after).
+fn watch_devices() -> io::Result<OwnedFd> {
+    // SAFETY: inotify_init1 only takes flags.
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: inotify_init1 just opened it, and nothing else owns it.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: the path is a valid, nul-terminated string.
+    let watch = unsafe {
+        libc::inotify_add_watch(
+            fd.as_raw_fd(),
+            c"/dev/input".as_ptr(),
+            libc::IN_CREATE | libc::IN_ATTRIB,
+        )
+    };
+    if watch < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Read the pending events of the inotify `watch`, we only care that there were any.
+fn drain(watch: &OwnedFd) {
+    let mut buffer = [0u8; 4096];
+    // SAFETY: `buffer` is valid for writes of its length. The watch doesn't
+    // block, so this stops at the first read without events.
+    while unsafe { libc::read(watch.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+}
+
+/// The names the board knows `devices` by: the names they report or, for
+/// identical devices (e.g. two keyboards of the same model), with `#1`, `#2`, …
+/// appended in the order of their paths, so their events can be told apart.
+fn device_names(devices: &[(usize, PathBuf, Device)]) -> Vec<Arc<str>> {
+    let reported: Vec<&str> = devices
+        .iter()
+        .map(|(_, _, device)| device.name().unwrap_or("unnamed device"))
+        .collect();
+    reported
+        .iter()
+        .zip(devices)
+        .map(|(name, (_, path, _))| {
+            let mut twins: Vec<_> = reported
+                .iter()
+                .zip(devices)
+                .filter(|(other, _)| other == &name)
+                .map(|(_, (_, path, _))| event_number(path))
+                .collect();
+            if twins.len() == 1 {
+                return (*name).into();
+            }
+            twins.sort();
+            let index = twins
+                .iter()
+                .position(|number| *number == event_number(path));
+            let alias = format!("{name} #{}", index.unwrap_or_default() + 1);
+            if index == Some(0) {
+                log!(
+                    "Warning: {count} identical devices named {name} are selected, \
+                     all of them trigger macros. Telling them apart as {name} #1 to #{count}",
+                    count = twins.len()
+                );
+            }
+            alias.into()
+        })
+        .collect()
+}
+
+/// `n` of `/dev/input/eventn`, to sort devices like the kernel numbered them.
+fn event_number(path: &Path) -> (usize, PathBuf) {
+    let number = path
+        .file_name()
+        .and_then(|name| name.to_str()?.strip_prefix("event")?.parse().ok());
+    (number.unwrap_or(usize::MAX), path.to_path_buf())
+}
+
+/// Stop reading the devices of `grabbers`, and select and grab them again like
+/// [`grab_devices`], e.g. after swapping keyboards. The board is left as it is,
+/// so running macros carry on.
+///
+/// Devices passed by systemd can't be opened again, so they're kept.
+pub fn regrab(
+    board: &mut MacroBoard,
+    grabbers: Grabbers,
+    sender: Sender<Input>,
+    terminate: &Arc<AtomicBool>,
+    grab: bool,
+) -> io::Result<Grabbers> {
+    if grabbers.passed {
+        log!("Not regrabbing: systemd passed the devices, we can't open them again");
+        return Ok(grabbers);
+    }
+    log!("Releasing {}", device_list(&grabbers.names));
+    if !grabbers.join() {
+        return Err(io::Error::other("reading a device panicked"));
+    }
+    // Their keys were released along with them.
+    sender.send(Input::Resync(AttributeSet::new())).ok();
+    let grabbers = grab_devices(board, Vec::new(), sender, terminate, grab)?;
+    log!("Now reading {}", device_list(&grabbers.names));
+    Ok(grabbers)
+}
+
+fn device_list(names: &[Arc<str>]) -> String {
+    if names.is_empty() {
+        return "no devices".to_string();
+    }
+    names
+        .iter()
+        .map(|name| name.as_ref())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::Receiver;
+    use evdev::EventType;
+    use std::collections::VecDeque;
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 returns.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: pipe2 just opened these, and nothing else owns them.
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    enum Step {
+        Events(Vec<InputEvent>),
+        /// The keys that are held from now on, as the kernel tells them.
+        Keys(AttributeSet<Key>),
+    }
+
+    /// Always readable, and returns the events of its steps, then nothing.
+    struct FakeDevice {
+        readable: OwnedFd,
+        _write: OwnedFd,
+        steps: VecDeque<Step>,
+        keys: AttributeSet<Key>,
+        /// Stops the reader once the steps are done, else it keeps reading nothing.
+        done: Option<Arc<AtomicBool>>,
+    }
+
+    impl FakeDevice {
+        fn new(steps: Vec<Step>, done: Option<Arc<AtomicBool>>) -> FakeDevice {
+            let (readable, write) = pipe();
+            // SAFETY: we write one byte from a valid buffer.
+            assert_eq!(
+                unsafe { libc::write(write.as_raw_fd(), [0u8].as_ptr().cast(), 1) },
+                1
+            );
+            FakeDevice {
+                readable,
+                _write: write,
+                steps: steps.into(),
+                keys: AttributeSet::new(),
+                done,
+            }
+        }
+    }
+
+    impl AsRawFd for FakeDevice {
+        fn as_raw_fd(&self) -> std::os::fd::RawFd {
+            self.readable.as_raw_fd()
+        }
+    }
+
+    impl EventSource for FakeDevice {
+        fn grab(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn ungrab(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+            while let Some(step) = self.steps.pop_front() {
+                match step {
+                    Step::Keys(keys) => self.keys = keys,
+                    Step::Events(events) => return Ok(events),
+                }
+            }
+            if let Some(done) = &self.done {
+                done.store(true, Ordering::Relaxed);
+            }
+            Ok(Vec::new())
+        }
+
+        fn get_key_state(&self) -> io::Result<AttributeSet<Key>> {
+            Ok(self.keys.clone())
+        }
+    }
+
+    /// A reader that isn't stopped until `terminate`, what it sends, and the
+    /// write end of its stop pipe.
+    fn reader(terminate: Arc<AtomicBool>) -> (Reader, Receiver<Input>, OwnedFd) {
+        let (sender, inputs) = crossbeam_channel::unbounded();
+        let (stopped, stop) = pipe();
+        let reader = Reader {
+            name: Arc::from("test"),
+            layer: None,
+            sender,
+            terminate,
+            stop: stopped,
+            grab: false,
+            passthrough: None,
+            backoff: Backoff::new(Duration::from_secs(1)),
+            resume: None,
+            flush: false,
+            on_panic: DevicePanic::Reconnect,
+            resync_interval: None,
+            held: AttributeSet::new(),
+            key_values: KeyValues::default(),
+            dropped: false,
+            report: Vec::new(),
+        };
+        (reader, inputs, stop)
+    }
+
+    fn key(key: Key, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY, key.code(), value)
+    }
+
+    fn syn(kind: Synchronization) -> InputEvent {
+        InputEvent::new(EventType::SYNCHRONIZATION, kind.0, 0)
+    }
+
+    #[test]
+    fn held_keys_are_reconciled_after_syn_dropped() {
+        let terminate = Arc::new(AtomicBool::new(false));
+        let (mut reader, inputs, _stop) = reader(Arc::clone(&terminate));
+        let mut device = FakeDevice::new(
+            vec![
+                Step::Events(vec![key(Key::KEY_A, 1), syn(Synchronization::SYN_REPORT)]),
+                Step::Events(vec![syn(Synchronization::SYN_DROPPED)]),
+                // A was released and B pressed while the kernel was dropping events.
+                Step::Keys([Key::KEY_B].into_iter().collect()),
+                Step::Events(vec![key(Key::KEY_B, 1), syn(Synchronization::SYN_REPORT)]),
+            ],
+            Some(terminate),
+        );
+
+        assert!(matches!(reader.read(&mut device), Ended::Stopped));
+
+        let inputs: Vec<_> = inputs.try_iter().collect();
+        assert_eq!(inputs.len(), 2);
+        assert!(matches!(
+            &inputs[0],
+            Input::Report { events, .. } if events.len() == 2
+        ));
+        assert!(matches!(
+            &inputs[1],
+            Input::Resync(keys) if keys.iter().eq([Key::KEY_B])
+        ));
+        assert!(reader.held.iter().eq([Key::KEY_B]));
+        assert!(!reader.dropped);
+    }
+
+    #[test]
+    fn held_keys_follow_the_key_values() {
+        let terminate = Arc::new(AtomicBool::new(false));
+        let (mut reader, _inputs, _stop) = reader(Arc::clone(&terminate));
+        reader.key_values = KeyValues {
+            press: vec![0],
+            release: vec![1],
+        };
+        let mut device = FakeDevice::new(
+            vec![Step::Events(vec![
+                key(Key::KEY_A, 0),
+                key(Key::KEY_B, 0),
+                key(Key::KEY_B, 1),
+                syn(Synchronization::SYN_REPORT),
+            ])],
+            Some(terminate),
+        );
+
+        assert!(matches!(reader.read(&mut device), Ended::Stopped));
+        assert!(reader.held.iter().eq([Key::KEY_A]));
+    }
+
+    #[test]
+    fn device_that_only_returns_empty_batches_stalls() {
+        let (mut reader, inputs, _stop) = reader(Arc::new(AtomicBool::new(false)));
+        let mut device = FakeDevice::new(Vec::new(), None);
+
+        let started = Instant::now();
+        assert!(matches!(reader.read(&mut device), Ended::Lost));
+
+        // Backed off instead of spinning through the reads.
+        let delayed = EMPTY_READS_BEFORE_STALLED - EMPTY_READS_BEFORE_DELAY;
+        assert!(started.elapsed() >= EMPTY_READ_DELAY * delayed);
+        assert!(inputs.try_recv().is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Keys that are held down while we grab a device.
+//!
+//! The rest of the system saw those keys go down, but never sees them come up
+//! again, because the release goes to us. That's how you end up with a stuck Shift.
+//! So we give the user a moment to let go before grabbing, and if they don't,
+//! release the keys on a virtual device when we ungrab.
+
+use crate::{log, source::EventSource, uinput};
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AttributeSet, EventType, InputEvent, Key,
+};
+use std::time::{Duration, Instant};
+
+/// How long we wait for keys to be released before grabbing anyway.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Wait for all keys of `device` to be released (for at most [`RELEASE_TIMEOUT`]),
+/// then grab it. Returns the keys that are still held.
+pub fn grab(device: &mut impl EventSource) -> std::io::Result<AttributeSet<Key>> {
+    let deadline = Instant::now() + RELEASE_TIMEOUT;
+    let mut held = device.get_key_state()?;
+    if held.iter().next().is_some() {
+        log!(
+            "Waiting for {keys} to be released before grabbing",
+            keys = names(&held)
+        );
+    }
+    while held.iter().next().is_some() && Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL);
+        held = device.get_key_state()?;
+    }
+    device.grab()?;
+    // Keys can go down between reading the state and grabbing.
+    let held = device.get_key_state()?;
+    if held.iter().next().is_some() {
+        if !uinput::available() {
+            log!(
+                "Grabbed while {keys} held, they may get stuck",
+                keys = names(&held)
+            );
+            return Ok(AttributeSet::new());
+        }
+        log!(
+            "Grabbed while {keys} held, releasing them on ungrab",
+            keys = names(&held)
+        );
+    }
+    Ok(held)
+}
+
+/// Ungrab `device` and send releases for the keys in `held` on a virtual keyboard.
+pub fn ungrab(device: &mut impl EventSource, held: &AttributeSet<Key>) -> std::io::Result<()> {
+    device.ungrab()?;
+    if held.iter().next().is_none() {
+        return Ok(());
+    }
+    let mut keyboard = release_device(held)?;
+    let releases: Vec<InputEvent> = held
+        .iter()
+        .map(|key| InputEvent::new(EventType::KEY, key.code(), 0))
+        .collect();
+    keyboard.emit(&releases)
+}
+
+fn release_device(keys: &AttributeSet<Key>) -> std::io::Result<VirtualDevice> {
+    VirtualDeviceBuilder::new()?
+        .name("evdev-macros key release")
+        .with_keys(keys)?
+        .build()
+}
+
+fn names(keys: &AttributeSet<Key>) -> String {
+    keys.iter()
+        .map(|key| format!("{key:?}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
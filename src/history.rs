@@ -0,0 +1,134 @@
+//! A bounded log of the most recent key events, for debugging misfiring macros.
+//!
+//! Available through SIGUSR2 (dumped to stderr) and the D-Bus `History` method.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// What the daemon did in response to an event.
+pub enum Outcome {
+    /// Key presses and repeats don't trigger macros.
+    Ignored,
+    Paused,
+    Disarmed,
+    /// Started with `--safe-mode`, or with ESC held.
+    SafeMode,
+    /// The arm key was pressed, macros are now armed (or not).
+    Armed(bool),
+    /// The profile key was pressed, this profile is now active.
+    Profile(Option<String>),
+    /// Excluded by `allowed_keys`/`denied_keys`.
+    Denied,
+    NoMacro,
+    /// Outside of the macro's `schedule`, and it has no `otherwise` macro.
+    Unscheduled,
+    /// Released after its chord already ran, see `chord.rs`.
+    Chorded,
+    /// Older than `max_event_age`.
+    Stale,
+    /// The macro has `single_instance` and an earlier run is still going.
+    StillRunning,
+    /// Over the `rate_limit`.
+    RateLimited,
+    /// ESC was held to stop the daemon, see `esc_passthrough`.
+    Quit,
+    /// ESC was tapped and typed on the virtual keyboard, see `esc_passthrough`.
+    Typed,
+    /// Waiting for more triggers, with this many so far. See `coalesce` in `config.rs`.
+    Coalesced(usize),
+    /// The macro ran, with this many scripts (an `actions` sequence counts as one).
+    Ran(usize),
+    Failed(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Ignored => write!(f, "ignored"),
+            Outcome::Paused => write!(f, "paused"),
+            Outcome::Disarmed => write!(f, "disarmed"),
+            Outcome::SafeMode => write!(f, "safe mode"),
+            Outcome::Armed(true) => write!(f, "armed macros"),
+            Outcome::Armed(false) => write!(f, "disarmed macros"),
+            Outcome::Profile(Some(profile)) => write!(f, "switched to profile {profile}"),
+            Outcome::Profile(None) => write!(f, "switched to shared macros"),
+            Outcome::Denied => write!(f, "denied"),
+            Outcome::NoMacro => write!(f, "no macro"),
+            Outcome::Unscheduled => write!(f, "outside of its schedule"),
+            Outcome::Chorded => write!(f, "part of a chord"),
+            Outcome::Stale => write!(f, "too old"),
+            Outcome::StillRunning => write!(f, "still running"),
+            Outcome::RateLimited => write!(f, "rate limited"),
+            Outcome::Quit => write!(f, "quit"),
+            Outcome::Typed => write!(f, "typed"),
+            Outcome::Coalesced(count) => write!(f, "coalesced ({count} so far)"),
+            Outcome::Ran(scripts) => write!(f, "ran {scripts} script(s)"),
+            Outcome::Failed(err) => write!(f, "failed: {err}"),
+        }
+    }
+}
+
+pub struct Entry {
+    pub timestamp: SystemTime,
+    /// The name of the device it came from, see `device.rs`.
+    pub device: String,
+    /// The macro name it resolved to, e.g. `KEY_F1`, `pad/KEY_KP1` or a chord.
+    pub key: String,
+    pub value: i32,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{secs}.{micros:06} {key} - {value}: {outcome}",
+            secs = since_epoch.as_secs(),
+            micros = since_epoch.subsec_micros(),
+            key = self.key,
+            value = self.value,
+            outcome = self.outcome,
+        )
+    }
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Remove the entries, oldest first.
+    pub fn take(&mut self) -> Vec<Entry> {
+        self.entries.drain(..).collect()
+    }
+
+    /// The entries formatted one per line, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.entries.iter().map(Entry::to_string).collect()
+    }
+}
@@ -0,0 +1,187 @@
+//! The `http` action, e.g. for the webhooks of home automation:
+//!
+//! ```toml
+//! [macros.KEY_F7]
+//! actions = [{ http = { method = "POST", url = "http://homeassistant.local:8123/api/webhook/lights", headers = { Content-Type = "application/json" }, body = '{"on":true}' } }]
+//! ```
+//!
+//! Only plain `http://` URLs work: this is a minimal HTTP/1.1 client, without
+//! TLS, redirects or keep-alive. For anything more, run `curl` with a `command`.
+
+use crate::config;
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+    #[serde(default = "default_method", deserialize_with = "method")]
+    pub method: String,
+    #[serde(deserialize_with = "url")]
+    pub url: String,
+    #[serde(default, deserialize_with = "headers")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Seconds to wait for connecting, and then for each read or write.
+    #[serde(
+        default = "default_timeout",
+        deserialize_with = "config::seconds",
+        serialize_with = "config::serialize_seconds"
+    )]
+    pub timeout: Duration,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn method<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let method = String::deserialize(deserializer)?;
+    if method.is_empty() || !method.bytes().all(|byte| byte.is_ascii_uppercase()) {
+        return Err(D::Error::custom(format!(
+            "invalid method `{method}`, expected e.g. GET or POST"
+        )));
+    }
+    Ok(method)
+}
+
+fn url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let url = String::deserialize(deserializer)?;
+    Target::parse(&url).map_err(D::Error::custom)?;
+    Ok(url)
+}
+
+fn headers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    let headers = BTreeMap::<String, String>::deserialize(deserializer)?;
+    for (name, value) in &headers {
+        if name.is_empty() || name.contains([':', ' ', '\r', '\n']) || value.contains(['\r', '\n'])
+        {
+            return Err(D::Error::custom(format!("invalid header `{name}`")));
+        }
+    }
+    Ok(headers)
+}
+
+/// Where a URL points to.
+struct Target<'a> {
+    /// With the port, if there is one, as it goes in the `Host` header.
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+impl<'a> Target<'a> {
+    fn parse(url: &'a str) -> Result<Target<'a>, String> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            format!("`{url}` isn't an http:// URL (https isn't supported, use curl in a command)")
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // Not the colons of an IPv6 address.
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("`{url}` has an invalid port"))?,
+            ),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("`{url}` has no host"));
+        }
+        if path.bytes().any(|byte| byte.is_ascii_whitespace()) {
+            return Err(format!("`{url}` contains whitespace"));
+        }
+        Ok(Target {
+            authority,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+impl Request {
+    /// Send the request and return the status code of the response, if it's a success.
+    pub fn send(&self) -> Result<u16, String> {
+        let target = Target::parse(&self.url)?;
+        let status = self.exchange(&target).map_err(|err| {
+            format!(
+                "{method} {url} failed: {err}",
+                method = self.method,
+                url = self.url
+            )
+        })?;
+        if (200..300).contains(&status) {
+            Ok(status)
+        } else {
+            Err(format!(
+                "{method} {url} failed with status {status}",
+                method = self.method,
+                url = self.url
+            ))
+        }
+    }
+
+    fn exchange(&self, target: &Target) -> io::Result<u16> {
+        let mut stream = self.connect(target)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n",
+            method = self.method,
+            path = target.path,
+            authority = target.authority,
+        );
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{name}: {value}\r\n"));
+        }
+        let body = self.body.as_deref().unwrap_or_default();
+        if self.body.is_some() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes())?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        // E.g. `HTTP/1.1 200 OK`.
+        status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .filter(|_| status_line.starts_with("HTTP/"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"))
+    }
+
+    /// Connect to the first address of the host that works.
+    fn connect(&self, target: &Target) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for address in (target.host, target.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "the host has no addresses")
+        }))
+    }
+}
@@ -0,0 +1,74 @@
+//! Interactive device picker for `--interactive`.
+
+use evdev::{Device, InputEventKind};
+use evdev_macros::{config::Config, device, privileges::AsUser};
+use std::io::{self, BufRead, Write};
+
+fn prompt_index(count: usize) -> Result<usize, Box<dyn std::error::Error>> {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("Select a keyboard [1-{count}]: ");
+        io::stdout().flush()?;
+
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err("Aborted".into());
+        }
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=count).contains(&number) => return Ok(number - 1),
+            _ => println!("Please enter a number between 1 and {count}"),
+        }
+    }
+}
+
+fn wait_for_key(device: &mut Device) -> io::Result<()> {
+    loop {
+        for event in device.fetch_events()? {
+            if event.value() == 1 {
+                if let InputEventKind::Key(key) = event.kind() {
+                    println!("Received {key:?}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// List the available keyboards, let the user pick one and store it in the config file.
+pub fn pick_device() -> Result<(), Box<dyn std::error::Error>> {
+    let mut devices: Vec<Device> = evdev::enumerate()
+        .map(|(_path, device)| device)
+        .filter(device::is_keyboard)
+        .collect();
+    if devices.is_empty() {
+        return Err("No keyboards found - are you allowed to read /dev/input?".into());
+    }
+
+    for (number, device) in devices.iter().enumerate() {
+        let ids = device.input_id();
+        println!(
+            "{number}) {name} (bus_type: {bus:?}, vendor: {vendor:#06x}, product: {product:#06x})",
+            number = number + 1,
+            name = device.name().unwrap_or("Unnamed device"),
+            bus = ids.bus_type(),
+            vendor = ids.vendor(),
+            product = ids.product(),
+        );
+    }
+    let mut device = devices.swap_remove(prompt_index(devices.len())?);
+
+    println!(
+        "Press any key on {name} to confirm...",
+        name = device.name().unwrap_or("the keyboard")
+    );
+    wait_for_key(&mut device)?;
+
+    let ids = device.input_id();
+    let as_user = AsUser::switch()?;
+    let path = Config::save_device(ids.vendor(), ids.product())?;
+    drop(as_user);
+
+    println!("Saved selection to {path}", path = path.display());
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! How long things take, for telling a slow daemon from a slow macro.
+//!
+//! Two distributions are kept: how old input events are when we process them
+//! (time spent in the kernel, the grabbing threads and our queue), and how long
+//! it takes from deciding to run a script to its process being spawned.
+//! Available through SIGUSR2 (dumped to stderr) and the D-Bus `Latency` method.
+//!
+//! Recording is an increment in a fixed array of power of two buckets, so the
+//! percentiles are upper bounds, within a factor of two.
+
+use std::time::Duration;
+
+/// Bucket `i` counts latencies below 2^i microseconds, the last one everything else.
+const BUCKETS: usize = 32;
+
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// At most this long, for the `quantile` (between 0 and 1) of what was recorded.
+    fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(1 << bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// E.g. `events: 1234, p50 ≤ 128µs, p90 ≤ 512µs, p99 ≤ 2ms, max 3.1ms`.
+    pub fn line(&self, name: &str) -> String {
+        if self.count == 0 {
+            return format!("{name}: none yet");
+        }
+        format!(
+            "{name}: {count}, p50 ≤ {p50:.1?}, p90 ≤ {p90:.1?}, p99 ≤ {p99:.1?}, max {max:.1?}",
+            count = self.count,
+            p50 = self.percentile(0.5),
+            p90 = self.percentile(0.9),
+            p99 = self.percentile(0.99),
+            max = self.max,
+        )
+    }
+}
+
+pub struct Latency {
+    /// From the timestamp of an input event to us processing it.
+    pub events: Histogram,
+    /// From running a macro to its script's process being spawned.
+    pub spawns: Histogram,
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        Latency {
+            events: Histogram::new(),
+            spawns: Histogram::new(),
+        }
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        vec![
+            self.events.line("Event delivery"),
+            self.spawns.line("Script spawn"),
+        ]
+    }
+}
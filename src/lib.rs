@@ -0,0 +1,51 @@
+//! The engine behind the `evdev-macros` daemon: grabbing input devices and
+//! running scripts (or configured actions) when their keys are released.
+//!
+//! A minimal daemon:
+//!
+//! ```no_run
+//! use evdev_macros::{device, systemd, Config, MacroBoard, Signals};
+//!
+//! let config = Config::load().unwrap();
+//! let (mut board, sender) = MacroBoard::builder(config).build().unwrap();
+//! let signals = Signals::default();
+//! let grabbers =
+//!     device::grab_devices(&mut board, systemd::listen_fds(), sender, &signals.terminate, true)
+//!         .unwrap();
+//! board.run(&signals);
+//! grabbers.join();
+//! ```
+
+pub mod actions;
+mod backoff;
+mod board;
+mod chord;
+pub mod clock;
+pub mod config;
+mod dbus;
+mod dbus_call;
+pub mod device;
+mod held_keys;
+pub mod history;
+mod http;
+mod latency;
+pub mod log;
+pub mod log_format;
+mod mirror;
+pub mod panics;
+mod passthrough;
+pub mod privileges;
+mod process;
+mod profile;
+mod rate_limit;
+pub mod replay;
+mod resume;
+pub mod schedule;
+mod session;
+pub mod source;
+mod state;
+pub mod systemd;
+mod uinput;
+
+pub use board::{Command, MacroBoard, MacroBoardBuilder, Signals, Stop, Waker};
+pub use config::Config;
@@ -0,0 +1,231 @@
+//! The lines the daemon logs to stderr, see [`log!`](crate::log!).
+//!
+//! With `--foreground-log-timestamps`, each line starts with a timestamp. Lines
+//! about an input event carry the time the kernel reported it, otherwise the time
+//! of logging. Timestamps are off by default, since journald adds its own.
+//!
+//! With `log_file` in `config.rs`, lines also go to that file, always with a
+//! timestamp (RFC 3339 unless set otherwise). Once it reaches `log_max_size`,
+//! it's renamed to `<log_file>.1` (and that one to `.2`, and so on, up to
+//! `log_max_files`), and a new one is started. Files are created as the user.
+
+use crate::privileges;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Timestamps {
+    #[default]
+    None,
+    /// Local time, e.g. `2024-05-31T14:28:37.123456+02:00`.
+    Rfc3339,
+    /// Seconds since boot, like the kernel log, e.g. `[ 1234.567890]`.
+    Monotonic,
+}
+
+impl std::str::FromStr for Timestamps {
+    type Err = String;
+
+    fn from_str(timestamps: &str) -> Result<Self, Self::Err> {
+        match timestamps {
+            "none" => Ok(Timestamps::None),
+            "rfc3339" => Ok(Timestamps::Rfc3339),
+            "monotonic" => Ok(Timestamps::Monotonic),
+            _ => Err(format!(
+                "unknown timestamps `{timestamps}`, expected rfc3339, monotonic or none"
+            )),
+        }
+    }
+}
+
+static TIMESTAMPS: AtomicU8 = AtomicU8::new(Timestamps::None as u8);
+
+/// Set the timestamps of all lines logged from now on.
+pub fn set_timestamps(timestamps: Timestamps) {
+    TIMESTAMPS.store(timestamps as u8, Ordering::Relaxed);
+}
+
+fn timestamps() -> Timestamps {
+    match TIMESTAMPS.load(Ordering::Relaxed) {
+        1 => Timestamps::Rfc3339,
+        2 => Timestamps::Monotonic,
+        _ => Timestamps::None,
+    }
+}
+
+/// The `log_file`, behind a lock so threads neither interleave their lines
+/// nor rotate it at the same time.
+static FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// How much is in `file`.
+    size: u64,
+    max_size: u64,
+    /// How many rotated files to keep.
+    max_files: usize,
+}
+
+impl LogFile {
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        privileges::as_user_fs(|| {
+            if self.max_files > 0 {
+                for n in (1..self.max_files).rev() {
+                    match std::fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1)) {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => (),
+                    }
+                }
+                std::fs::rename(&self.path, rotated(&self.path, 1))?;
+            }
+            self.file = open(&self.path, self.max_files == 0)?;
+            self.size = 0;
+            Ok(())
+        })
+    }
+}
+
+/// The `n`th newest rotated log file.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
+
+fn open(path: &Path, truncate: bool) -> io::Result<File> {
+    File::options()
+        .create(true)
+        .append(!truncate)
+        .write(true)
+        .truncate(truncate)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Also log to the file at `path` from now on, see `log_file` in `config.rs`.
+pub fn set_file(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<()> {
+    let file = privileges::as_user_fs(|| open(&path, false))?;
+    let size = file.metadata()?.len();
+    let mut log_file = LogFile {
+        path,
+        file,
+        size,
+        max_size,
+        max_files,
+    };
+    if log_file.size >= max_size {
+        log_file.rotate()?;
+    }
+    *FILE.lock().unwrap_or_else(PoisonError::into_inner) = Some(log_file);
+    Ok(())
+}
+
+/// Log a line that happened at `time`, for [`log!`](crate::log!).
+#[doc(hidden)]
+pub fn line(time: SystemTime, args: fmt::Arguments) {
+    let timestamps = timestamps();
+    let timestamp = |timestamps| match timestamps {
+        Timestamps::None => String::new(),
+        Timestamps::Rfc3339 => format!("{} ", rfc3339(time)),
+        Timestamps::Monotonic => {
+            let time = monotonic(time);
+            format!("[{:5}.{:06}] ", time.as_secs(), time.subsec_micros())
+        }
+    };
+    eprintln!("{}{args}", timestamp(timestamps));
+    let mut file = FILE.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(log_file) = &mut *file {
+        let timestamps = match timestamps {
+            Timestamps::None => Timestamps::Rfc3339,
+            timestamps => timestamps,
+        };
+        if let Err(err) = log_file.write(&format!("{}{args}\n", timestamp(timestamps))) {
+            // Not through `log!`, which would try the file again.
+            eprintln!(
+                "Failed to write to {path}, no longer logging to it: {err}",
+                path = log_file.path.display()
+            );
+            *file = None;
+        }
+    }
+}
+
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    // SAFETY: `tm` is a valid output for localtime_r, which (unlike localtime)
+    // doesn't share it with other threads.
+    let tm = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return format!(
+                "{}.{:06}",
+                since_epoch.as_secs(),
+                since_epoch.subsec_micros()
+            );
+        }
+        tm
+    };
+    let offset = tm.tm_gmtoff / 60;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}{}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_micros(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60,
+    )
+}
+
+/// `time` on the monotonic clock, assuming the wall clock didn't jump since.
+fn monotonic(time: SystemTime) -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid output for clock_gettime.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+    let ago = SystemTime::now().duration_since(time).unwrap_or_default();
+    now.saturating_sub(ago)
+}
+
+/// `eprintln!`, with the timestamp configured by [`set_timestamps`].
+///
+/// `log!(at: time, ...)` logs something that happened at `time`, e.g. the
+/// timestamp of an input event, instead of now.
+#[macro_export]
+macro_rules! log {
+    (at: $time:expr, $($arg:tt)*) => {
+        $crate::log::line($time, format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::log::line(std::time::SystemTime::now(), format_args!($($arg)*))
+    };
+}
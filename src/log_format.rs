@@ -0,0 +1,132 @@
+//! The line logged for every key event, configured with `log_format`.
+//!
+//! Templates may contain `{key}` (e.g. `KEY_F1`), `{value}` (0 for released,
+//! 1 for pressed, 2 for repeats), `{code}` (the numeric key code), `{timestamp}`
+//! (seconds since the epoch) and `{device}` (the name of the device). Literal
+//! braces are written as `{{` and `}}`, e.g. for JSON:
+//!
+//! ```toml
+//! log_format = '{{"key": "{key}", "value": {value}}}'
+//! ```
+
+use evdev::{InputEvent, Key};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::{str::FromStr, time::UNIX_EPOCH};
+
+pub const DEFAULT: &str = "{key} - {value}";
+
+#[derive(Clone, Copy, Debug)]
+enum Field {
+    Key,
+    Value,
+    Code,
+    Timestamp,
+    Device,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug)]
+pub struct LogFormat {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed `{{` in `{template}`"))?;
+                    let field = match &rest[..end] {
+                        "key" => Field::Key,
+                        "value" => Field::Value,
+                        "code" => Field::Code,
+                        "timestamp" => Field::Timestamp,
+                        "device" => Field::Device,
+                        other => {
+                            return Err(format!("unknown field `{{{other}}}` in `{template}`"))
+                        }
+                    };
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                    segments.push(Segment::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched `}}` in `{template}`, use `}}}}`")),
+                c => text.push(c),
+            }
+        }
+        segments.push(Segment::Text(text));
+        Ok(LogFormat {
+            template: template.to_string(),
+            segments,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for LogFormat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for LogFormat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.template)
+    }
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        DEFAULT.parse().unwrap()
+    }
+}
+
+impl LogFormat {
+    pub fn format(&self, event: &InputEvent, key: Key, device: &str) -> String {
+        let mut line = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => line.push_str(text),
+                Segment::Field(Field::Key) => line.push_str(&format!("{key:?}")),
+                Segment::Field(Field::Value) => line.push_str(&event.value().to_string()),
+                Segment::Field(Field::Code) => line.push_str(&key.code().to_string()),
+                Segment::Field(Field::Timestamp) => {
+                    let since_epoch = event
+                        .timestamp()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    line.push_str(&format!(
+                        "{secs}.{micros:06}",
+                        secs = since_epoch.as_secs(),
+                        micros = since_epoch.subsec_micros()
+                    ));
+                }
+                Segment::Field(Field::Device) => line.push_str(device),
+            }
+        }
+        line
+    }
+}
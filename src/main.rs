@@ -1,166 +1,225 @@
-use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
-use evdev::{Device, InputEvent, InputEventKind, Key};
-use notify_rust::Notification;
-use signal_hook::consts::TERM_SIGNALS;
-use std::{
-    io,
-    path::{Path, PathBuf},
-    process::Child,
-    process::{Command, Stdio},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
+mod args;
+mod interactive;
+
+use args::Args;
+use evdev_macros::{
+    config, device, history::Outcome, log, privileges, replay, systemd, Config, MacroBoard,
+    Signals, Stop,
 };
+use signal_hook::consts::{SIGHUP, SIGUSR1, SIGUSR2, TERM_SIGNALS};
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
 
-fn process_events(device: &mut Device, sender: &mut Sender<InputEvent>) -> std::io::Result<()> {
-    let events = device.fetch_events()?;
-    for ev in events {
-        sender.send(ev).ok();
-    }
-    Ok(())
-}
+/// How long `--once-per-device` waits for keys.
+const ATTRIBUTION_TIMEOUT: Duration = Duration::from_secs(60);
 
-fn grab_inputs(mut device: Device, mut sender: Sender<InputEvent>) {
-    std::thread::spawn(move || {
-        device.grab().unwrap();
-        loop {
-            if let Err(e) = process_events(&mut device, &mut sender) {
-                eprintln!("Error: {}", e);
-            }
-        }
+fn main() {
+    let mut args = Args::parse().unwrap_or_else(|err| {
+        log!("{err}");
+        std::process::exit(2);
     });
-}
-
-struct MacroBoard {
-    pub receiver: Receiver<InputEvent>,
-
-    pub quit: bool,
-
-    pub vendor: u16,
-    pub product: u16,
-}
-
-impl MacroBoard {
-    fn execute_script(&self, path: &Path) -> io::Result<()> {
-        eprintln!("Running macro: {path}", path = path.display());
-
-        let old_euid = users::get_effective_uid();
-        let old_egid = users::get_effective_gid();
-        users::switch::set_effective_uid(users::get_current_uid())?;
-        users::switch::set_effective_gid(users::get_current_gid())?;
-
-        let result = Command::new(path).stdin(Stdio::null()).spawn();
-
-        users::switch::set_effective_uid(old_euid).unwrap();
-        users::switch::set_effective_gid(old_egid).unwrap();
-
-        result.map(|mut child| {
-            std::thread::spawn(move || {
-                // We need to wait for our child process to finish,
-                // Otherwise we're leaving defunct zombie processes behind.
-                //
-                // See: https://doc.rust-lang.org/std/process/struct.Child.html
-                child.wait().ok();
-            });
-            ()
-        })
+    evdev_macros::log::set_timestamps(args.log_timestamps);
+    evdev_macros::panics::install_hook();
+    if let Some(dir) = args.config_dir.take() {
+        config::set_config_dir(dir);
     }
-
-    fn run_macro(&self, macro_name: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let username = users::get_current_username()
-            .map(|s| s.to_string_lossy().to_string())
-            .ok_or("User no longer exists!")?;
-        let config_path = PathBuf::from(format!("/home/{username}/.config/evdev-macros/"));
-        let entries: Vec<_> = std::fs::read_dir(config_path)?
-            .filter_map(|entry| {
-                if let Ok(entry) = entry {
-                    if entry.path().file_stem().and_then(|s| s.to_str()) == Some(macro_name) {
-                        return Some(entry);
-                    }
-                }
-                None
-            })
-            .collect();
-        for entry in entries {
-            self.execute_script(&*entry.path()).and(Ok(()))?
+    if let Err(err) = check_config_dir(args.init) {
+        log!("{err}");
+        std::process::exit(1);
+    }
+    if args.interactive {
+        if let Err(err) = interactive::pick_device() {
+            log!("Error: {err}");
+            std::process::exit(1);
         }
-
-        Ok(())
+        return;
     }
 
-    fn process_event(&mut self, event: InputEvent) {
-        if event.value() == 0 && event.kind() == InputEventKind::Key(Key::KEY_ESC) {
-            eprintln!("Received ESC - exiting!");
-            self.quit = true;
+    let mut config = Config::load().unwrap_or_else(|err| {
+        log!("Failed to load config: {err}");
+        std::process::exit(1);
+    });
+    if args.validate_config {
+        println!("Config is valid");
+        return;
+    }
+    if args.print_config {
+        if let Some(uniq) = args.uniq {
+            config.override_uniq(uniq);
         }
-        match (event.value(), event.kind()) {
-            (0, InputEventKind::Key(key)) => {
-                let key_name = format!("{key:?}");
-                eprintln!("{key_name} - 0");
-                if let Err(err) = self.run_macro(key_name.as_str()) {
-                    eprintln!("Failed to execute macro: {err}");
-
-                    let old_euid = users::get_effective_uid();
-                    let old_egid = users::get_effective_gid();
-                    users::switch::set_effective_uid(users::get_current_uid()).unwrap();
-                    users::switch::set_effective_gid(users::get_current_gid()).unwrap();
-                    Notification::new()
-                        .summary(format!("Error executing {key_name} macro").as_str())
-                        .body(err.to_string().as_str())
-                        .show()
-                        .ok();
-                    users::switch::set_effective_uid(old_euid).ok();
-                    users::switch::set_effective_gid(old_egid).ok();
-                }
+        match config.to_toml() {
+            Ok(toml) => print!("{toml}"),
+            Err(err) => {
+                log!("Failed to print config: {err}");
+                std::process::exit(1);
             }
-            (value, InputEventKind::Key(key)) => eprintln!("{key:?} - {value}"),
-            (_, _) => (),
         }
+        return;
     }
 
-    pub fn process_events(&mut self) {
-        match self.receiver.recv_timeout(Duration::from_millis(100)) {
-            Ok(event) => self.process_event(event),
-            Err(RecvTimeoutError::Disconnected) => {
-                eprintln!("Disconnected - exiting!");
-                self.quit = true;
+    // One-off runs must not take over the bus name or log file of a running daemon.
+    let daemon = args.once.is_none()
+        && args.test.is_none()
+        && args.once_per_device.is_none()
+        && !args.test_notify
+        && !args.check;
+    if let Some(log_file) = config.log_file.take().filter(|_| daemon) {
+        let result = config::config_dir().and_then(|dir| {
+            Ok(log::set_file(
+                dir.join(log_file),
+                config.log_max_size,
+                config.log_max_files,
+            )?)
+        });
+        if let Err(err) = result {
+            log!("Failed to open log file: {err}");
+        }
+    }
+    if let Some(session) = &args.test {
+        // As the user, who may not be able to read just any file.
+        let result = privileges::as_user_fs(|| File::open(session))
+            .map_err(Into::into)
+            .and_then(|file| {
+                replay::test(config, BufReader::new(file), args.allow_insecure_scripts)
+            });
+        match result {
+            Ok(lines) => {
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+            Err(err) => {
+                log!(
+                    "Failed to replay {session}: {err}",
+                    session = session.display()
+                );
+                std::process::exit(1);
             }
-            Err(RecvTimeoutError::Timeout) => (),
         }
+        return;
+    }
+    let passed_fds = systemd::listen_fds();
+    let (mut board, sender) = MacroBoard::builder(config)
+        .dry_run(args.dry_run || args.once_per_device.is_some())
+        .verbosity(args.verbose)
+        .safe_mode(args.safe_mode)
+        .allow_insecure_scripts(args.allow_insecure_scripts)
+        .notifications(!args.no_notify)
+        .dbus(daemon)
+        .uniq(args.uniq)
+        .build()
+        .unwrap_or_else(|err| {
+            log!("Invalid settings: {err}");
+            std::process::exit(1);
+        });
+    if args.check {
+        if !device::check(&board) {
+            std::process::exit(1);
+        }
+        println!("Everything looks fine");
+        return;
+    }
+    if args.test_notify {
+        if let Err(err) = board.test_notifications() {
+            log!("Failed to show notification: {err}");
+            std::process::exit(1);
+        }
+        println!("Sent sample notifications");
+        return;
+    }
+    if let Some(key_name) = &args.once {
+        let outcome = board.run_once(key_name);
+        println!("{key_name}: {outcome}");
+        if matches!(outcome, Outcome::Failed(_) | Outcome::NoMacro) {
+            std::process::exit(1);
+        }
+        return;
     }
-}
 
-fn main() {
-    let (sender, receiver) = crossbeam_channel::unbounded();
-    let mut board = MacroBoard {
-        receiver,
-        vendor: 0xa5c,
-        product: 0x4502,
-        quit: false,
-    };
+    let signals = Signals::default();
+    for sig in TERM_SIGNALS {
+        signal_hook::flag::register(*sig, Arc::clone(&signals.terminate)).unwrap();
+    }
+    signal_hook::flag::register(SIGHUP, Arc::clone(&signals.reload)).unwrap();
+    signal_hook::flag::register(SIGUSR2, Arc::clone(&signals.dump_history)).unwrap();
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&signals.next_profile)).unwrap();
+    // Wake the board up to look at the flags right away. Handlers run in the order
+    // they were registered, so the flags are already set when it does.
+    let waker = board.waker();
+    let mut incoming =
+        signal_hook::iterator::Signals::new(TERM_SIGNALS.iter().chain(&[SIGHUP, SIGUSR1, SIGUSR2]))
+            .unwrap();
+    std::thread::spawn(move || {
+        for _ in incoming.forever() {
+            waker.wake();
+        }
+    });
 
-    for (_path, device) in evdev::enumerate() {
-        // println!("{}, {}", path.to_string_lossy(), device);
-        let ids = device.input_id();
-        let supports_esc = device
-            .supported_keys()
-            .map(|keys| keys.contains(Key::KEY_ESC))
-            .unwrap_or_default();
-        if ids.vendor() == board.vendor && ids.product() == board.product && supports_esc {
-            println!("Found keyboard:\n{device}");
-            grab_inputs(device, sender.clone());
+    let mut grabbers = device::grab_devices(
+        &mut board,
+        passed_fds,
+        sender.clone(),
+        &signals.terminate,
+        !args.no_grab,
+    )
+    .unwrap_or_else(|err| {
+        log!("Failed to read devices: {err}");
+        std::process::exit(1);
+    });
+    if args.no_grab {
+        log!("Not grabbing: key presses also reach other applications");
+    }
+    if let Some(keys) = args.once_per_device {
+        log!(
+            "Reading {count} device(s), press up to {keys} keys (or ESC to stop)",
+            count = grabbers.len()
+        );
+        board.attribute(&signals, keys, ATTRIBUTION_TIMEOUT, |line| {
+            println!("{line}")
+        });
+        if !grabbers.join() {
+            std::process::exit(1);
         }
+        return;
     }
-    drop(sender);
 
-    let terminate = Arc::new(AtomicBool::new(false));
-    for sig in TERM_SIGNALS {
-        signal_hook::flag::register(*sig, Arc::clone(&terminate)).unwrap();
+    board.announce_start(grabbers.len());
+    while board.run(&signals) == Stop::Regrab {
+        grabbers = device::regrab(
+            &mut board,
+            grabbers,
+            sender.clone(),
+            &signals.terminate,
+            !args.no_grab,
+        )
+        .unwrap_or_else(|err| {
+            log!("Failed to regrab devices: {err}");
+            std::process::exit(1);
+        });
+    }
+    if !grabbers.join() {
+        std::process::exit(1);
     }
+}
 
-    while !terminate.load(Ordering::Relaxed) && !board.quit {
-        board.process_events();
+/// Check that a config directory given with `--config-dir` (or in the
+/// environment) exists, or create it with `--init`.
+fn check_config_dir(init: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if !init && config::overridden_config_dir().is_none() {
+        return Ok(());
     }
+    let dir = config::config_dir()?;
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if !init {
+        return Err(format!(
+            "The config directory {dir} doesn't exist, create it with --init",
+            dir = dir.display()
+        )
+        .into());
+    }
+    privileges::as_user_fs(|| std::fs::create_dir_all(&dir))
+        .map_err(|err| format!("Failed to create {dir}: {err}", dir = dir.display()))?;
+    log!("Created {dir}", dir = dir.display());
+    Ok(())
 }
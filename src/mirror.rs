@@ -0,0 +1,142 @@
+//! Copying the events we read to a named pipe, so other programs can follow
+//! them without grabbing the device themselves. See `mirror` in `config.rs`.
+//!
+//! Each event is one line of JSON:
+//!
+//! ```json
+//! {"time":1717171717.123456,"device":"My Keyboard","type":1,"code":30,"value":1}
+//! ```
+//!
+//! `time` is the kernel's timestamp in seconds since the epoch, `type`, `code`
+//! and `value` are the raw evdev values (see `linux/input-event-codes.h`).
+//! Events come as the kernel reports them, including the SYN_REPORT (type 0)
+//! ending each report, but without the events of `forward_keys` and `forward_types`.
+//!
+//! Mirroring never holds up macros: without a reader, or when the reader
+//! falls behind and the pipe is full, events are dropped.
+
+use crate::{log, privileges};
+use evdev::InputEvent;
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+/// How often we look for a reader while there is none.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Mirror {
+    path: PathBuf,
+    pipe: Option<File>,
+    next_attempt: Instant,
+}
+
+impl Mirror {
+    /// Mirror to the named pipe at `path`, which is created (as the user) if
+    /// it doesn't exist.
+    pub fn new(path: PathBuf) -> Result<Mirror, Box<dyn std::error::Error>> {
+        privileges::as_user_fs(|| create(&path))?;
+        Ok(Mirror {
+            path,
+            pipe: None,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    pub fn write(&mut self, event: &InputEvent, device: &str) {
+        if self.pipe.is_none() && Instant::now() >= self.next_attempt {
+            self.next_attempt = Instant::now() + RETRY_INTERVAL;
+            self.pipe = open(&self.path);
+        }
+        let Some(pipe) = &mut self.pipe else {
+            return;
+        };
+        let time = event
+            .timestamp()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{{\"time\":{secs}.{micros:06},\"device\":{device},\"type\":{kind},\"code\":{code},\"value\":{value}}}\n",
+            secs = time.as_secs(),
+            micros = time.subsec_micros(),
+            device = json_string(device),
+            kind = event.event_type().0,
+            code = event.code(),
+            value = event.value(),
+        );
+        // Lines are shorter than PIPE_BUF, so they're written whole or not at all.
+        match pipe.write(line.as_bytes()) {
+            Ok(_) => (),
+            // The reader is falling behind.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            // The reader is gone, wait for the next one.
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => self.pipe = None,
+            Err(err) => {
+                log!("Failed to mirror events: {err}");
+                self.pipe = None;
+            }
+        }
+    }
+}
+
+/// Make sure there is a named pipe at `path`.
+fn create(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.exists() {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `c_path` is a valid, NUL-terminated path.
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } == -1 {
+            return Err(format!(
+                "Failed to create {path}: {err}",
+                path = path.display(),
+                err = io::Error::last_os_error()
+            )
+            .into());
+        }
+    } else if !path.metadata()?.file_type().is_fifo() {
+        return Err(format!("{path} is not a named pipe", path = path.display()).into());
+    }
+    Ok(())
+}
+
+/// Open the pipe for writing, if someone is reading it.
+fn open(path: &Path) -> Option<File> {
+    // As the user, so the config can't point us at a file only root may write.
+    let opened = privileges::as_user_fs(|| {
+        File::options()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+    });
+    match opened {
+        Ok(pipe) if pipe.metadata().is_ok_and(|meta| meta.file_type().is_fifo()) => Some(pipe),
+        Ok(_) => {
+            log!("{path} is no longer a named pipe", path = path.display());
+            None
+        }
+        // Nobody is reading.
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => None,
+        Err(err) => {
+            log!("Failed to open {path}: {err}", path = path.display());
+            None
+        }
+    }
+}
+
+fn json_string(string: &str) -> String {
+    let mut json = String::with_capacity(string.len() + 2);
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
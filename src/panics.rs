@@ -0,0 +1,40 @@
+//! Logging panics, so they show up like the rest of our log (with timestamps)
+//! and say which thread, and so which device, they happened in.
+//!
+//! Grabbing threads are named after their device (see `grab_inputs` in
+//! `device.rs`), and recover from panics according to `on_device_panic` in
+//! `config.rs`.
+
+use crate::log;
+use std::{backtrace::Backtrace, backtrace::BacktraceStatus, panic::PanicHookInfo};
+
+/// Replace the default panic message with a line in our log.
+pub fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("unnamed thread");
+        let location = info
+            .location()
+            .map(|location| format!(" at {location}"))
+            .unwrap_or_default();
+        log!(
+            "Panic in {thread}{location}: {message}",
+            message = message(info)
+        );
+        // Like the default hook, only with RUST_BACKTRACE set.
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            log!("{backtrace}");
+        }
+    }));
+}
+
+fn message<'a>(info: &'a PanicHookInfo) -> &'a str {
+    if let Some(message) = info.payload().downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = info.payload().downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
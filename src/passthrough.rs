@@ -0,0 +1,100 @@
+//! Keys (and other events) of a grabbed device that should still reach the
+//! rest of the system, see `forward_keys` and `forward_types` in `config.rs`.
+//!
+//! The kernel can only grab a device as a whole. For composite devices, e.g. a
+//! keyboard whose volume keys are on the same device node, we grab everything
+//! and re-emit the forwarded events on a virtual device. Events that aren't
+//! forwarded only reach us, e.g. the pointer movements (EV_REL) of a keyboard
+//! with a trackpoint, unless their type is in `forward_types`.
+//!
+//! Forwarded keys never trigger macros. To everyone else, they come from the
+//! virtual device instead of the real one, so settings tied to the real device
+//! (like a compositor's per-device keymap) don't apply to them. Forwarding needs
+//! `/dev/uinput`, see `uinput.rs`.
+
+use crate::{config::ForwardType, log};
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AttributeSet, EventType, InputEvent, InputEventKind, Key, MiscType, RelativeAxisType,
+    SwitchType,
+};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The highest codes of the forwarded types, from `linux/input-event-codes.h`.
+const REL_MAX: u16 = 0x0f;
+const MSC_MAX: u16 = 0x07;
+const SW_MAX: u16 = 0x10;
+
+/// Shared by all grabbing threads, each with its own `pending` events.
+#[derive(Clone)]
+pub struct Passthrough {
+    keys: Arc<[Key]>,
+    types: Arc<[EventType]>,
+    device: Arc<Mutex<VirtualDevice>>,
+    /// The forwarded events of the current report, re-emitted at its SYN_REPORT
+    /// so they stay together.
+    pending: Vec<InputEvent>,
+}
+
+impl Passthrough {
+    pub fn new(keys: &[Key], types: &[ForwardType]) -> std::io::Result<Passthrough> {
+        let mut supported = AttributeSet::new();
+        for key in keys {
+            supported.insert(*key);
+        }
+        let mut builder = VirtualDeviceBuilder::new()?
+            .name("evdev-macros passthrough")
+            .with_keys(&supported)?;
+        // Every code of the type, as we don't know which ones the devices have.
+        for kind in types {
+            builder = match kind {
+                ForwardType::Relative => builder.with_relative_axes(
+                    &(0..=REL_MAX)
+                        .map(RelativeAxisType)
+                        .collect::<AttributeSet<_>>(),
+                )?,
+                ForwardType::Misc => {
+                    builder.with_msc(&(0..=MSC_MAX).map(MiscType).collect::<AttributeSet<_>>())?
+                }
+                ForwardType::Switch => builder
+                    .with_switches(&(0..=SW_MAX).map(SwitchType).collect::<AttributeSet<_>>())?,
+            };
+        }
+        let device = builder.build()?;
+        Ok(Passthrough {
+            keys: keys.into(),
+            types: types.iter().map(|kind| kind.event_type()).collect(),
+            device: Arc::new(Mutex::new(device)),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Whether `event` is forwarded instead of handled by us. It's sent with the
+    /// next [`Passthrough::flush`].
+    pub fn forward(&mut self, event: InputEvent) -> bool {
+        match event.kind() {
+            InputEventKind::Key(key) if self.keys.contains(&key) => {
+                self.pending.push(event);
+                true
+            }
+            _ if self.types.contains(&event.event_type()) => {
+                self.pending.push(event);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Emit the pending events, at the end of a report.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        // A grabbing thread that panicked while emitting didn't break the device.
+        let mut device = self.device.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = device.emit(&self.pending) {
+            log!("Failed to forward events: {err}");
+        }
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,139 @@
+//! Temporarily acting as the user who started the daemon.
+//!
+//! The daemon is setuid root, so it can read input devices. Anything it does on
+//! the user's behalf (files in their home directory, their session bus) happens
+//! with their effective uid and gid instead.
+
+use std::io;
+use users::switch::{set_effective_gid, set_effective_uid};
+
+/// Acts as the user until dropped, then switches back to the previous
+/// effective ids.
+///
+/// Switching back also happens if switching to the user only partly worked,
+/// so an error can't leave the daemon without its privileges.
+#[must_use = "dropping the guard switches the user back right away"]
+pub struct AsUser {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl AsUser {
+    pub fn switch() -> io::Result<AsUser> {
+        AsUser::switch_to(users::get_current_uid(), users::get_current_gid())
+    }
+
+    fn switch_to(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<AsUser> {
+        let guard = AsUser {
+            uid: users::get_effective_uid(),
+            gid: users::get_effective_gid(),
+        };
+        // The gid first, changing it needs the root uid we're about to give up.
+        set_effective_gid(gid)?;
+        set_effective_uid(uid)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for AsUser {
+    fn drop(&mut self) {
+        // The reverse order: root may change the gid back, the user may not.
+        set_effective_uid(self.uid).expect("Failed to restore effective uid");
+        set_effective_gid(self.gid).expect("Failed to restore effective gid");
+    }
+}
+
+/// Run `f` with the user's file system ids, so the files it creates are theirs
+/// and it can only touch what they can.
+///
+/// Unlike [`AsUser`], this only affects the calling thread, so other threads
+/// may use it at any time (e.g. for logging).
+pub fn as_user_fs<T>(f: impl FnOnce() -> T) -> T {
+    // SAFETY: setfsuid and setfsgid only take integers.
+    let (uid, gid) = unsafe {
+        (
+            libc::setfsuid(users::get_current_uid()),
+            libc::setfsgid(users::get_current_gid()),
+        )
+    };
+    let result = f();
+    // SAFETY: as above, with the ids they returned.
+    unsafe {
+        libc::setfsgid(gid as libc::gid_t);
+        libc::setfsuid(uid as libc::uid_t);
+    }
+    result
+}
+
+/// Run `f` with the user's effective ids, e.g. to connect to their buses, which
+/// check who connects.
+///
+/// Like [`as_user_fs`], this only affects the calling thread (and threads `f`
+/// starts, which keep the user's ids): unlike the libc functions [`AsUser`]
+/// uses, the raw system calls don't switch every thread of the process.
+pub fn as_user_thread<T>(f: impl FnOnce() -> T) -> io::Result<T> {
+    let guard = ThreadAsUser {
+        uid: users::get_effective_uid(),
+        gid: users::get_effective_gid(),
+    };
+    set_thread_id(libc::SYS_setresgid, users::get_current_gid())?;
+    set_thread_id(libc::SYS_setresuid, users::get_current_uid())?;
+    let result = f();
+    drop(guard);
+    Ok(result)
+}
+
+/// Switches the thread back when dropped, like [`AsUser`].
+struct ThreadAsUser {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl Drop for ThreadAsUser {
+    fn drop(&mut self) {
+        set_thread_id(libc::SYS_setresuid, self.uid).expect("Failed to restore effective uid");
+        set_thread_id(libc::SYS_setresgid, self.gid).expect("Failed to restore effective gid");
+    }
+}
+
+/// Set the effective id with `call` (`SYS_setresuid` or `SYS_setresgid`),
+/// keeping the real and saved ones.
+fn set_thread_id(call: libc::c_long, id: u32) -> io::Result<()> {
+    // SAFETY: both system calls only take integers, -1 leaves an id as it is.
+    let result = unsafe { libc::syscall(call, -1 as libc::c_long, libc::c_long::from(id), -1) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_gid_switch_keeps_the_effective_ids() {
+        let (euid, egid) = (users::get_effective_uid(), users::get_effective_gid());
+        // setegid refuses -1 with EINVAL, whoever we are.
+        let err = AsUser::switch_to(users::get_current_uid(), libc::gid_t::MAX)
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(users::get_effective_uid(), euid);
+        assert_eq!(users::get_effective_gid(), egid);
+    }
+
+    #[test]
+    fn failed_uid_switch_restores_the_gid() {
+        // Only root can switch to another gid in the first place.
+        if users::get_effective_uid() != 0 {
+            return;
+        }
+        let egid = users::get_effective_gid();
+        let other = egid.wrapping_add(1);
+        let err = AsUser::switch_to(libc::uid_t::MAX, other).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(users::get_effective_uid(), 0);
+        assert_eq!(users::get_effective_gid(), egid);
+    }
+}
@@ -0,0 +1,507 @@
+//! Supervising the processes spawned by macros.
+
+use crate::{
+    config::{IoClass, Limits},
+    log, Command,
+};
+use crossbeam_channel::Sender;
+use std::{
+    fmt,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::Path,
+    process::{Child, ExitStatus, Stdio},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How often we check whether a child with limits has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The environment of the processes a macro starts.
+#[derive(Clone, Debug, Default)]
+pub struct Env {
+    /// Start from nothing instead of the daemon's environment, see `env_clear`.
+    pub clear: bool,
+    pub vars: Vec<(String, String)>,
+}
+
+impl Env {
+    /// `extra` on top of the daemon's environment or, if `clear`, of a minimal one
+    /// with only `PATH`, `HOME`, `USER`, `LOGNAME` and the variables in `keep`.
+    /// Either way, the variables of the user's `session` (see `session.rs`) are
+    /// added, unless the daemon's environment has them already.
+    pub fn new(
+        clear: bool,
+        keep: &[String],
+        session: &[(String, String)],
+        extra: &[(&str, String)],
+    ) -> Env {
+        let mut vars = Vec::new();
+        if clear {
+            vars.push(("PATH".to_string(), DEFAULT_PATH.to_string()));
+            if let Ok(home) = crate::config::home_dir() {
+                vars.push(("HOME".to_string(), home.to_string_lossy().into_owned()));
+            }
+            if let Some(name) = users::get_current_username() {
+                let name = name.to_string_lossy().into_owned();
+                vars.push(("USER".to_string(), name.clone()));
+                vars.push(("LOGNAME".to_string(), name));
+            }
+            vars.extend(
+                keep.iter()
+                    .filter_map(|name| Some((name.clone(), std::env::var(name).ok()?))),
+            );
+        }
+        vars.extend(
+            session
+                .iter()
+                .filter(|(name, _)| {
+                    std::env::var_os(name).is_none() || (clear && !keep.contains(name))
+                })
+                .cloned(),
+        );
+        vars.extend(
+            extra
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone())),
+        );
+        Env { clear, vars }
+    }
+
+    pub fn apply(&self, command: &mut std::process::Command) {
+        if self.clear {
+            command.env_clear();
+        }
+        command.envs(self.vars.iter().map(|(name, value)| (name, value)));
+    }
+}
+
+/// How the processes of a macro are started and supervised, from its `MacroConfig`.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub env: Env,
+    /// See [`watch`].
+    pub limits: Limits,
+    /// The user who started the daemon, to switch to when we're root.
+    pub user: Option<Arc<Identity>>,
+    /// The `run_as` user instead, looked up when a process starts.
+    pub run_as: Option<String>,
+    pub nice: Option<i32>,
+    pub ionice: Option<IoClass>,
+}
+
+/// `PATH` in a cleared environment, like the default of login(1).
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Whether `program` exists, as a path or in the `PATH` of macros (the
+/// daemon's, or the default one if `clear`).
+pub fn is_installed(program: &str, clear: bool) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    let path = if clear {
+        DEFAULT_PATH.into()
+    } else {
+        std::env::var_os("PATH").unwrap_or_default()
+    };
+    std::env::split_paths(&path).any(|dir| dir.join(program).is_file())
+}
+
+/// Log a step in the life of a macro's process: `resolved`, `spawned`, `exited`,
+/// `timed-out` or `killed`.
+///
+/// These lines all start with `macro::process:` and consist of `key=value` fields,
+/// so they can be picked out of the journal (e.g. with `journalctl --grep`) and parsed.
+pub fn log(event: &str, macro_name: &str, fields: fmt::Arguments) {
+    log!("macro::process: event={event} macro={macro_name} {fields}");
+}
+
+/// The fields describing how a process exited, for [`log`].
+fn exit_fields(status: &ExitStatus, elapsed: Duration) -> String {
+    let elapsed = elapsed.as_secs_f64();
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("code={code} elapsed={elapsed:.3}s"),
+        (None, Some(signal)) => format!("signal={signal} elapsed={elapsed:.3}s"),
+        (None, None) => format!("elapsed={elapsed:.3}s"),
+    }
+}
+
+/// Kill the process group `pgid` with `signal`.
+///
+/// Macros are spawned as leaders of their own process group (see `execute_script`),
+/// so this also reaches anything they started in the background.
+fn kill_group(pgid: u32, signal: libc::c_int) -> std::io::Result<()> {
+    // Signalling our own group (or every process, for pgid 0/1) would take down the daemon.
+    let pgid = pgid as libc::pid_t;
+    // SAFETY: getpgrp and kill have no memory safety requirements.
+    if pgid <= 1 || pgid == unsafe { libc::getpgrp() } {
+        return Err(std::io::Error::other(format!(
+            "refusing to signal process group {pgid}"
+        )));
+    }
+    // SAFETY: see above.
+    if unsafe { libc::kill(-pgid, signal) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether anything is left of the process group `pgid`, once its leader exited.
+///
+/// Those of them that are our children are reaped, which they only are if we
+/// (e.g. a test) are a subreaper. Otherwise init adopted them, and reaps them.
+fn group_alive(pgid: u32) -> bool {
+    let pgid = pgid as libc::pid_t;
+    // SAFETY: waitpid allows a null status, and kill has no memory safety requirements.
+    unsafe {
+        while libc::waitpid(-pgid, std::ptr::null_mut(), libc::WNOHANG) > 0 {}
+        libc::kill(-pgid, 0) == 0
+            || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+    }
+}
+
+/// A user to run macros as, see `run_as` in `config.rs`.
+///
+/// Looked up before forking, so the child only has to make system calls.
+#[derive(Debug)]
+pub struct Identity {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    home: std::path::PathBuf,
+}
+
+impl Identity {
+    pub fn lookup(name: &str) -> std::io::Result<Identity> {
+        let user = users::get_user_by_name(name)
+            .ok_or_else(|| std::io::Error::other(format!("Unknown user: {name}")))?;
+        Identity::of(&user)
+    }
+
+    /// The user who started the daemon, i.e. our real uid.
+    pub fn current() -> std::io::Result<Identity> {
+        let uid = users::get_current_uid();
+        let user = users::get_user_by_uid(uid)
+            .ok_or_else(|| std::io::Error::other(format!("Unknown uid: {uid}")))?;
+        Identity::of(&user)
+    }
+
+    fn of(user: &users::User) -> std::io::Result<Identity> {
+        use users::os::unix::UserExt;
+
+        let name = user.name().to_string_lossy().into_owned();
+        let groups = group_list(&name, user.primary_group_id())?;
+        Ok(Identity {
+            name,
+            uid: user.uid(),
+            gid: user.primary_group_id(),
+            groups,
+            home: user.home_dir().to_path_buf(),
+        })
+    }
+
+    /// The environment variables that describe the user.
+    pub fn env(&self) -> [(&str, std::ffi::OsString); 3] {
+        [
+            ("USER", self.name.clone().into()),
+            ("LOGNAME", self.name.clone().into()),
+            ("HOME", self.home.clone().into_os_string()),
+        ]
+    }
+
+    /// Switch the current process (entirely) to this user. Needs root.
+    ///
+    /// Runs in the child between fork and exec, like [`set_priority`].
+    pub fn switch_to(&self) -> std::io::Result<()> {
+        // SAFETY: `groups` outlives the call and its length is passed along.
+        // The other calls only take integers.
+        unsafe {
+            if libc::setgroups(self.groups.len(), self.groups.as_ptr()) == -1
+                || libc::setgid(self.gid) == -1
+                || libc::setuid(self.uid) == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set up `command` to run as a process of a macro with `settings`: without
+/// stdin, in a process group of its own (so [`watch`] can stop everything it
+/// starts), as the user or its `run_as` user, with all of their groups, and
+/// with the `nice` and `ionice` of the macro.
+///
+/// The switch to the user happens in the child, between fork and exec, so that
+/// it also works on the threads running the actions of macros. The priorities
+/// are set after it, so they can't be higher than the user may set them.
+pub fn prepare(command: &mut std::process::Command, settings: &Settings) -> std::io::Result<()> {
+    command.stdin(Stdio::null()).process_group(0);
+    let identity = match &settings.run_as {
+        Some(_) if users::get_effective_uid() != 0 => {
+            return Err(std::io::Error::other(
+                "run_as needs the daemon to run as root (setuid root)",
+            ));
+        }
+        Some(name) => {
+            let identity = Identity::lookup(name)?;
+            command.envs(identity.env());
+            Some(Arc::new(identity))
+        }
+        None => settings.user.clone(),
+    };
+    let (nice, ionice) = (settings.nice, settings.ionice);
+    if identity.is_some() || nice.is_some() || ionice.is_some() {
+        // SAFETY: `switch_to` and `set_priority` only make async-signal-safe system calls.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(identity) = &identity {
+                    identity.switch_to()?;
+                }
+                set_priority(nice, ionice)
+            });
+        }
+    }
+    Ok(())
+}
+
+/// All groups of the user `name`, including `gid`.
+///
+/// `users::get_user_groups` can't be used for this: it returns a spurious
+/// group 0, which would make the macro a member of root's group.
+fn group_list(name: &str, gid: libc::gid_t) -> std::io::Result<Vec<libc::gid_t>> {
+    let name = std::ffi::CString::new(name).map_err(std::io::Error::other)?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 16];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: `groups` has room for `count` entries, and `name` is a valid C string.
+        let result =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if result != -1 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // `count` now holds the number of groups the user is in.
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+}
+
+/// Apply the scheduling priorities of a macro to the current process.
+///
+/// Runs in the child between fork and exec (see [`prepare`]),
+/// so it may only make async-signal-safe calls.
+fn set_priority(nice: Option<i32>, ionice: Option<IoClass>) -> std::io::Result<()> {
+    if let Some(nice) = nice {
+        // SAFETY: setpriority has no memory safety requirements.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    if let Some(class) = ionice {
+        // See linux/ioprio.h. Realtime and best-effort get the default level, 4.
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let priority = match class {
+            IoClass::Realtime => 1 << IOPRIO_CLASS_SHIFT | 4,
+            IoClass::BestEffort => 2 << IOPRIO_CLASS_SHIFT | 4,
+            IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        };
+        // SAFETY: ioprio_set only takes integers.
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Stage {
+    Warn,
+    Terminate,
+    Kill,
+}
+
+/// Wait for `child` on a separate thread, escalating from a warning to SIGTERM to
+/// SIGKILL (for its whole process group) as it exceeds its `limits`.
+///
+/// With limits, the macro isn't done when `child` exits, but when its process
+/// group is empty: what it started in the background is stopped at the
+/// deadlines as well.
+///
+/// We need to wait for our child process to finish,
+/// Otherwise we're leaving defunct zombie processes behind.
+///
+/// See: https://doc.rust-lang.org/std/process/struct.Child.html
+pub fn watch(
+    child: Child,
+    macro_name: String,
+    limits: Limits,
+    commands: Sender<Command>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        if let Err(err) = supervise(child, &macro_name, limits, &commands) {
+            log!("Failed to wait for {macro_name} macro: {err}");
+        }
+    })
+}
+
+/// Wait for `child` like [`watch`], but on the calling thread, e.g. the one
+/// running the actions of a macro. Returns how `child` exited.
+pub fn supervise(
+    mut child: Child,
+    macro_name: &str,
+    limits: Limits,
+    commands: &Sender<Command>,
+) -> std::io::Result<ExitStatus> {
+    let started = Instant::now();
+    let pid = child.id();
+    let exited = |status: ExitStatus| {
+        log(
+            "exited",
+            macro_name,
+            format_args!("pid={pid} {}", exit_fields(&status, started.elapsed())),
+        );
+        status
+    };
+    let stages = [
+        (limits.warn, Stage::Warn),
+        (limits.terminate, Stage::Terminate),
+        (limits.kill, Stage::Kill),
+    ];
+    let mut status = None;
+    for (after, stage) in stages {
+        let Some(after) = after else {
+            continue;
+        };
+        while started.elapsed() < after {
+            if status.is_none() {
+                status = child.try_wait()?.map(exited);
+            }
+            if let Some(status) = status.filter(|_| !group_alive(pid)) {
+                return Ok(status);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        let secs = after.as_secs_f64();
+        let (summary, body) = match stage {
+            Stage::Warn if status.is_some() => {
+                log!("{macro_name} macro left processes running after {secs}s");
+                continue;
+            }
+            Stage::Warn => {
+                log!("{macro_name} macro is still running after {secs}s");
+                continue;
+            }
+            Stage::Terminate => {
+                log!("{macro_name} macro timed out after {secs}s - terminating it");
+                log(
+                    "timed-out",
+                    macro_name,
+                    format_args!("pid={pid} after={secs}s signal=SIGTERM"),
+                );
+                let body = match kill_group(pid, libc::SIGTERM) {
+                    Ok(()) => format!("Sent SIGTERM after {secs}s"),
+                    Err(err) => {
+                        log!("Failed to terminate {macro_name} macro: {err}");
+                        format!("Timed out after {secs}s, failed to terminate it: {err}")
+                    }
+                };
+                (format!("{macro_name} macro terminated"), body)
+            }
+            Stage::Kill => {
+                log!("{macro_name} macro timed out after {secs}s - killing it");
+                log(
+                    "killed",
+                    macro_name,
+                    format_args!("pid={pid} after={secs}s signal=SIGKILL"),
+                );
+                let body = match kill_group(pid, libc::SIGKILL) {
+                    Ok(()) => format!("Killed after {secs}s"),
+                    Err(err) => {
+                        log!("Failed to kill {macro_name} macro: {err}");
+                        child.kill().ok();
+                        format!("Timed out after {secs}s, failed to kill all its processes: {err}")
+                    }
+                };
+                (format!("{macro_name} macro killed"), body)
+            }
+        };
+        commands
+            .send(Command::Notify {
+                summary,
+                body,
+                urgency: None,
+                icon: None,
+                buttons: Vec::new(),
+            })
+            .ok();
+    }
+    match status {
+        Some(status) => Ok(status),
+        None => child.wait().map(exited),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+
+    #[test]
+    fn sleeping_child_in_the_group_is_reaped() {
+        // So the sleep is adopted by us once its shell exits, instead of by an
+        // init that might not reap it.
+        // SAFETY: prctl with PR_SET_CHILD_SUBREAPER only takes integers.
+        assert_eq!(unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) }, 0);
+        let child = std::process::Command::new("sh")
+            .args(["-c", "sleep 30 & exit 0"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = child.id();
+        let limits = Limits {
+            warn: None,
+            terminate: Some(Duration::from_millis(200)),
+            kill: Some(Duration::from_millis(400)),
+        };
+        let (commands, notifications) = crossbeam_channel::unbounded();
+        let started = Instant::now();
+        watch(child, "test".to_string(), limits, commands)
+            .join()
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!group_alive(pgid));
+        assert!(matches!(
+            notifications.try_recv(),
+            Ok(Command::Notify { summary, .. }) if summary == "test macro terminated"
+        ));
+    }
+
+    #[test]
+    fn watching_ends_with_the_group() {
+        let child = std::process::Command::new("true")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pgid = child.id();
+        let limits = Limits {
+            warn: None,
+            terminate: None,
+            kill: Some(Duration::from_secs(30)),
+        };
+        let (commands, notifications) = crossbeam_channel::unbounded();
+        let started = Instant::now();
+        watch(child, "test".to_string(), limits, commands)
+            .join()
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!group_alive(pgid));
+        assert!(notifications.try_recv().is_err());
+    }
+}
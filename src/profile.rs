@@ -0,0 +1,39 @@
+//! Named sets of macros, e.g. for coding and for gaming.
+//!
+//! A profile is a directory in `~/.config/evdev-macros/profiles/`. Its scripts are
+//! used in addition to the ones in `~/.config/evdev-macros/`, replacing those for
+//! the same key. Only one profile is active at a time; it is switched with the
+//! `profile_key`, SIGUSR1 or the D-Bus `SetProfile` method, and remembered across
+//! restarts, see `state.rs`.
+
+use crate::config;
+use std::path::PathBuf;
+
+pub fn dir(name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(config::config_dir()?.join("profiles").join(name))
+}
+
+/// The names of all profiles, sorted.
+pub fn list() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut profiles: Vec<String> = match std::fs::read_dir(config::config_dir()?.join("profiles"))
+    {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .collect(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    profiles.sort();
+    Ok(profiles)
+}
+
+/// The profile after `current`, wrapping around to the first one.
+pub fn next(current: Option<&str>) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let profiles = list()?;
+    let index = current
+        .and_then(|current| profiles.iter().position(|profile| profile == current))
+        .map_or(0, |index| (index + 1) % profiles.len());
+    Ok(profiles.into_iter().nth(index))
+}
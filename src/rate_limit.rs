@@ -0,0 +1,44 @@
+//! The global `rate_limit` on macro runs (see `config.rs`), a circuit breaker
+//! for when something triggers macros far more often than anyone could, like a
+//! stuck key or a misbehaving device.
+//!
+//! It's a token bucket: it holds up to `runs` tokens, each run takes one, and
+//! they come back at `runs` per `per` seconds. So bursts of up to `runs` runs
+//! are fine, and a steady stream is limited to the average rate.
+
+use crate::config;
+use std::time::{Duration, Instant};
+
+pub struct Bucket {
+    capacity: f64,
+    /// Tokens per second.
+    rate: f64,
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+impl Bucket {
+    pub fn new(limit: config::RateLimit) -> Bucket {
+        let capacity = f64::from(limit.runs);
+        Bucket {
+            capacity,
+            rate: capacity / limit.per.max(Duration::from_millis(1)).as_secs_f64(),
+            tokens: capacity,
+            updated: None,
+        }
+    }
+
+    /// Take a token for a run at `now`, if there is one.
+    pub fn take(&mut self, now: Instant) -> bool {
+        if let Some(updated) = self.updated {
+            let refill = now.saturating_duration_since(updated).as_secs_f64() * self.rate;
+            self.tokens = (self.tokens + refill).min(self.capacity);
+        }
+        self.updated = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
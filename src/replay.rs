@@ -0,0 +1,225 @@
+//! Replaying a recorded session through the macros, to see what they would do,
+//! see `--test`.
+//!
+//! A session is what the `mirror` pipe carries (see `mirror.rs`), e.g. with
+//! `mirror = "events"`, saved with `cat ~/.config/evdev-macros/events > session.jsonl`.
+//! Its events go through
+//! the same board as those of real devices, as a dry run and with the time
+//! taken from the events, so chords, `coalesce` and `max_event_age` work as they
+//! did. For each key event, we print what would have happened:
+//!
+//! ```text
+//! +0.000000 KEY_F1 - 1: ignored
+//! +0.095123 KEY_F1 - 0: ran 1 script(s)
+//! ```
+//!
+//! The times are relative to the first event, so the output of a session only
+//! changes with the config, e.g. to compare it with an expected output in CI.
+
+use crate::{clock::Clock, config::Config, device::Input, history::Entry, MacroBoard};
+use evdev::InputEvent;
+use std::{
+    io::BufRead,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The time of the event being replayed.
+struct ReplayClock {
+    start: Instant,
+    /// The times of the first and the current event.
+    events: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReplayClock {
+    fn set(&self, time: SystemTime) {
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        let first = events.map_or(time, |(first, _)| first);
+        *events = Some((first, time));
+    }
+
+    fn events(&self) -> Option<(SystemTime, SystemTime)> {
+        *self.events.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Clock for ReplayClock {
+    fn now(&self) -> Instant {
+        let elapsed = self.events().map_or(Duration::ZERO, |(first, current)| {
+            current.duration_since(first).unwrap_or_default()
+        });
+        self.start + elapsed
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.events()
+            .map_or_else(SystemTime::now, |(_, current)| current)
+    }
+}
+
+/// Replay the events of `session` with `config`, and return what happened,
+/// one line per key event.
+pub fn test(
+    config: Config,
+    session: impl BufRead,
+    allow_insecure_scripts: bool,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let clock = Arc::new(ReplayClock {
+        start: Instant::now(),
+        events: Mutex::new(None),
+    });
+    let (mut board, sender) = MacroBoard::builder(config)
+        .dry_run(true)
+        .allow_insecure_scripts(allow_insecure_scripts)
+        .notifications(false)
+        .dbus(false)
+        // Not whatever the last run of the daemon left behind.
+        .fresh_state(true)
+        .clock(Arc::clone(&clock) as Arc<dyn Clock>)
+        .build()?;
+    let mut lines = Vec::new();
+    let mut first = None;
+    for (number, line) in session.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (event, device) =
+            parse(&line).map_err(|err| format!("line {number}: {err}", number = number + 1))?;
+        clock.set(event.timestamp());
+        let first = *first.get_or_insert(event.timestamp());
+        let layer = board.layer(&device).map(Arc::from);
+        // One at a time, so we can tell which of them led to what.
+        sender.send(Input::Report {
+            events: vec![event],
+            device: device.into(),
+            layer,
+        })?;
+        board.process_pending();
+        lines.extend(
+            board
+                .history
+                .take()
+                .iter()
+                .map(|entry| line_for(entry, first)),
+        );
+    }
+    Ok(lines)
+}
+
+fn line_for(entry: &Entry, first: SystemTime) -> String {
+    let since = entry.timestamp.duration_since(first).unwrap_or_default();
+    format!(
+        "+{secs}.{micros:06} {key} - {value}: {outcome}",
+        secs = since.as_secs(),
+        micros = since.subsec_micros(),
+        key = entry.key,
+        value = entry.value,
+        outcome = entry.outcome,
+    )
+}
+
+/// An event as `mirror.rs` writes it, and the name of its device.
+fn parse(line: &str) -> Result<(InputEvent, String), String> {
+    let mut fields = Fields(line.trim());
+    fields.expect('{')?;
+    let (mut time, mut device, mut kind, mut code, mut value) = (None, None, None, None, None);
+    loop {
+        let name = fields.string()?;
+        fields.expect(':')?;
+        match name.as_str() {
+            "device" => device = Some(fields.string()?),
+            "time" => time = Some(fields.number()?),
+            "type" => kind = Some(fields.number()?),
+            "code" => code = Some(fields.number()?),
+            "value" => value = Some(fields.number()?),
+            other => return Err(format!("unknown field `{other}`")),
+        }
+        if fields.expect(',').is_err() {
+            break;
+        }
+    }
+    fields.expect('}')?;
+    let missing = |name| format!("`{name}` is missing");
+    let time = time.ok_or_else(|| missing("time"))?;
+    let kind: u16 = kind.ok_or_else(|| missing("type"))?.parse().map_err(bad)?;
+    let code: u16 = code.ok_or_else(|| missing("code"))?.parse().map_err(bad)?;
+    let value: i32 = value
+        .ok_or_else(|| missing("value"))?
+        .parse()
+        .map_err(bad)?;
+    // Seconds with six digits of microseconds, parsed as such so they don't get rounded.
+    let (secs, micros) = time.split_once('.').unwrap_or((&time, "0"));
+    let micros = format!("{micros:0<6}");
+    let event = InputEvent::from(libc::input_event {
+        time: libc::timeval {
+            tv_sec: secs.parse().map_err(bad)?,
+            tv_usec: micros.get(..6).unwrap_or_default().parse().map_err(bad)?,
+        },
+        type_: kind,
+        code,
+        value,
+    });
+    Ok((event, device.ok_or_else(|| missing("device"))?))
+}
+
+fn bad(err: impl std::fmt::Display) -> String {
+    format!("invalid number: {err}")
+}
+
+/// The rest of a line of JSON, as far as `mirror.rs` writes it: one object of
+/// strings and numbers.
+struct Fields<'a>(&'a str);
+
+impl Fields<'_> {
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.0 = self
+            .0
+            .trim_start()
+            .strip_prefix(c)
+            .ok_or_else(|| format!("expected `{c}`"))?;
+        Ok(())
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let rest = self.0.trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+        if end == 0 {
+            return Err("expected a number".to_string());
+        }
+        self.0 = &rest[end..];
+        Ok(rest[..end].to_string())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        let mut chars = self.0.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.0 = &self.0[index + 1..];
+                    return Ok(string);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape `\\u{hex}`"))?;
+                        string.push(c);
+                    }
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some(c @ ('"' | '\\' | '/')) => string.push(c),
+                    _ => return Err("invalid escape".to_string()),
+                },
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
@@ -0,0 +1,44 @@
+//! Noticing that the system resumed from suspend, see `regrab_on_resume` in `config.rs`.
+//!
+//! The monotonic clock stops while the system is suspended, the boot time clock
+//! doesn't. So the gap between them grows by however long each suspend lasted.
+
+use std::time::Duration;
+
+/// Clock drift between checks stays far below this.
+const MIN_SUSPEND: Duration = Duration::from_secs(1);
+
+pub struct Resume {
+    suspended: Duration,
+}
+
+impl Resume {
+    pub fn new() -> Resume {
+        Resume {
+            suspended: suspended(),
+        }
+    }
+
+    /// Whether the system was suspended since the last call, and for how long.
+    pub fn check(&mut self) -> Option<Duration> {
+        let suspended = suspended();
+        let since = suspended.saturating_sub(self.suspended);
+        self.suspended = suspended;
+        (since >= MIN_SUSPEND).then_some(since)
+    }
+}
+
+/// How long the system has been suspended since it booted, in total.
+fn suspended() -> Duration {
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
+fn clock(id: libc::clockid_t) -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid output for clock_gettime.
+    unsafe { libc::clock_gettime(id, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
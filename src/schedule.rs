@@ -0,0 +1,150 @@
+//! Macros that only run at certain times, e.g.
+//!
+//! ```toml
+//! [macros.KEY_F5]
+//! schedule = { days = ["mon", "tue", "wed", "thu", "fri"], from = "09:00", to = "17:30" }
+//! # Runs instead outside of those times. Without it, the key does nothing then.
+//! otherwise = "KEY_F5_AFTER_HOURS"
+//! ```
+//!
+//! Times are in the system's local time zone, including daylight saving time.
+//! A range that ends before it starts (e.g. `from = "22:00", to = "06:00"`) spans
+//! midnight, and belongs to the day it starts on.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+impl Weekday {
+    /// From `tm_wday`, which counts from Sunday.
+    fn from_index(index: i32) -> Weekday {
+        const DAYS: [Weekday; 7] = [
+            Weekday::Sun,
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+        ];
+        DAYS[index.rem_euclid(7) as usize]
+    }
+
+    fn previous(self) -> Weekday {
+        Weekday::from_index(self as i32 - 1)
+    }
+}
+
+/// A time of day as `HH:MM`, stored as minutes since midnight.
+/// `24:00` is allowed, to end a range at midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u16);
+
+impl std::str::FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(time: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time `{time}`, expected HH:MM");
+        let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        // Checking the hours first, so large ones can't overflow.
+        if minutes >= 60 || hours > 24 || hours * 60 + minutes > 24 * 60 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(hours * 60 + minutes))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Schedule {
+    /// The days the range starts on, every day if empty.
+    pub days: Vec<Weekday>,
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            days: Vec::new(),
+            from: TimeOfDay(0),
+            to: TimeOfDay(24 * 60),
+        }
+    }
+}
+
+impl Schedule {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether the schedule includes the current local time.
+    pub fn is_active(&self) -> bool {
+        // Better to run a macro at the wrong time than never.
+        now().is_none_or(|(day, time)| self.includes(day, time))
+    }
+
+    fn includes(&self, day: Weekday, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            self.on(day) && self.from <= time && time < self.to
+        } else {
+            (self.on(day) && self.from <= time) || (self.on(day.previous()) && time < self.to)
+        }
+    }
+}
+
+/// The local weekday and time of day.
+fn now() -> Option<(Weekday, TimeOfDay)> {
+    // SAFETY: `time` accepts a null pointer, and `tm` is a valid output for
+    // localtime_r, which (unlike localtime) doesn't share it with other threads.
+    unsafe {
+        let time = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return None;
+        }
+        Some((
+            Weekday::from_index(tm.tm_wday),
+            TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u16),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_of_day_end_at_midnight() {
+        assert_eq!("24:00".parse(), Ok(TimeOfDay(24 * 60)));
+        assert!("24:01".parse::<TimeOfDay>().is_err());
+        assert!("2000:00".parse::<TimeOfDay>().is_err());
+        assert!("12:60".parse::<TimeOfDay>().is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! The graphical session of the user we run macros for.
+//!
+//! Started by systemd or from a TTY, the daemon doesn't have `DISPLAY`,
+//! `WAYLAND_DISPLAY` or `DBUS_SESSION_BUS_ADDRESS`, so GUI apps started by
+//! macros can't find the session. We look for it in the places it usually
+//! leaves behind (the user's `XDG_RUNTIME_DIR` and the X11 sockets), and pass
+//! on what we find, unless the daemon's environment already has it.
+//!
+//! This is best-effort: with several sessions, we pick one.
+//!
+//! The user may log in (or out) while we run, so a thread looks again every
+//! [`REFRESH_INTERVAL`]. Triggering a macro only takes what it found last, and
+//! never waits for the file system.
+
+use crate::log;
+use std::{
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// How often [`watch`] looks for the session again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The session variables of the user, as [`watch`] last found them.
+pub type Shared = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Look for the session of the user `uid` now, and then every
+/// [`REFRESH_INTERVAL`] on a thread of its own, until the result is dropped.
+pub fn watch(uid: u32) -> Shared {
+    let vars = discover(uid);
+    report(&vars);
+    let session = Arc::new(Mutex::new(vars));
+    let watched = Arc::downgrade(&session);
+    let spawned = std::thread::Builder::new()
+        .name("session".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(REFRESH_INTERVAL);
+            let Some(session) = watched.upgrade() else {
+                return;
+            };
+            let vars = discover(uid);
+            let mut current = session.lock().unwrap_or_else(PoisonError::into_inner);
+            if *current != vars {
+                report(&vars);
+                *current = vars;
+            }
+        });
+    if let Err(err) = spawned {
+        log!("Failed to start watching for the graphical session: {err}");
+    }
+    session
+}
+
+fn report(vars: &[(String, String)]) {
+    if is_graphical(vars) {
+        let vars: Vec<_> = vars
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        log!("Found graphical session: {}", vars.join(" "));
+    } else {
+        log!("No graphical session found, macros that open windows may fail");
+    }
+}
+
+/// The session variables of the user `uid`.
+pub fn discover(uid: u32) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    let runtime_dir = Path::new("/run/user").join(uid.to_string());
+    if !is_owned_by(&runtime_dir, uid) {
+        return vars;
+    }
+    let bus = runtime_dir.join("bus");
+    if is_socket(&bus) {
+        vars.push((
+            "DBUS_SESSION_BUS_ADDRESS".to_string(),
+            format!("unix:path={bus}", bus = bus.display()),
+        ));
+    }
+    let mut wayland: Vec<_> = std::fs::read_dir(&runtime_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| is_socket(&entry.path()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("wayland-"))
+        .collect();
+    wayland.sort();
+    if let Some(display) = wayland.into_iter().next() {
+        vars.push(("WAYLAND_DISPLAY".to_string(), display));
+    }
+    // X servers (including Xwayland) of the user's session run as the user.
+    let mut x11: Vec<u32> = std::fs::read_dir("/tmp/.X11-unix")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| is_socket(&entry.path()) && is_owned_by(&entry.path(), uid))
+        .filter_map(|entry| entry.file_name().to_str()?.strip_prefix('X')?.parse().ok())
+        .collect();
+    x11.sort();
+    if let Some(display) = x11.into_iter().next() {
+        vars.push(("DISPLAY".to_string(), format!(":{display}")));
+    }
+    vars.push((
+        "XDG_RUNTIME_DIR".to_string(),
+        runtime_dir.to_string_lossy().into_owned(),
+    ));
+    vars
+}
+
+/// Whether `vars` (with the daemon's environment) are enough to show windows.
+pub fn is_graphical(vars: &[(String, String)]) -> bool {
+    ["WAYLAND_DISPLAY", "DISPLAY"]
+        .iter()
+        .any(|name| vars.iter().any(|(var, _)| var == name) || std::env::var_os(name).is_some())
+}
+
+fn is_socket(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_socket())
+}
+
+fn is_owned_by(path: &Path, uid: u32) -> bool {
+    path.metadata().is_ok_and(|metadata| metadata.uid() == uid)
+}
@@ -0,0 +1,60 @@
+//! Where the grabbing threads read their events from.
+
+use evdev::{raw_stream::RawDevice, AttributeSet, InputEvent, Key};
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+};
+
+/// An evdev device as used by `grab_inputs`.
+pub trait EventSource: AsRawFd + Send + 'static {
+    fn grab(&mut self) -> io::Result<()>;
+    fn ungrab(&mut self) -> io::Result<()>;
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>>;
+    /// The keys that are currently held down.
+    fn get_key_state(&self) -> io::Result<AttributeSet<Key>>;
+}
+
+impl EventSource for RawDevice {
+    fn grab(&mut self) -> io::Result<()> {
+        RawDevice::grab(self)
+    }
+
+    fn ungrab(&mut self) -> io::Result<()> {
+        RawDevice::ungrab(self)
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        Ok(RawDevice::fetch_events(self)?.collect())
+    }
+
+    fn get_key_state(&self) -> io::Result<AttributeSet<Key>> {
+        RawDevice::get_key_state(self)
+    }
+}
+
+/// Write `events` to a device, e.g. to switch its LEDs.
+pub fn write_events(fd: &impl AsRawFd, events: &[InputEvent]) -> io::Result<()> {
+    for event in events {
+        let raw: &libc::input_event = event.as_ref();
+        // SAFETY: we write exactly one input_event from a valid reference.
+        let written = unsafe {
+            libc::write(
+                fd.as_raw_fd(),
+                (raw as *const libc::input_event).cast(),
+                std::mem::size_of::<libc::input_event>(),
+            )
+        };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// A second handle to the same device, which stays usable while a grabbing thread
+/// owns the original.
+pub fn duplicate(device: &impl AsRawFd) -> io::Result<OwnedFd> {
+    // SAFETY: the device keeps its descriptor open for the duration of this call.
+    unsafe { BorrowedFd::borrow_raw(device.as_raw_fd()) }.try_clone_to_owned()
+}
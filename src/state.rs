@@ -0,0 +1,52 @@
+//! State that survives restarts, stored in `~/.local/state/evdev-macros/state.toml`.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct State {
+    /// Whether macros run at all, toggled with the `arm_key`.
+    pub armed: bool,
+    /// The active profile, see `profile.rs`.
+    pub profile: Option<String>,
+    /// Whether the macros with a `toggle` that `persist` are on.
+    pub toggles: BTreeMap<String, bool>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            armed: true,
+            profile: None,
+            toggles: BTreeMap::new(),
+        }
+    }
+}
+
+fn state_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    Ok(crate::config::home_dir()?.join(".local/state/evdev-macros/state.toml"))
+}
+
+impl State {
+    /// Load the saved state, falling back to the defaults if there is none.
+    pub fn load() -> Result<State, Box<dyn std::error::Error>> {
+        let path = state_path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(toml::from_str(&contents)
+                .map_err(|err| format!("{path}: {err}", path = path.display()))?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(format!("{path}: {err}", path = path.display()).into()),
+        }
+    }
+
+    /// Must be called with the user's effective uid, so the file is owned by them.
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = state_path()?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! Reading input devices that systemd opened for us.
+//!
+//! In a hardened setup, the daemon doesn't need permission to open input devices
+//! at all: systemd opens the device and passes the file descriptor using the
+//! socket activation protocol (`LISTEN_FDS`/`LISTEN_PID`, see sd_listen_fds(3)).
+//! The daemon can then run as the unprivileged user, without the `input` group or
+//! setuid root, and only ever has access to the one keyboard it was given.
+//!
+//! With systemd 253 or newer, `OpenFile=` does this directly in the service:
+//!
+//! ```ini
+//! # /etc/systemd/system/evdev-macros.service
+//! [Service]
+//! ExecStart=/usr/local/bin/evdev-macros
+//! User=alice
+//! OpenFile=/dev/input/by-id/usb-Broadcom_Corp_BCM20702A0-event-kbd
+//! ```
+//!
+//! Older versions can use a socket unit with `ListenSpecial=` pointing at the
+//! device node instead; the service is then started on the first key press.
+//!
+//! When file descriptors are passed, device selection is skipped and every passed
+//! descriptor is grabbed. Otherwise the daemon enumerates `/dev/input` as usual.
+//!
+//! The evdev crate can only open devices by path, so the few operations we need
+//! are done on the descriptor directly.
+
+use crate::source::EventSource;
+use evdev::{AttributeSet, InputEvent, Key};
+use std::{
+    io,
+    mem::size_of,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Number of key codes, `KEY_MAX + 1` from linux/input-event-codes.h.
+const KEY_CNT: u16 = 0x300;
+
+// ioctl request numbers from linux/input.h
+const fn ioc(direction: libc::c_ulong, nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (direction << 30) | ((size as libc::c_ulong) << 16) | ((b'E' as libc::c_ulong) << 8) | nr
+}
+const fn ioc_read(nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    ioc(2, nr, size)
+}
+const EVIOCGRAB: libc::c_ulong = ioc(1, 0x90, size_of::<libc::c_int>());
+const KEY_STATE_BYTES: usize = (KEY_CNT as usize).div_ceil(8);
+const EVIOCGKEY: libc::c_ulong = ioc_read(0x18, KEY_STATE_BYTES);
+const NAME_BYTES: usize = 256;
+const EVIOCGNAME: libc::c_ulong = ioc_read(0x06, NAME_BYTES);
+
+/// Take the file descriptors passed by systemd, if any.
+///
+/// Like sd_listen_fds(3) with `unset_environment`, so macros don't inherit the variables.
+pub fn listen_fds() -> Vec<FdDevice> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let count = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passed us ownership of these descriptors, nothing else uses them.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            FdDevice {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            }
+        })
+        .collect()
+}
+
+/// An input device we only have a file descriptor for.
+pub struct FdDevice {
+    fd: OwnedFd,
+}
+
+impl FdDevice {
+    fn ioctl(&self, request: libc::c_ulong, arg: *mut libc::c_void) -> io::Result<libc::c_int> {
+        // SAFETY: callers pass a request matching the size of the buffer behind `arg`.
+        match unsafe { libc::ioctl(self.fd.as_raw_fd(), request, arg) } {
+            -1 => Err(io::Error::last_os_error()),
+            result => Ok(result),
+        }
+    }
+
+    fn set_grab(&self, grab: libc::c_int) -> io::Result<()> {
+        // SAFETY: EVIOCGRAB takes its argument by value.
+        match unsafe { libc::ioctl(self.fd.as_raw_fd(), EVIOCGRAB, grab) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The device name, for logging.
+    pub fn name(&self) -> io::Result<String> {
+        let mut name = [0u8; NAME_BYTES];
+        self.ioctl(EVIOCGNAME, name.as_mut_ptr().cast())?;
+        let length = name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(NAME_BYTES);
+        Ok(String::from_utf8_lossy(&name[..length]).into_owned())
+    }
+}
+
+impl AsRawFd for FdDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl EventSource for FdDevice {
+    fn grab(&mut self) -> io::Result<()> {
+        self.set_grab(1)
+    }
+
+    fn ungrab(&mut self) -> io::Result<()> {
+        self.set_grab(0)
+    }
+
+    fn fetch_events(&mut self) -> io::Result<Vec<InputEvent>> {
+        // SAFETY: input_event is plain old data, all zeroes is a valid value.
+        let mut buffer: [libc::input_event; 64] = unsafe { std::mem::zeroed() };
+        // SAFETY: we read at most the size of `buffer` into it.
+        let read = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                size_of::<[libc::input_event; 64]>(),
+            )
+        };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The kernel only ever returns whole events.
+        let count = read as usize / size_of::<libc::input_event>();
+        Ok(buffer[..count]
+            .iter()
+            .copied()
+            .map(InputEvent::from)
+            .collect())
+    }
+
+    fn get_key_state(&self) -> io::Result<AttributeSet<Key>> {
+        let mut bits = [0u8; KEY_STATE_BYTES];
+        self.ioctl(EVIOCGKEY, bits.as_mut_ptr().cast())?;
+        let mut keys = AttributeSet::new();
+        for code in 0..KEY_CNT {
+            if bits[code as usize / 8] & (1 << (code % 8)) != 0 {
+                keys.insert(Key::new(code));
+            }
+        }
+        Ok(keys)
+    }
+}
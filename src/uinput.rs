@@ -0,0 +1,32 @@
+//! Whether we can create virtual input devices.
+//!
+//! Sending keys (`send_keys` actions) and releasing keys that were held while
+//! grabbing (see `held_keys.rs`) need `/dev/uinput`. Without it, everything else
+//! keeps working: devices are still grabbed (or only monitored, with `--no-grab`).
+
+use crate::log;
+use std::sync::OnceLock;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Checks once whether `/dev/uinput` can be opened, explaining how to fix it if not.
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(
+        || match std::fs::OpenOptions::new().write(true).open(UINPUT_PATH) {
+            Ok(_) => true,
+            Err(err) => {
+                log!("Can't open {UINPUT_PATH}: {err}");
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    log!("Load the uinput kernel module with `modprobe uinput`");
+                } else {
+                    log!(
+                        "Make sure the daemon may write to {UINPUT_PATH}, \
+                         e.g. by running it as root or with a udev rule"
+                    );
+                }
+                false
+            }
+        },
+    )
+}
@@ -0,0 +1,48 @@
+//! Driving a [`MacroBoard`] through the public API, without any devices.
+
+use evdev::{EventType, InputEvent, Key};
+use evdev_macros::{
+    clock::{Clock, ManualClock},
+    device::Input,
+    history::Outcome,
+    Config, MacroBoard, MacroBoardBuilder, Signals, Stop,
+};
+use std::sync::{atomic::Ordering, Arc};
+
+fn board(config: &str) -> (MacroBoard, crossbeam_channel::Sender<Input>) {
+    let config: Config = toml::from_str(config).unwrap();
+    MacroBoardBuilder::for_tests(config, Arc::new(ManualClock::new()) as Arc<dyn Clock>)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn run_once_runs_the_configured_actions() {
+    let (mut board, _) = board(
+        r#"
+        [macros.KEY_F1]
+        actions = [{ command = "true" }]
+        "#,
+    );
+
+    assert!(matches!(board.run_once("KEY_F1"), Outcome::Ran(1)));
+    assert!(matches!(board.run_once("KEY_F2"), Outcome::NoMacro));
+}
+
+#[test]
+fn releasing_esc_stops_the_board() {
+    let (mut board, sender) = board("");
+    let signals = Signals::default();
+    for value in [1, 0] {
+        sender
+            .send(Input::Report {
+                events: vec![InputEvent::new(EventType::KEY, Key::KEY_ESC.code(), value)],
+                device: Arc::from("test"),
+                layer: None,
+            })
+            .unwrap();
+    }
+
+    assert_eq!(board.run(&signals), Stop::Exit);
+    assert!(signals.terminate.load(Ordering::Relaxed));
+}